@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use diff::crate_files;
+use storage::{LocalStorage, Storage};
+use verify_cache::VerifyCache;
+use {sha256sum_file, Crate, Settings, EXIT_MISMATCH, EXIT_OK, EXIT_PARTIAL};
+
+/// How the crate files in the archive compare with the crates in the index
+#[derive(Serialize, Debug, Default)]
+pub struct Audit {
+    /// The number of crates whose file matches the checksum in the index
+    pub matching: usize,
+    /// Crate files whose checksum doesn't match the index
+    pub mismatched: Vec<String>,
+    /// Crates in the index that have no file in the archive
+    pub missing: Vec<String>,
+}
+impl Audit {
+    /// The report printed by --verify-against-index without --audit-json
+    pub fn to_text(&self) -> String {
+        let mut ret = String::new();
+        for filename in &self.mismatched {
+            ret.push_str(&format!("mismatched {}\n", filename));
+        }
+        for filename in &self.missing {
+            ret.push_str(&format!("missing {}\n", filename));
+        }
+        ret.push_str(&format!("{} matching, {} mismatched, {} missing\n",
+                              self.matching,
+                              self.mismatched.len(),
+                              self.missing.len()));
+        ret
+    }
+
+    /// The exit code for the report. Like for a run, missing crates take
+    /// precedence over mismatched ones if there are both.
+    pub fn exit_code(&self) -> i32 {
+        if !self.missing.is_empty() {
+            EXIT_PARTIAL
+        } else if !self.mismatched.is_empty() {
+            EXIT_MISMATCH
+        } else {
+            EXIT_OK
+        }
+    }
+}
+
+/// Which crates of the index have a file in the archive, for
+/// --only-missing-report
+#[derive(Serialize, Debug, Default)]
+pub struct MissingReport {
+    pub present: usize,
+    /// Crates in the index that have no file in the archive
+    pub missing: Vec<String>,
+}
+impl MissingReport {
+    /// The report printed by --only-missing-report without --audit-json
+    pub fn to_text(&self) -> String {
+        let mut ret = String::new();
+        for filename in &self.missing {
+            ret.push_str(&format!("{}\n", filename));
+        }
+        ret.push_str(&format!("{} present, {} missing\n",
+                              self.present,
+                              self.missing.len()));
+        ret
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        if self.missing.is_empty() {
+            EXIT_OK
+        } else {
+            EXIT_PARTIAL
+        }
+    }
+}
+
+/// Check which of the given crates have a file in the archive, its tiers or
+/// the staging directory, without reading any of the files
+pub fn missing_crates<I>(crates: I, settings: &Settings) -> MissingReport
+    where I: IntoIterator<Item = Crate>
+{
+    let storage = LocalStorage::new(settings);
+    let mut ret = MissingReport::default();
+    for c in crates {
+        let filename = format!("{}-{}.crate", c.name, c.vers);
+        if storage.local_path(&c).is_some() {
+            ret.present += 1;
+        } else {
+            ret.missing.push(filename);
+        }
+    }
+    ret
+}
+
+/// Check the crate files of the given crates in the archive, its tiers or the
+/// staging directory against the index, without changing anything
+///
+/// Files that haven't changed since they were last verified are trusted,
+/// unless --force-verify is given.
+pub fn audit_archive<I>(crates: I, settings: &Settings) -> Audit
+    where I: IntoIterator<Item = Crate>
+{
+    let storage = LocalStorage::new(settings);
+    let verify_cache = VerifyCache::read(&settings.archive);
+    let mut buf = Vec::new();
+    let mut ret = Audit::default();
+
+    for c in crates {
+        let filename = format!("{}-{}.crate", c.name, c.vers);
+        let path = storage.local_path(&c).unwrap_or_default();
+        let metadata = match fs::metadata(&path) {
+            Ok(x) if x.is_file() => x,
+            _ => {
+                ret.missing.push(filename);
+                continue;
+            },
+        };
+
+        let cached = verify_cache.lookup(&filename,
+                                         &metadata,
+                                         settings.reverify_after);
+        let matches = if !settings.force_verify && cached == Some(&c.cksum) {
+            true
+        } else {
+            c.checksum_algorithm().hash_file(&path, &mut buf) == c.cksum
+        };
+        if matches {
+            ret.matching += 1;
+        } else {
+            ret.mismatched.push(filename);
+        }
+    }
+
+    ret
+}
+
+/// Check the crate files in the archive against a SHA256SUMS file from
+/// somewhere else, such as another mirror, instead of the index, for
+/// --compare-checksums-file
+///
+/// The file has lines of the form `{sha256}  {filename}` as written by
+/// sha256sum. Only the file names are compared, so that the other mirror may
+/// lay out its files differently, and entries that aren't crate files are
+/// skipped. Crate files in the archive that the file doesn't list aren't
+/// reported, since it may only cover part of the archive.
+pub fn compare_checksums(sums: &Path, settings: &Settings) -> Result<Audit, String> {
+    let contents = match fs::read_to_string(sums) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Error reading {}: {}", sums.to_string_lossy(), e)),
+    };
+
+    /* Crates in the archive or its tiers come before ones waiting in the
+     * staging directory, as for a run */
+    let mut files = BTreeMap::new();
+    for dir in Some(&settings.archive)
+            .into_iter()
+            .chain(settings.tiers.iter().map(|x| &x.dir))
+            .chain(&settings.staging) {
+        for filename in crate_files(dir) {
+            let path = dir.join(&filename);
+            files.entry(filename).or_insert(path);
+        }
+    }
+
+    let mut buf = Vec::new();
+    let mut ret = Audit::default();
+    for line in contents.lines() {
+        /* sha256sum marks files read in binary mode with a * */
+        let mut fields = line.splitn(2, ' ');
+        let (sha256, file) = match (fields.next(), fields.next()) {
+            (Some(sha256), Some(file)) if sha256.len() == 64 &&
+                                          sha256.chars().all(|c| c.is_ascii_hexdigit()) => {
+                (sha256.to_ascii_lowercase(), file.trim_start_matches([' ', '*']))
+            },
+            _ => {
+                if !line.trim().is_empty() {
+                    println!("Warning: Ignoring invalid line in {}: {}",
+                             sums.to_string_lossy(),
+                             line);
+                }
+                continue;
+            },
+        };
+        let filename = match Path::new(file).file_name().and_then(|x| x.to_str()) {
+            Some(x) if x.ends_with(".crate") => x,
+            _ => continue,
+        };
+
+        match files.get(filename) {
+            Some(path) if sha256sum_file(path, &mut buf) == sha256 => ret.matching += 1,
+            Some(_) => ret.mismatched.push(filename.to_string()),
+            None => ret.missing.push(filename.to_string()),
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sha256sum;
+    use tests::{test_dir, test_settings};
+
+    #[test]
+    fn compare_checksums_file() {
+        let dir = test_dir("compare-checksums");
+        fs::create_dir_all(dir.join("archive")).unwrap();
+        fs::write(dir.join("archive/same-0.1.0.crate"), "same").unwrap();
+        fs::write(dir.join("archive/changed-0.1.0.crate"), "changed").unwrap();
+        fs::write(dir.join("archive/unlisted-0.1.0.crate"), "unlisted").unwrap();
+        fs::create_dir_all(dir.join("staging")).unwrap();
+        fs::write(dir.join("staging/staged-0.1.0.crate"), "staged").unwrap();
+        fs::write(dir.join("SHA256SUMS"),
+                  format!("{}  crates/same/same-0.1.0.crate\n{} *changed-0.1.0.crate\n{}  missing-0.1.0.crate\n{}  staged-0.1.0.crate\n{}  README\nnot a checksum\n",
+                          sha256sum(b"same"),
+                          sha256sum(b"original"),
+                          sha256sum(b"missing"),
+                          sha256sum(b"staged"),
+                          sha256sum(b"readme")))
+            .unwrap();
+
+        let settings = test_settings(&dir.join("archive"),
+                                     &["--staging", &dir.join("staging").to_string_lossy()]);
+        let audit = compare_checksums(&dir.join("SHA256SUMS"), &settings).unwrap();
+        assert_eq!(audit.matching, 2);
+        assert_eq!(audit.mismatched, vec!["changed-0.1.0.crate"]);
+        assert_eq!(audit.missing, vec!["missing-0.1.0.crate"]);
+        assert_eq!(audit.exit_code(), EXIT_PARTIAL);
+        assert!(compare_checksums(&dir.join("nonexistent"), &settings).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
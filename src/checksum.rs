@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use sha2::{Digest, Sha256, Sha512};
+
+/// The hash algorithm of the checksum of a crate in the index. Entries
+/// without a cksum_algo field use sha256, which is all the index has used
+/// so far.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+impl Algorithm {
+    /// Hash the data, returning it as a hex string
+    pub fn hash(self, data: &[u8]) -> String {
+        match self {
+            Algorithm::Sha256 => to_hex(&Sha256::digest(data)),
+            Algorithm::Sha512 => to_hex(&Sha512::digest(data)),
+        }
+    }
+
+    /// Read the file into buf and return its hash, exiting if it can't be
+    /// read
+    pub fn hash_file(self, path: &Path, buf: &mut Vec<u8>) -> String {
+        buf.clear();
+        let mut f = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => error!("Error opening {}: {}", path.to_string_lossy(), e),
+        };
+        match f.read_to_end(buf) {
+            Ok(_) => (),
+            Err(e) => error!("Error reading {}: {}", path.to_string_lossy(), e),
+        };
+        self.hash(buf)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .fold("".to_string(), |mut a, b| {
+            a.push_str(&b);
+            a
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    #[test]
+    fn algorithms() {
+        assert_eq!(Algorithm::Sha256.hash(b"abc"),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(&Algorithm::Sha512.hash(b"abc")[..16], "ddaf35a193617aba");
+        assert_eq!(serde_json::from_str::<Algorithm>(r#""sha512""#).unwrap(),
+                   Algorithm::Sha512);
+        assert!(serde_json::from_str::<Algorithm>(r#""md5""#).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use checksum::Algorithm;
+
+/// Hardlink crate files in the archive with identical contents to a single
+/// file, given the filename and checksum of every verified crate file.
+///
+/// If the filesystem doesn't support hardlinks, a warning is printed and the
+/// files are left alone.
+pub fn dedup_crates(archive: &Path, files: &[(String, Algorithm, String)]) {
+    let mut seen: HashMap<(Algorithm, &str), PathBuf> = HashMap::new();
+    let mut count = 0;
+
+    for &(ref filename, algorithm, ref cksum) in files {
+        let path = archive.join(filename);
+        /* Crates that were left in the staging directory aren't in the
+         * archive yet */
+        if !path.exists() {
+            continue;
+        }
+        let original = match seen.get(&(algorithm, cksum.as_str())) {
+            Some(x) => x,
+            None => {
+                seen.insert((algorithm, cksum), path);
+                continue;
+            },
+        };
+        if same_file(original, &path) {
+            continue;
+        }
+
+        /* Link next to it first, so the crate is never missing if we fail
+         * halfway */
+        let partfile = path.with_extension("crate.part");
+        let _ = fs::remove_file(&partfile);
+        match fs::hard_link(original, &partfile) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Warning: Unable to hardlink {} to {}, skipping deduplication: {}",
+                         original.to_string_lossy(),
+                         partfile.to_string_lossy(),
+                         e);
+                return;
+            },
+        }
+        match fs::rename(&partfile, &path) {
+            Ok(()) => (),
+            Err(e) => {
+                error!("Error renaming {} to {}: {}",
+                       partfile.to_string_lossy(),
+                       path.to_string_lossy(),
+                       e)
+            },
+        }
+        count += 1;
+    }
+
+    println!("Deduplicated {} crate files", count);
+}
+
+/// Whether the two paths are already hardlinks of the same file
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+#[cfg(not(unix))]
+fn same_file(_: &Path, _: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tests::test_dir;
+
+    #[test]
+    fn hardlinks_identical_files() {
+        let dir = test_dir("dedup");
+
+        fs::write(dir.join("a-0.1.0.crate"), "same").unwrap();
+        fs::write(dir.join("a-0.2.0.crate"), "same").unwrap();
+        fs::write(dir.join("b-0.1.0.crate"), "different").unwrap();
+        let file = |name: &str, cksum: &str| (name.to_string(), Algorithm::Sha256, cksum.to_string());
+        let files = vec![file("a-0.1.0.crate", "1"),
+                         file("a-0.2.0.crate", "1"),
+                         file("b-0.1.0.crate", "2"),
+                         file("missing-0.1.0.crate", "2")];
+
+        dedup_crates(&dir, &files);
+        assert!(same_file(&dir.join("a-0.1.0.crate"), &dir.join("a-0.2.0.crate")));
+        assert!(!same_file(&dir.join("a-0.1.0.crate"), &dir.join("b-0.1.0.crate")));
+        assert_eq!(fs::read(dir.join("a-0.2.0.crate")).unwrap(), b"same");
+        assert!(!dir.join("a-0.2.0.crate.part").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::str::FromStr;
+
+use version_req::{Version, VersionReq};
+use Crate;
+
+/// A dependency of a crate, as listed in the index
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    name: String,
+    req: String,
+    /// normal, build or dev, absent means normal
+    #[serde(default)]
+    kind: Option<String>,
+    /// The index of the registry the dependency is from, absent means the
+    /// same registry
+    #[serde(default)]
+    registry: Option<String>,
+    /// The name of the crate if the dependency was renamed
+    #[serde(default)]
+    package: Option<String>,
+}
+impl Dependency {
+    fn crate_name(&self) -> &str {
+        self.package.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Whether cargo needs the dependency to resolve a crate that depends on
+    /// the crate. Dev-dependencies are only needed when testing the crate
+    /// itself, and dependencies from other registries aren't in this index.
+    fn needed(&self) -> bool {
+        self.kind.as_deref() != Some("dev") && self.registry.is_none()
+    }
+}
+
+/// A crate that --depends-on keeps the dependents of, optionally with a
+/// version requirement on it that their requirement has to overlap
+#[derive(Serialize, Debug, Clone)]
+pub struct DependsOn {
+    name: String,
+    /// The requirement as it was given
+    req: Option<String>,
+    #[serde(skip)]
+    parsed_req: Option<VersionReq>,
+}
+impl FromStr for DependsOn {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (name, req) = match s.split_once('@') {
+            Some((name, req)) => {
+                match VersionReq::parse(req) {
+                    Some(x) => (name, Some((req, x))),
+                    None => return Err(format!("expected a version requirement like 1 or >=1.2 after the @, got {}", req)),
+                }
+            },
+            None => (s, None),
+        };
+        if name.is_empty() {
+            return Err(format!("expected NAME or NAME@REQ, got {}", s));
+        }
+        Ok(DependsOn {
+               name: name.to_string(),
+               req: req.as_ref().map(|x| x.0.to_string()),
+               parsed_req: req.map(|x| x.1),
+           })
+    }
+}
+impl DependsOn {
+    /// Whether the crate lists a dependency on the crate of any kind,
+    /// including optional and dev-dependencies
+    pub fn matches(&self, c: &Crate) -> bool {
+        c.deps.iter().any(|dep| {
+            dep.registry.is_none() && dep.crate_name().eq_ignore_ascii_case(&self.name) &&
+            self.parsed_req.as_ref().is_none_or(|r| {
+                VersionReq::parse(&dep.req).is_some_and(|req| req.overlaps(r))
+            })
+        })
+    }
+}
+
+/// The versions that have to be added to the kept crates so that every
+/// dependency of every crate can be resolved from the mirror, including the
+/// dependencies of the added versions
+///
+/// versions_of gives all versions of a crate that may be added. For every
+/// dependency that no kept version matches, the newest version that isn't
+/// yanked is added, or the newest yanked one if all matching versions are
+/// yanked. Optional dependencies are included, since any of them can be
+/// enabled by a crate depending on the crate.
+pub fn dependency_closure<F>(kept: &[Crate], mut versions_of: F) -> Vec<Crate>
+    where F: FnMut(&str) -> Vec<Crate>
+{
+    let mut have: BTreeMap<String, Vec<Version>> = BTreeMap::new();
+    for c in kept {
+        if let Some(v) = Version::parse(&c.vers) {
+            have.entry(c.name.clone()).or_default().push(v);
+        }
+    }
+    /* Every version of the crates that had to be looked up, so that each
+     * index file is only read once */
+    let mut available: BTreeMap<String, Vec<(Version, Crate)>> = BTreeMap::new();
+    let mut queue: VecDeque<Crate> = kept.iter().cloned().collect();
+    let mut added = Vec::new();
+
+    while let Some(c) = queue.pop_front() {
+        for dep in c.deps.iter().filter(|x| x.needed()) {
+            let req = match VersionReq::parse(&dep.req) {
+                Some(x) => x,
+                None => {
+                    println!("Warning: Ignoring the dependency on {} of {}-{}, unable to parse its requirement {}",
+                             dep.crate_name(),
+                             c.name,
+                             c.vers,
+                             dep.req);
+                    continue;
+                },
+            };
+            let name = dep.crate_name();
+            if have.get(name)
+                   .is_some_and(|x| x.iter().any(|v| req.matches(v))) {
+                continue;
+            }
+
+            let versions = available.entry(name.to_string()).or_insert_with(|| {
+                versions_of(name)
+                    .into_iter()
+                    .filter_map(|x| Version::parse(&x.vers).map(|v| (v, x)))
+                    .collect()
+            });
+            let best = versions
+                .iter()
+                .filter(|x| req.matches(&x.0))
+                .max_by(|a, b| (!a.1.yanked, &a.0).cmp(&(!b.1.yanked, &b.0)));
+            match best {
+                Some((v, x)) => {
+                    have.entry(name.to_string()).or_default().push(v.clone());
+                    added.push(x.clone());
+                    queue.push_back(x.clone());
+                },
+                None => {
+                    println!("Warning: No version of {} in the index matches the requirement {} of {}-{}",
+                             name,
+                             dep.req,
+                             c.name,
+                             c.vers);
+                },
+            }
+        }
+    }
+
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    fn krate(line: &str) -> Crate {
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn closure() {
+        let kept = vec![krate(r#"{"name":"app","vers":"1.0.0","cksum":"a","yanked":false,"deps":[
+            {"name":"lib","req":"^0.2"},
+            {"name":"tester","req":"1","kind":"dev"},
+            {"name":"other","req":"1","registry":"https://example.com/index"},
+            {"name":"renamed","package":"util","req":"=1.0.0"}]}"#),
+                        krate(r#"{"name":"util","vers":"2.0.0","cksum":"b","yanked":false}"#)];
+        let index = [krate(r#"{"name":"lib","vers":"0.2.0","cksum":"c","yanked":false}"#),
+                     krate(r#"{"name":"lib","vers":"0.2.1","cksum":"d","yanked":false,"deps":[{"name":"util","req":"2"}]}"#),
+                     krate(r#"{"name":"lib","vers":"0.2.2","cksum":"e","yanked":true}"#),
+                     krate(r#"{"name":"lib","vers":"0.3.0","cksum":"f","yanked":false}"#),
+                     krate(r#"{"name":"util","vers":"1.0.0","cksum":"g","yanked":true}"#),
+                     krate(r#"{"name":"util","vers":"2.0.0","cksum":"b","yanked":false}"#)];
+
+        let mut lookups = Vec::new();
+        let added = dependency_closure(&kept, |name| {
+            lookups.push(name.to_string());
+            index
+                .iter()
+                .filter(|x| x.name == name)
+                .cloned()
+                .collect()
+        });
+        assert_eq!(added,
+                   vec![Crate::new("lib", "0.2.1"), Crate::new("util", "1.0.0")]);
+        assert_eq!(lookups, vec!["lib", "util"]);
+    }
+
+    #[test]
+    fn depends_on() {
+        let c = krate(r#"{"name":"app","vers":"1.0.0","cksum":"a","yanked":false,"deps":[
+            {"name":"rt","package":"Tokio","req":"^1.2"},
+            {"name":"serde","req":"1","registry":"https://example.com/index"}]}"#);
+        let matches = |s: &str| s.parse::<DependsOn>().unwrap().matches(&c);
+        assert!(matches("tokio"));
+        assert!(matches("tokio@1"));
+        assert!(matches("tokio@1.5.0"));
+        assert!(matches("tokio@>=1.1, <1.3"));
+        assert!(!matches("tokio@=1.1.0"));
+        assert!(!matches("tokio@2"));
+        assert!(!matches("rt"));
+        assert!(!matches("serde"));
+
+        assert!("tokio@latest".parse::<DependsOn>().is_err());
+        assert!("@1.0.0".parse::<DependsOn>().is_err());
+        assert_eq!(::serde_json::to_value("tokio@1".parse::<DependsOn>().unwrap()).unwrap(),
+                   ::serde_json::json!({"name": "tokio", "req": "1"}));
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use walkdir::WalkDir;
+
+use sha256sum_file;
+
+/// The differences between the crate files in two archive directories
+#[derive(Serialize, Debug, Default)]
+pub struct ArchiveDiff {
+    /// Crate files that are only in the other archive
+    pub added: Vec<String>,
+    /// Crate files that are only in the archive
+    pub removed: Vec<String>,
+    /// Crate files that are in both, but whose contents differ
+    pub changed: Vec<String>,
+}
+impl ArchiveDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() &&
+        self.changed.is_empty()
+    }
+
+    /// The report printed when comparing archives without --diff-json
+    pub fn to_text(&self) -> String {
+        let mut ret = String::new();
+        for (prefix, files) in &[("+", &self.added),
+                                 ("-", &self.removed),
+                                 ("~", &self.changed)] {
+            for filename in *files {
+                ret.push_str(&format!("{} {}\n", prefix, filename));
+            }
+        }
+        ret.push_str(&format!("{} added, {} removed, {} changed\n",
+                              self.added.len(),
+                              self.removed.len(),
+                              self.changed.len()));
+        ret
+    }
+}
+
+/// Compare the crate files in the archive with the ones in another archive,
+/// hashing the files that are in both
+pub fn diff_archives(archive: &Path, other: &Path) -> ArchiveDiff {
+    let ours = crate_files(archive);
+    let theirs = crate_files(other);
+
+    let mut buf = Vec::new();
+    ArchiveDiff {
+        added: theirs.difference(&ours).cloned().collect(),
+        removed: ours.difference(&theirs).cloned().collect(),
+        changed: ours.intersection(&theirs)
+            .filter(|x| {
+                        sha256sum_file(&archive.join(x), &mut buf) !=
+                        sha256sum_file(&other.join(x), &mut buf)
+                    })
+            .cloned()
+            .collect(),
+    }
+}
+
+/// The names of the .crate files in the directory
+pub fn crate_files(dir: &Path) -> BTreeSet<String> {
+    let mut ret = BTreeSet::new();
+    for entry in WalkDir::new(dir).min_depth(1).max_depth(1) {
+        let entry = match entry {
+            Ok(x) => x,
+            Err(e) => error!("Error reading {}: {}", dir.to_string_lossy(), e),
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if let Some(filename) = entry.file_name().to_str() {
+            if filename.ends_with(".crate") {
+                ret.insert(filename.to_string());
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use tests::test_dir;
+
+    #[test]
+    fn compares_archives() {
+        let dir = test_dir("diff");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::create_dir_all(&a).unwrap();
+        fs::create_dir_all(&b).unwrap();
+
+        fs::write(a.join("same-0.1.0.crate"), "same").unwrap();
+        fs::write(b.join("same-0.1.0.crate"), "same").unwrap();
+        fs::write(a.join("changed-0.1.0.crate"), "old").unwrap();
+        fs::write(b.join("changed-0.1.0.crate"), "new").unwrap();
+        fs::write(a.join("removed-0.1.0.crate"), "removed").unwrap();
+        fs::write(b.join("added-0.1.0.crate"), "added").unwrap();
+        fs::write(b.join("not-a-crate.txt"), "ignored").unwrap();
+
+        let diff = diff_archives(&a, &b);
+        assert_eq!(diff.added, vec!["added-0.1.0.crate"]);
+        assert_eq!(diff.removed, vec!["removed-0.1.0.crate"]);
+        assert_eq!(diff.changed, vec!["changed-0.1.0.crate"]);
+        assert!(!diff.is_empty());
+        assert!(diff_archives(&a, &a).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use write_atomically;
+
+/// The name of the file with the ETags, placed in the archive directory
+const ETAGS_FILE: &str = ".ectype-etags";
+
+/// Remembers the ETag that the server sent with every downloaded crate, so
+/// that with --etags the next run can ask the server whether the crate has
+/// changed without downloading it again
+///
+/// The file has one line per crate file, of the form `{filename} {etag}`.
+pub struct EtagCache {
+    path: PathBuf,
+    entries: BTreeMap<String, String>,
+    dirty: bool,
+}
+impl EtagCache {
+    /// Read the ETags from the given archive directory. A missing file
+    /// results in an empty cache.
+    pub fn read(archive: &Path) -> Self {
+        let path = archive.join(ETAGS_FILE);
+        let mut entries = BTreeMap::new();
+        if let Ok(contents) = fs::read_to_string(&path) {
+            for line in contents.lines() {
+                let mut fields = line.splitn(2, ' ');
+                match (fields.next(), fields.next()) {
+                    (Some(filename), Some(etag)) if !etag.is_empty() => {
+                        entries.insert(filename.to_string(), etag.to_string());
+                    },
+                    _ => {
+                        println!("Warning: Ignoring invalid line in {}: {}",
+                                 path.to_string_lossy(),
+                                 line)
+                    },
+                }
+            }
+        }
+
+        EtagCache {
+            path,
+            entries,
+            dirty: false,
+        }
+    }
+
+    pub fn get(&self, filename: &str) -> Option<&str> {
+        self.entries.get(filename).map(|x| x.as_str())
+    }
+
+    /// Record the ETag of a crate file that was just downloaded, or forget
+    /// the old one if the response had none
+    pub fn set(&mut self, filename: &str, etag: Option<String>) {
+        let changed = match etag {
+            Some(x) => {
+                self.entries.insert(filename.to_string(), x.clone()) != Some(x)
+            },
+            None => self.entries.remove(filename).is_some(),
+        };
+        self.dirty |= changed;
+    }
+
+    /// Write the ETags back to the archive directory, if they have changed
+    pub fn write(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        let mut tmp = String::new();
+        for (filename, etag) in &self.entries {
+            tmp.push_str(&format!("{} {}\n", filename, etag));
+        }
+
+        if let Err(e) = write_atomically(&self.path, tmp) {
+            error!("{}", e);
+        }
+
+        self.dirty = false;
+    }
+}
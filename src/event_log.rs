@@ -0,0 +1,210 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use stats::DownloadStats;
+use MirrorEvent;
+
+/// A line of the --json-lines-output log
+#[derive(Serialize)]
+struct EventLine<'a> {
+    /// Seconds since the unix epoch
+    timestamp: f64,
+    /// Only given when mirroring several registries with --registry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registry: Option<&'a str>,
+    name: &'a str,
+    vers: &'a str,
+    action: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<usize>,
+    /// Seconds the download took
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actual: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The last line of the --json-lines-output log, with the downloads of this
+/// run and of all runs into the archive
+#[derive(Serialize)]
+struct SummaryLine<'a> {
+    timestamp: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registry: Option<&'a str>,
+    action: &'static str,
+    run: DownloadStats,
+    lifetime: DownloadStats,
+}
+
+/// Writes every event as a line of json as it happens, so that the run can
+/// be followed by a log shipper
+pub struct EventLog {
+    path: String,
+    registry: Option<String>,
+    out: Box<dyn Write>,
+}
+impl EventLog {
+    /// Append to the file at the given path, or write to stderr if it is -.
+    /// The lines name the registry if it is given.
+    pub fn open(path: &str, registry: Option<&str>) -> Self {
+        let out: Box<dyn Write> = if path == "-" {
+            Box::new(io::stderr())
+        } else {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(f) => Box::new(f),
+                Err(e) => error!("Error opening {}: {}", path, e),
+            }
+        };
+        EventLog {
+            path: path.to_string(),
+            registry: registry.map(|x| x.to_string()),
+            out,
+        }
+    }
+
+    pub fn log(&mut self, event: &MirrorEvent) {
+        let line = event_line(event, self.registry.as_deref(), now());
+        self.write_line(&line);
+    }
+
+    pub fn log_summary(&mut self, run: DownloadStats, lifetime: DownloadStats) {
+        let line = SummaryLine {
+            timestamp: now(),
+            registry: self.registry.as_deref(),
+            action: "summary",
+            run,
+            lifetime,
+        };
+        let mut line = serde_json::to_string(&line).expect("Error encoding summary");
+        line.push('\n');
+        self.write_line(&line);
+    }
+
+    fn write_line(&mut self, line: &str) {
+        /* Write whole lines at once so they don't get mixed up with other
+         * output going to the same place */
+        match self.out
+                  .write_all(line.as_bytes())
+                  .and_then(|()| self.out.flush()) {
+            Ok(()) => (),
+            Err(e) => error!("Error writing to {}: {}", self.path, e),
+        }
+    }
+}
+
+/// Seconds since the unix epoch
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// The event as a line of json, including the newline
+fn event_line(event: &MirrorEvent, registry: Option<&str>, timestamp: f64) -> String {
+    let krate = match *event {
+        MirrorEvent::Started { krate, .. } |
+        MirrorEvent::Downloaded { krate, .. } |
+        MirrorEvent::Skipped { krate } |
+        MirrorEvent::Mismatch { krate, .. } |
+        MirrorEvent::Unavailable { krate } |
+        MirrorEvent::TooLarge { krate, .. } |
+        MirrorEvent::Fallback { krate, .. } |
+        MirrorEvent::Paused { krate } |
+        MirrorEvent::Resumed { krate } |
+        MirrorEvent::Failed { krate, .. } => krate,
+    };
+    let mut line = EventLine {
+        timestamp,
+        registry,
+        name: &krate.name,
+        vers: &krate.vers,
+        action: "",
+        url: None,
+        bytes: None,
+        duration: None,
+        expected: None,
+        actual: None,
+        error: None,
+    };
+    match *event {
+        MirrorEvent::Started { url, .. } => {
+            line.action = "started";
+            line.url = Some(url);
+        },
+        MirrorEvent::Downloaded {
+            bytes, duration, ..
+        } => {
+            line.action = "downloaded";
+            line.bytes = Some(bytes);
+            line.duration = Some(duration.as_secs_f64());
+        },
+        MirrorEvent::Skipped { .. } => line.action = "skipped",
+        MirrorEvent::Mismatch {
+            expected, actual, ..
+        } => {
+            line.action = "mismatch";
+            line.expected = Some(expected);
+            line.actual = Some(actual);
+        },
+        MirrorEvent::Unavailable { .. } => line.action = "unavailable",
+        MirrorEvent::TooLarge { bytes, .. } => {
+            line.action = "too_large";
+            line.bytes = Some(bytes as usize);
+        },
+        MirrorEvent::Fallback { url, error, .. } => {
+            line.action = "fallback";
+            line.url = Some(url);
+            line.error = Some(format!("Error downloading from the CDN: {}", error));
+        },
+        MirrorEvent::Paused { .. } => line.action = "paused",
+        MirrorEvent::Resumed { .. } => line.action = "resumed",
+        MirrorEvent::Failed { action, error, .. } => {
+            line.action = "failed";
+            line.error = Some(format!("Error {}: {}", action, error));
+        },
+    }
+
+    let mut ret = serde_json::to_string(&line).expect("Error encoding event");
+    ret.push('\n');
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use Crate;
+
+    #[test]
+    fn lines() {
+        let c = Crate::new("foo", "0.1.0");
+        assert_eq!(event_line(&MirrorEvent::Downloaded {
+                                  krate: &c,
+                                  bytes: 12,
+                                  duration: Duration::from_millis(1500),
+                              },
+                              None,
+                              100.5),
+                   "{\"timestamp\":100.5,\"name\":\"foo\",\"vers\":\"0.1.0\",\"action\":\"downloaded\",\"bytes\":12,\"duration\":1.5}\n");
+        assert_eq!(event_line(&MirrorEvent::Failed {
+                                  krate: &c,
+                                  action: "downloading",
+                                  error: "timed out",
+                              },
+                              Some("private"),
+                              100.0),
+                   "{\"timestamp\":100.0,\"registry\":\"private\",\"name\":\"foo\",\"vers\":\"0.1.0\",\"action\":\"failed\",\"error\":\"Error downloading: timed out\"}\n");
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Keeps the crate files in the archive under --max-archive-size, by
+/// removing the ones that were downloaded longest ago to make room for new
+/// downloads
+///
+/// When a file was downloaded is taken from its modification time.
+pub struct Evictor {
+    dir: PathBuf,
+    max_size: u64,
+    size: u64,
+    /// The crate files, oldest first
+    files: BTreeSet<(SystemTime, String)>,
+    /// The modification time and size of every file in files
+    entries: HashMap<String, (SystemTime, u64)>,
+}
+impl Evictor {
+    /// Find the crate files that are already in the archive
+    pub fn new(dir: &Path, max_size: u64) -> Self {
+        let mut ret = Evictor {
+            dir: dir.to_path_buf(),
+            max_size,
+            size: 0,
+            files: BTreeSet::new(),
+            entries: HashMap::new(),
+        };
+
+        let entries = match fs::read_dir(dir) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error reading directory {}: {}", dir.to_string_lossy(), e)
+            },
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Error reading directory {}: {}",
+                           dir.to_string_lossy(),
+                           e)
+                },
+            };
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            if !filename.ends_with(".crate") {
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(x) if x.is_file() => x,
+                _ => continue,
+            };
+            let mtime = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            ret.insert(filename, mtime, metadata.len());
+        }
+
+        ret
+    }
+
+    fn insert(&mut self, filename: String, mtime: SystemTime, size: u64) {
+        self.remove(&filename);
+        self.size += size;
+        self.files.insert((mtime, filename.clone()));
+        self.entries.insert(filename, (mtime, size));
+    }
+
+    fn remove(&mut self, filename: &str) {
+        if let Some((mtime, size)) = self.entries.remove(filename) {
+            self.size -= size;
+            self.files.remove(&(mtime, filename.to_string()));
+        }
+    }
+
+    /// Remove the oldest files until there is room for a new file of the
+    /// given size, leaving the ones that keep returns true for, which are
+    /// those confirmed in the current run. Returns false without removing
+    /// anything if there can't be enough room.
+    pub fn make_room<F>(&mut self, size: u64, keep: F) -> bool
+        where F: Fn(&str) -> bool
+    {
+        let mut remove = Vec::new();
+        let mut freed = 0;
+        for (_, filename) in &self.files {
+            if self.size - freed + size <= self.max_size {
+                break;
+            }
+            if !keep(filename) {
+                freed += self.entries[filename].1;
+                remove.push(filename.clone());
+            }
+        }
+        if self.size - freed + size > self.max_size {
+            return false;
+        }
+
+        for filename in remove {
+            let path = self.dir.join(&filename);
+            println!("Removing {} to stay under --max-archive-size",
+                     path.to_string_lossy());
+            match fs::remove_file(&path) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => {
+                    error!("Error removing {}: {}", path.to_string_lossy(), e)
+                },
+            }
+            self.remove(&filename);
+        }
+        true
+    }
+
+    /// Record a file that was just added to the archive
+    pub fn add(&mut self, filename: &str, size: u64) {
+        self.insert(filename.to_string(), SystemTime::now(), size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tests::test_dir;
+
+    #[test]
+    fn evicts_oldest() {
+        let dir = test_dir("evict");
+        let now = SystemTime::now();
+        for (i, name) in ["old", "middle", "new"].iter().enumerate() {
+            let path = dir.join(format!("{}-0.1.0.crate", name));
+            fs::write(&path, "1234").unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(100 - i as u64))
+                .unwrap();
+        }
+
+        /* Files confirmed in this run are left alone */
+        let mut evictor = Evictor::new(&dir, 10);
+        assert!(evictor.make_room(4, |x| x == "old-0.1.0.crate"));
+        assert!(dir.join("old-0.1.0.crate").exists());
+        assert!(!dir.join("middle-0.1.0.crate").exists());
+        assert!(!dir.join("new-0.1.0.crate").exists());
+
+        fs::write(dir.join("newest-0.1.0.crate"), "1234").unwrap();
+        evictor.add("newest-0.1.0.crate", 4);
+        assert!(evictor.make_room(2, |_| false));
+        assert!(dir.join("old-0.1.0.crate").exists());
+        assert!(!evictor.make_room(3, |_| true));
+        assert!(dir.join("newest-0.1.0.crate").exists());
+        assert!(evictor.make_room(3, |_| false));
+        assert!(!dir.join("old-0.1.0.crate").exists());
+        assert!(dir.join("newest-0.1.0.crate").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
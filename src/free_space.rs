@@ -0,0 +1,37 @@
+use std::path::Path;
+
+/// The number of bytes available to us on the filesystem the path is on, or
+/// None if it can't be determined
+#[cfg(unix)]
+pub fn available(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    use libc;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    (stat.f_bavail as u64).checked_mul(stat.f_frsize as u64)
+}
+#[cfg(not(unix))]
+pub fn available(_: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[cfg(unix)]
+    #[test]
+    fn temp_dir_has_space() {
+        assert!(available(&env::temp_dir()).is_some_and(|x| x > 0));
+        assert_eq!(available(Path::new("/nonexistent/directory")), None);
+    }
+}
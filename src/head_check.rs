@@ -0,0 +1,55 @@
+use download::Downloader;
+use {ConfigJsonFile, Crate, Settings};
+
+/// Which crates can be downloaded, according to HEAD requests for their
+/// download URLs
+#[derive(Serialize, Debug, Default)]
+pub struct HeadReport {
+    /// The number of crates whose URL responded with a 2xx status
+    pub available: usize,
+    /// Crates whose URL responded with another status, along with it
+    pub unavailable: Vec<(String, u32)>,
+    /// Crates whose request failed, along with the error
+    pub failed: Vec<(String, String)>,
+}
+impl HeadReport {
+    /// The report printed by --head-check without --audit-json
+    pub fn to_text(&self) -> String {
+        let mut ret = String::new();
+        for (filename, code) in &self.unavailable {
+            ret.push_str(&format!("unavailable {} (status {})\n", filename, code));
+        }
+        for (filename, e) in &self.failed {
+            ret.push_str(&format!("failed {}: {}\n", filename, e));
+        }
+        ret.push_str(&format!("{} available, {} unavailable, {} failed\n",
+                              self.available,
+                              self.unavailable.len(),
+                              self.failed.len()));
+        ret
+    }
+}
+
+/// Make a HEAD request for the download URL of every crate, without
+/// downloading anything
+pub fn head_check<I>(crates: I,
+                     config: &ConfigJsonFile,
+                     settings: &Settings,
+                     downloader: &mut dyn Downloader)
+                     -> HeadReport
+    where I: IntoIterator<Item = Crate>
+{
+    let mut ret = HeadReport::default();
+    for c in crates {
+        let filename = format!("{}-{}.crate", c.name, c.vers);
+        if let Some(ref rules) = settings.header_rules {
+            downloader.set_headers(rules.headers_for(&c.name));
+        }
+        match downloader.head(&c.download_url(config, settings)) {
+            Ok(x) if (200..300).contains(&x.status) => ret.available += 1,
+            Ok(x) => ret.unavailable.push((filename, x.status)),
+            Err(e) => ret.failed.push((filename, e)),
+        }
+    }
+    ret
+}
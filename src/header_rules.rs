@@ -0,0 +1,133 @@
+use std::fs;
+use std::path::Path;
+
+use toml;
+
+use expand_env;
+use ignore::glob_match;
+
+/// Extra headers sent when downloading some of the crates, read from the
+/// file given with --header-rules, e.g. for registries that give different
+/// crates to different credentials.
+///
+/// The file is toml with a list of rules, each with a crate name pattern
+/// like in .ectypeignore and the headers to send for the crates it matches:
+///
+/// ```toml
+/// [[rule]]
+/// crates = "acme-*"
+/// headers = ["Authorization: Bearer ${ACME_TOKEN}"]
+/// ```
+///
+/// The first rule that matches a crate is used, and crates that no rule
+/// matches are downloaded without extra headers. Environment variables in
+/// the headers are expanded, so that the file doesn't need to hold secrets.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct HeaderRules {
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    crates: String,
+    /// Only the names of the headers are printed by --dump-config, since
+    /// their values are often credentials
+    #[serde(serialize_with = "serialize_header_names")]
+    headers: Vec<String>,
+}
+fn serialize_header_names<S: ::serde::Serializer>(headers: &[String],
+                                                  s: S)
+                                                  -> Result<S::Ok, S::Error> {
+    s.collect_seq(headers.iter().map(|x| x.split(':').next().unwrap_or("")))
+}
+impl HeaderRules {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(x) => HeaderRules::parse(&x),
+            Err(e) => Err(format!("error reading {}: {}", path.to_string_lossy(), e)),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut ret: HeaderRules = match toml::from_str(contents) {
+            Ok(x) => x,
+            Err(e) => return Err(e.to_string()),
+        };
+        for rule in &mut ret.rules {
+            let valid = !rule.crates.is_empty() &&
+                        rule.crates
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "-_*?".contains(c));
+            if !valid {
+                return Err(format!("invalid crate name pattern {}", rule.crates));
+            }
+            rule.crates = rule.crates.to_ascii_lowercase();
+
+            for header in &mut rule.headers {
+                /* Names with spaces, or without a value, aren't headers, and
+                 * a newline would let a value add more of them. This is
+                 * checked after expanding, since environment variables can
+                 * hold newlines too. Only the header as written is shown,
+                 * the expanded one may hold credentials. */
+                let expanded = expand_env(header)?;
+                let name = expanded.split(':').next().unwrap_or("");
+                if !expanded.contains(':') || name.is_empty() ||
+                   name.contains(char::is_whitespace) ||
+                   expanded.contains(['\r', '\n']) {
+                    return Err(format!("invalid header {}, expected \"Name: value\"", header));
+                }
+                *header = expanded;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// The headers to send when downloading the crate with the given name
+    pub fn headers_for(&self, name: &str) -> &[String] {
+        let name = name.to_ascii_lowercase();
+        self.rules
+            .iter()
+            .find(|x| glob_match(&x.crates, &name))
+            .map_or(&[], |x| &x.headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_match() {
+        let rules = HeaderRules::parse(r#"
+            [[rule]]
+            crates = "acme-internal"
+            headers = ["Authorization: Bearer internal", "X-Scope: internal"]
+
+            [[rule]]
+            crates = "acme-*"
+            headers = ["Authorization: Bearer acme"]
+        "#)
+                .unwrap();
+        assert_eq!(rules.headers_for("Acme-Internal"),
+                   ["Authorization: Bearer internal", "X-Scope: internal"]);
+        assert_eq!(rules.headers_for("acme-util"), ["Authorization: Bearer acme"]);
+        assert!(rules.headers_for("serde").is_empty());
+        assert_eq!(::serde_json::to_value(&rules).unwrap()["rule"][0]["headers"],
+                   ::serde_json::json!(["Authorization", "X-Scope"]));
+
+        assert!(HeaderRules::parse("").unwrap().headers_for("acme").is_empty());
+        assert!(HeaderRules::parse("[[rule]]\ncrates = \"a/b\"\nheaders = []\n").is_err());
+        assert!(HeaderRules::parse("[[rule]]\ncrates = \"a\"\nheaders = [\"Auth\"]\n").is_err());
+        assert!(HeaderRules::parse("[[rule]]\ncrates = \"a\"\nheaders = [\"A: b\\r\\nC: d\"]\n")
+                    .is_err());
+
+        ::std::env::set_var("CRATES_ECTYPE_TEST_HEADER_TOKEN", "x\r\nX-Injected: 1");
+        assert!(HeaderRules::parse("[[rule]]\ncrates = \"a\"\nheaders = [\"Authorization: Bearer ${CRATES_ECTYPE_TEST_HEADER_TOKEN}\"]\n")
+                    .is_err());
+        ::std::env::set_var("CRATES_ECTYPE_TEST_HEADER_TOKEN", "secret");
+        let rules = HeaderRules::parse("[[rule]]\ncrates = \"a\"\nheaders = [\"Authorization: Bearer ${CRATES_ECTYPE_TEST_HEADER_TOKEN}\"]\n")
+            .unwrap();
+        assert_eq!(rules.headers_for("a"), ["Authorization: Bearer secret"]);
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The name of the ignore file, placed in the archive directory
+const IGNORE_FILE: &str = ".ectypeignore";
+
+/// Crate name patterns from the .ectypeignore file of an archive.
+///
+/// Each line is a pattern matched against crate names, where `*` matches any
+/// number of characters and `?` matches a single character. Patterns
+/// starting with `!` un-ignore crates matched by earlier patterns, and like
+/// with gitignore the last matching pattern wins. Blank lines and lines
+/// starting with `#` are skipped.
+pub struct IgnoreRules {
+    /// The patterns in order, along with whether they are negated
+    rules: Vec<(bool, String)>,
+}
+impl IgnoreRules {
+    /// Read the ignore file in the given archive directory, if there is one
+    pub fn read(archive: &Path) -> Self {
+        let path = archive.join(IGNORE_FILE);
+        let mut tmp = String::new();
+        match File::open(&path) {
+            Ok(mut f) => {
+                match f.read_to_string(&mut tmp) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        error!("Error reading {}: {}", path.to_string_lossy(), e)
+                    },
+                }
+            },
+            Err(_) => {
+                return IgnoreRules {
+                    rules: Vec::new(),
+                }
+            },
+        }
+
+        IgnoreRules::parse(&tmp, &path.to_string_lossy())
+    }
+
+    /// Parse the contents of an ignore file, warning about invalid patterns.
+    /// The source is only used in warnings.
+    pub fn parse(contents: &str, source: &str) -> Self {
+        let mut rules = Vec::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (negated, pattern) = if let Some(x) = line.strip_prefix('!') {
+                (true, x)
+            } else {
+                (false, line)
+            };
+
+            let valid = !pattern.is_empty() &&
+                        pattern.chars().all(|c| {
+                                                c.is_ascii_alphanumeric() ||
+                                                "-_*?".contains(c)
+                                            });
+            if !valid {
+                println!("Warning: Ignoring invalid pattern in {}: {}",
+                         source,
+                         line);
+                continue;
+            }
+
+            rules.push((negated, pattern.to_ascii_lowercase()));
+        }
+
+        IgnoreRules {
+            rules,
+        }
+    }
+
+    /// Whether the crate with the given name should be skipped
+    pub fn is_ignored(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        let mut ignored = false;
+        for &(negated, ref pattern) in &self.rules {
+            if glob_match(pattern, &name) {
+                ignored = !negated;
+            }
+        }
+        ignored
+    }
+}
+
+/// Match the name against a pattern where `*` matches any number of
+/// characters and `?` matches exactly one
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    /* The position of the last * in the pattern, and the position in the
+     * name it is currently matched up to */
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            /* Let the last * match one more character and try again */
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("tokio", "tokio"));
+        assert!(!glob_match("tokio", "tokio-util"));
+        assert!(glob_match("tokio-*", "tokio-util"));
+        assert!(glob_match("*-sys", "openssl-sys"));
+        assert!(!glob_match("*-sys", "openssl-sys2"));
+        assert!(glob_match("aws-sdk-*", "aws-sdk-s3"));
+        assert!(glob_match("ser?e", "serde"));
+        assert!(!glob_match("ser?e", "sere"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn negation() {
+        let rules = IgnoreRules::parse("# comment\n\n*-sys\n!openssl-sys\nfoo/bar\n",
+                                       "test");
+        assert_eq!(rules.rules.len(), 2);
+        assert!(rules.is_ignored("libgit2-sys"));
+        assert!(rules.is_ignored("LIBGIT2-SYS"));
+        assert!(!rules.is_ignored("openssl-sys"));
+        assert!(!rules.is_ignored("serde"));
+    }
+}
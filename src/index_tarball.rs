@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use tar::{Archive, EntryType};
+
+use index_path;
+
+/// A snapshot of the index in a tar, read with --index-tarball instead of a
+/// git clone
+pub type IndexTarball = Archive<Box<dyn Read>>;
+
+/// Open the tar, which is gzipped if its name ends with .gz or .tgz
+pub fn open(path: &Path) -> Result<IndexTarball, String> {
+    let f = match File::open(path) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Error opening {}: {}", path.to_string_lossy(), e)),
+    };
+    let name = path.to_string_lossy();
+    let f: Box<dyn Read> = if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Box::new(GzDecoder::new(f))
+    } else {
+        Box::new(f)
+    };
+    Ok(Archive::new(f))
+}
+
+/// Whether the entry at path is at rel relative to the root of the index.
+/// Snapshots of the index are often made with a single top-level directory,
+/// as by git archive --prefix, so the entry may be in one.
+fn index_relative(path: &Path, rel: &Path) -> bool {
+    let extra = path.components().count() as isize - rel.components().count() as isize;
+    path.ends_with(rel) && (extra == 0 || extra == 1)
+}
+
+/// Read config.json from the tar
+pub fn read_config(path: &Path) -> Result<String, String> {
+    let mut tar = open(path)?;
+    let name = path.to_string_lossy();
+    let entries = match tar.entries() {
+        Ok(x) => x,
+        Err(e) => return Err(format!("{} is not a valid tar: {}", name, e)),
+    };
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Invalid entry in {}: {}", name, e)),
+        };
+        let is_config = match entry.path() {
+            Ok(x) => index_relative(&x, Path::new("config.json")),
+            Err(e) => return Err(format!("Invalid entry path in {}: {}", name, e)),
+        };
+        if !is_config {
+            continue;
+        }
+
+        let mut ret = String::new();
+        if let Err(e) = entry.read_to_string(&mut ret) {
+            return Err(format!("Error reading config.json from {}: {}", name, e));
+        }
+        return Ok(ret);
+    }
+    Err(format!("{} has no config.json", name))
+}
+
+/// The index files in the tar, in the order they are stored, with the path
+/// they would have under git_dir and their contents
+///
+/// The tar is read as it goes, so each file has to be read before the next
+/// one is returned. Files that aren't where the index would have the crate
+/// of their name, such as config.json or anything under .git, are skipped.
+pub fn index_files<'a>(tar: &'a mut IndexTarball,
+                       path: &'a Path,
+                       git_dir: &'a Path)
+                       -> impl Iterator<Item = (PathBuf, Box<dyn BufRead + 'a>)> + 'a {
+    let entries = match tar.entries() {
+        Ok(x) => x,
+        Err(e) => error!("{} is not a valid tar: {}", path.to_string_lossy(), e),
+    };
+    entries.filter_map(move |entry| {
+        let entry = match entry {
+            Ok(x) => x,
+            Err(e) => error!("Invalid entry in {}: {}", path.to_string_lossy(), e),
+        };
+        if entry.header().entry_type() != EntryType::Regular {
+            return None;
+        }
+        let rel = {
+            let entry_path = match entry.path() {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Invalid entry path in {}: {}", path.to_string_lossy(), e)
+                },
+            };
+            let name = entry_path.file_name()?.to_str()?;
+            if !name.is_ascii() || name.starts_with('.') || name == "config.json" {
+                return None;
+            }
+            let rel = index_path(name);
+            if !index_relative(&entry_path, &rel) {
+                return None;
+            }
+            rel
+        };
+        Some((git_dir.join(rel), Box::new(BufReader::new(entry)) as Box<dyn BufRead + 'a>))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths() {
+        let rel = index_path("serde");
+        assert!(index_relative(Path::new("se/rd/serde"), &rel));
+        assert!(index_relative(Path::new("crates.io-index/se/rd/serde"), &rel));
+        assert!(!index_relative(Path::new("a/b/se/rd/serde"), &rel));
+        assert!(!index_relative(Path::new("rd/serde"), &rel));
+        assert!(index_relative(Path::new("index/config.json"), Path::new("config.json")));
+    }
+}
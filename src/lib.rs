@@ -0,0 +1,6397 @@
+//! crates-ectype mirrors the crates of a cargo registry into a directory.
+//!
+//! Besides the command line program, which is `run`, the mirroring can be
+//! driven from other programs: `mirror` does a whole run like the program
+//! does, and `read_crate_index` with `fetch_crates` fetch a list of crates.
+//! Both report the progress of every crate as a `MirrorEvent` to a callback
+//! instead of printing it.
+
+extern crate getopts;
+extern crate git2;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate walkdir;
+extern crate curl;
+extern crate sha2;
+extern crate toml;
+extern crate flate2;
+extern crate tar;
+#[cfg(unix)]
+extern crate libc;
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::env;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use git2::Repository;
+
+use walkdir::WalkDir;
+use walkdir::WalkDirIterator;
+
+
+/// The index that is mirrored unless --index-url is given
+const CRATES_IO_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
+
+/// The newest index schema version we understand. Index entries with a newer
+/// version are skipped, like cargo does.
+const MAX_INDEX_SCHEMA_VERSION: u32 = 2;
+
+/// How many crates read from the index may be waiting to be fetched. This
+/// bounds the memory used when reading the index is faster than downloading.
+const INDEX_CHANNEL_SIZE: usize = 1024;
+
+/// While a file with this name is in the archive directory, no more crates
+/// are fetched, so that a long run can be held up without stopping it
+const PAUSE_FILE: &str = "PAUSE";
+/// How often a paused run checks whether the pause file has been removed
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The longest crate file name that is written, which leaves room for the
+/// suffix of its .part file within the 255 bytes most filesystems allow
+const MAX_CRATE_FILENAME_LEN: usize = 240;
+
+/// Exit codes returned by the program. Any other error exits with 1.
+const EXIT_OK: i32 = 0;
+/// The run completed, but some crates had checksum mismatches
+const EXIT_MISMATCH: i32 = 2;
+/// The run completed, but some crates were unavailable upstream
+const EXIT_UNAVAILABLE: i32 = 3;
+/// A download or git operation failed
+const EXIT_NETWORK: i32 = 4;
+/// The run was stopped before all crates were processed, because it took
+/// longer than --max-duration
+const EXIT_PARTIAL: i32 = 5;
+
+/// Exit on error, printing the given error message with identical arguments as
+/// to println!
+///
+/// The exit code is 1, unless given explicitly as `error!(exit CODE, ...)`
+macro_rules! error {
+    (exit $code:expr, $fmtstr:tt) => { error!(exit $code, $fmtstr,) };
+    (exit $code:expr, $fmtstr:tt, $( $args:expr ),* ) => {
+        {
+            println!($fmtstr, $( $args ),* );
+            ::std::process::exit($code);
+        }
+    };
+    ($fmtstr:tt) => { error!(exit 1, $fmtstr,) };
+    ($fmtstr:tt, $( $args:expr ),* ) => {
+        error!(exit 1, $fmtstr, $( $args ),* )
+    };
+}
+
+mod dedup;
+mod audit;
+mod checksum;
+mod deps;
+mod diff;
+mod download;
+mod etags;
+mod evict;
+mod event_log;
+mod free_space;
+mod head_check;
+mod header_rules;
+mod ignore;
+mod index_tarball;
+mod lockfile;
+mod manifest;
+mod metrics;
+mod pack;
+mod registry;
+mod run_state;
+mod s3;
+mod self_test;
+mod serve;
+mod shard;
+mod stats;
+mod storage;
+mod tarball;
+mod tiers;
+mod verify_cache;
+mod verify_pool;
+mod version_req;
+
+pub use download::{CurlDownloader, Downloader};
+use download::{Conditional, Head};
+use ignore::IgnoreRules;
+use run_state::RunState;
+use s3::S3Storage;
+pub use storage::{LocalStorage, Storage};
+use verify_cache::VerifyCache;
+use evict::Evictor;
+use verify_pool::{VerifyJob, VerifyPool};
+
+/// Represents the config.json file in the crates.io-index
+#[derive(Deserialize, Serialize)]
+pub struct ConfigJsonFile {
+    dl: String,
+    /// Registries that can't be published to don't have an API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api: Option<String>,
+    dl_orig: Option<String>,
+    /// The fields we don't use, such as auth-required, which are kept when
+    /// the file is written again
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+impl ConfigJsonFile {
+    /// Read the config given the path to the git directory
+    pub fn read(git_dir: &Path) -> Self {
+        let path = git_dir.join("config.json");
+        let mut f = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Error opening file {}: {}", path.to_string_lossy(), e)
+            },
+        };
+        let mut tmp = String::new();
+        match f.read_to_string(&mut tmp) {
+            Ok(_) => (),
+            Err(e) => error!("Error reading {}: {}", path.to_string_lossy(), e),
+        }
+
+        Self::parse(&tmp, &path.to_string_lossy())
+    }
+    /// Read the config.json file from the tar given with --index-tarball
+    fn read_tarball(tarball: &Path) -> Self {
+        match index_tarball::read_config(tarball) {
+            Ok(x) => {
+                Self::parse(&x,
+                            &format!("config.json in {}", tarball.to_string_lossy()))
+            },
+            Err(e) => error!("{}", e),
+        }
+    }
+    fn parse(contents: &str, name: &str) -> Self {
+        match serde_json::from_str(contents) {
+            Ok(x) => x,
+            Err(e) => error!("Error parsing {}: {}", name, e),
+        }
+    }
+    /// Write the config.json file to the given path in the git directory
+    fn write(&self, git_dir: &Path) {
+        let path = git_dir.join("config.json");
+
+        let tmp: String =
+            serde_json::to_string(self).expect("Error encoding Config");
+
+        let mut f = match File::create(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Error creating file {}: {}",
+                       &path.to_string_lossy(),
+                       e);
+            },
+        };
+
+        match f.write_all(tmp.as_bytes()) {
+            Ok(()) => (),
+            Err(e) => {
+                error!("Error writing to file {}: {}",
+                       &path.to_string_lossy(),
+                       e)
+            },
+        }
+    }
+}
+
+/// Represents the settings in a given run of the program
+/// Printed as json by --dump-config, so the names and types of the fields
+/// are part of the output
+#[derive(Serialize, Debug)]
+pub struct Settings {
+    help: bool,
+    version: bool,
+    /// Print the settings and exit
+    dump_config: bool,
+    /// Check that mirroring works against a fixture registry and exit
+    self_test: bool,
+    update_index: bool,
+    download_yanked: bool,
+    check_sums: bool,
+    replace: Option<String>,
+    strict_mode: bool,
+    download_old: bool,
+    archive: PathBuf,
+    use_orig_dl: bool,
+    user_agent: String,
+    force_verify: bool,
+    staging: Option<PathBuf>,
+    max_crate_size: u64,
+    index_url: String,
+    registry_name: String,
+    list_crates: Option<ListFormat>,
+    /// The name and email used for the commit made by replace_url
+    commit_author: (String, String),
+    commit_message: String,
+    push: Option<String>,
+    /// Replace the branch of the --push repository even if the pushed commit
+    /// isn't a descendant of it
+    force_push: bool,
+    cacert: Option<PathBuf>,
+    capath: Option<PathBuf>,
+    insecure: bool,
+    dedup: bool,
+    expected_index_commit: Option<String>,
+    quiet_existing: bool,
+    /// Download URL with `{crate}` and `{version}` placeholders
+    dl_template: Option<String>,
+    s3_bucket: Option<String>,
+    s3_endpoint: String,
+    s3_region: String,
+    s3_prefix: String,
+    verify_jobs: usize,
+    only_yanked: bool,
+    quarantine: Option<PathBuf>,
+    allow_stale_index: bool,
+    save_metadata: bool,
+    /// Record where and when every new crate was downloaded from
+    provenance: bool,
+    #[serde(serialize_with = "serialize_opt_secs")]
+    max_duration: Option<Duration>,
+    max_redirects: u32,
+    /// Hosts that downloads may be redirected to
+    redirect_hosts: Vec<String>,
+    shallow: bool,
+    max_failures: Option<usize>,
+    verbose: bool,
+    #[serde(serialize_with = "serialize_secs")]
+    slow_download: Duration,
+    verify_tarball: bool,
+    /// Download existing crates that fail verification again instead of
+    /// exiting
+    auto_repair: bool,
+    max_rust_version: Option<(u32, u32, u32)>,
+    /// Another archive to compare this one with instead of mirroring
+    diff: Option<PathBuf>,
+    diff_json: bool,
+    /// A SHA256SUMS file to check the archive against instead of mirroring
+    compare_checksums_file: Option<PathBuf>,
+    /// The names of the crates to mirror, instead of every crate
+    from_names: Option<Vec<String>>,
+    /// The only versions to mirror, with their checksums from the Cargo.lock
+    #[serde(serialize_with = "serialize_locked")]
+    cargo_lock: Option<BTreeMap<(String, String), Option<String>>>,
+    /// Hash existing files again once they were last verified this long ago
+    #[serde(serialize_with = "serialize_opt_secs")]
+    reverify_after: Option<Duration>,
+    /// Warn about, or in strict mode refuse, an index whose newest commit is
+    /// older than this
+    #[serde(serialize_with = "serialize_opt_secs")]
+    max_index_age: Option<Duration>,
+    /// Where to write every event as a line of json, - for stderr
+    json_lines_output: Option<String>,
+    /// The commit or tag of the index to mirror instead of the newest one
+    index_commit: Option<String>,
+    /// Stop downloading when less than this many bytes are free
+    min_free_space: Option<u64>,
+    /// Check the name and version in the Cargo.toml of every download
+    verify_contents: bool,
+    /// Leave out the crates that are known to be unavailable on crates.io
+    skip_unavailable: bool,
+    /// Keep a SHA256SUMS file with every verified crate in the archive
+    sha256sums: bool,
+    /// Leave out pre-release versions
+    stable_only: bool,
+    /// Remove the oldest crates to keep the archive under this many bytes
+    max_archive_size: Option<u64>,
+    /// The position of every crate in --priority-file, to mirror them in
+    /// that order
+    priority: Option<BTreeMap<String, usize>>,
+    /// Add the versions that the selected crates depend on
+    dependency_closure: bool,
+    /// Download through the proxy listening on this Unix domain socket
+    unix_socket: Option<String>,
+    /// Remember the ETags of downloads, and download crates that are
+    /// already in the archive again if their ETag changed
+    etags: bool,
+    /// Compare the archive with the index instead of mirroring
+    verify_against_index: bool,
+    audit_json: bool,
+    /// List the crates that aren't in the archive instead of mirroring
+    only_missing_report: bool,
+    /// Quote the body of error responses in download errors
+    verbose_errors: bool,
+    sort_order: SortOrder,
+    /// Stop once this many crates have been downloaded in this run
+    limit: Option<u64>,
+    /// Extra headers to send when downloading some of the crates
+    header_rules: Option<header_rules::HeaderRules>,
+    /// Download through the SOCKS5 proxy at this HOST:PORT
+    socks5: Option<String>,
+    /// Write Prometheus metrics about the run to this file
+    metrics_file: Option<PathBuf>,
+    /// Only read the index files changed by commits after this unix time
+    since: Option<i64>,
+    /// Don't download crates larger than this
+    skip_larger_than: Option<u64>,
+    /// Retry crates that fail to download from the CDN from config.json
+    prefer_orig_on_cdn_failure: bool,
+    /// Write the verified crates into this tar after the run
+    pack: Option<PathBuf>,
+    /// Restore the crates in this tar into the archive instead of mirroring
+    unpack: Option<PathBuf>,
+    /// Check which crates can be downloaded instead of mirroring
+    head_check: bool,
+    /// Only mirror the crates in this part of the index
+    shard: Option<shard::Shard>,
+    /// Only mirror the crates whose name starts with a character in this
+    /// range
+    name_prefix: Option<shard::NameRange>,
+    /// Read the index from this tar instead of the git clone
+    index_tarball: Option<PathBuf>,
+    /// The permissions crate files are written with
+    file_mode: u32,
+    /// The most bytes written to a crate file at a time
+    write_buffer_size: usize,
+    /// Only mirror the crates that depend on this one
+    depends_on: Option<deps::DependsOn>,
+    /// Discard changes made by hand to the index repository when updating it
+    force: bool,
+    /// Registries to mirror into directories of the archive, instead of the
+    /// one given by --index-url
+    registries: Vec<registry::Registry>,
+    /// The address to serve the mirror on instead of mirroring
+    serve: Option<String>,
+    /// Only connect over IPv4 or IPv6
+    ip_version: Option<u8>,
+    /// Directories that some crates are stored in instead of the archive
+    tiers: Vec<tiers::Tier>,
+}
+/// Durations are given to --dump-config in seconds
+fn serialize_secs<S: serde::Serializer>(x: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(x.as_secs_f64())
+}
+fn serialize_opt_secs<S>(x: &Option<Duration>, s: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer
+{
+    match *x {
+        Some(ref x) => serialize_secs(x, s),
+        None => s.serialize_none(),
+    }
+}
+/// The locked versions are given as {name}-{version}, since json keys have to
+/// be strings
+fn serialize_locked<S>(x: &Option<BTreeMap<(String, String), Option<String>>>,
+                       s: S)
+                       -> Result<S::Ok, S::Error>
+    where S: serde::Serializer
+{
+    use serde::Serialize;
+
+    x.as_ref()
+        .map(|x| {
+                 x.iter()
+                     .map(|(k, v)| (format!("{}-{}", k.0, k.1), v))
+                     .collect::<BTreeMap<_, _>>()
+             })
+        .serialize(s)
+}
+
+impl Settings {
+    /// The settings given by the command line arguments, without the name of
+    /// the program, read like those of crates-ectype itself including
+    /// --config. Exits if they are invalid.
+    pub fn parse(args: &[String]) -> Self {
+        Settings::from(&parse_args(&options(), args))
+    }
+}
+impl From<&getopts::Matches> for Settings {
+    fn from(matches: &getopts::Matches) -> Self {
+        /* Paths and URLs may refer to environment variables */
+        let expanded = |name: &str, x: String| match expand_env(&x) {
+            Ok(x) => x,
+            Err(e) => error!("Invalid value for --{}: {}", name, e),
+        };
+        let opt_expanded = |name: &str| {
+            matches.opt_str(name).map(|x| expanded(name, x))
+        };
+
+        let archive = match matches.free.first() {
+            Some(x) => {
+                match expand_env(x) {
+                    Ok(x) => x,
+                    Err(e) => error!("Invalid archive directory: {}", e),
+                }
+            },
+            None => String::new(),
+        };
+        let cargo_lock = opt_expanded("from-cargo-lock")
+            .map(|x| lockfile::read_lockfile(Path::new(&x)));
+        let from_names = match (opt_expanded("from-names-file"), &cargo_lock) {
+            (Some(_), Some(_)) => {
+                error!("--from-names-file and --from-cargo-lock can't be used together")
+            },
+            (Some(x), None) => Some(read_names_file(Path::new(&x))),
+            /* Only the index files of the locked crates need to be read */
+            (None, Some(locked)) => {
+                let mut names: Vec<String> =
+                    locked.keys().map(|(name, _)| name.clone()).collect();
+                names.dedup();
+                Some(names)
+            },
+            (None, None) => None,
+        };
+        Settings {
+            help: matches.opt_present("help"),
+            version: matches.opt_present("version"),
+            update_index: !matches.opt_present("no-update-index"),
+            download_yanked: matches.opt_present("yanked"),
+            /* Downloads are always verified, so this only affects the
+             * crates that are already in the archive */
+            check_sums: !(matches.opt_present("no-check-sums") ||
+                          matches.opt_present("checksum-only-new")),
+            replace: opt_expanded("replace").map(|x| {
+                if !is_url(&x) {
+                    error!("Invalid value for --replace: {}, expected a URL like https://mirror.example.com/crates",
+                           x)
+                }
+                x
+            }),
+            strict_mode: matches.opt_present("strict"),
+            download_old: matches.opt_present("download-old"),
+            self_test: matches.opt_present("self-test"),
+            dump_config: matches.opt_present("dump-config"),
+            archive: PathBuf::from(archive),
+            use_orig_dl: matches.opt_present("use-orig-dl"),
+            user_agent: match matches.opt_str("user-agent") {
+                Some(x) => x,
+                None => {
+                    format!("{}/{}",
+                            env!("CARGO_PKG_NAME"),
+                            env!("CARGO_PKG_VERSION"))
+                },
+            },
+            force_verify: matches.opt_present("force-verify"),
+            staging: opt_expanded("staging").map(PathBuf::from),
+            max_crate_size: parse_opt(matches, "max-crate-size")
+                .unwrap_or(100 * 1024 * 1024),
+            index_url: opt_expanded("index-url")
+                .unwrap_or_else(|| CRATES_IO_INDEX.to_string()),
+            registry_name: matches
+                .opt_str("registry-name")
+                .unwrap_or_else(|| "crates.io".to_string()),
+            list_crates: parse_opt(matches, "list-crates"),
+            commit_author: match matches.opt_str("commit-author") {
+                Some(x) => {
+                    match parse_author(&x) {
+                        Some(x) => x,
+                        None => {
+                            error!("Invalid value for --commit-author: {}, expected \"Name <email>\"",
+                                   x)
+                        },
+                    }
+                },
+                None => {
+                    ("crates-ectype".to_string(),
+                     "crates-ectype@localhost".to_string())
+                },
+            },
+            commit_message: match matches.opt_str("commit-message") {
+                Some(x) => x,
+                None => "crates-ectype updating DL location".to_string(),
+            },
+            push: opt_expanded("push"),
+            force_push: matches.opt_present("force-push"),
+            cacert: opt_expanded("cacert").map(PathBuf::from),
+            capath: opt_expanded("capath").map(PathBuf::from),
+            insecure: matches.opt_present("insecure"),
+            dedup: matches.opt_present("dedup"),
+            expected_index_commit: matches
+                .opt_str("expected-index-commit")
+                .map(|x| x.to_ascii_lowercase()),
+            quiet_existing: matches.opt_present("quiet-existing"),
+            dl_template: match opt_expanded("dl-template") {
+                Some(ref x) if !x.contains("{crate}") ||
+                               !x.contains("{version}") => {
+                    error!("Invalid value for --dl-template: {}, it must contain both {{crate}} and {{version}}",
+                           x)
+                },
+                x => x,
+            },
+            s3_bucket: matches.opt_str("s3-bucket"),
+            s3_endpoint: opt_expanded("s3-endpoint")
+                .unwrap_or_else(|| "https://s3.amazonaws.com".to_string()),
+            s3_region: matches
+                .opt_str("s3-region")
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            s3_prefix: matches.opt_str("s3-prefix").unwrap_or_default(),
+            verify_jobs: match parse_opt(matches, "verify-jobs") {
+                Some(0) => error!("Invalid value for --verify-jobs: 0"),
+                Some(x) => x,
+                None => 1,
+            },
+            only_yanked: matches.opt_present("only-yanked"),
+            quarantine: opt_expanded("quarantine").map(PathBuf::from),
+            allow_stale_index: matches.opt_present("allow-stale-index"),
+            save_metadata: matches.opt_present("save-metadata"),
+            provenance: matches.opt_present("provenance"),
+            max_duration: matches.opt_str("max-duration").map(|x| {
+                match parse_duration(&x) {
+                    Some(x) => x,
+                    None => {
+                        error!("Invalid value for --max-duration: {}, expected e.g. 90s, 30m or 6h",
+                               x)
+                    },
+                }
+            }),
+            max_redirects: parse_opt(matches, "max-redirects").unwrap_or(10),
+            redirect_hosts: matches.opt_strs("redirect-host"),
+            shallow: matches.opt_present("shallow"),
+            max_failures: parse_opt(matches, "max-failures"),
+            verbose: matches.opt_present("verbose"),
+            slow_download: match matches.opt_str("slow-download") {
+                Some(x) => {
+                    match parse_duration(&x) {
+                        Some(x) => x,
+                        None => {
+                            error!("Invalid value for --slow-download: {}, expected e.g. 90s, 30m or 6h",
+                                   x)
+                        },
+                    }
+                },
+                None => Duration::from_secs(30),
+            },
+            verify_tarball: matches.opt_present("verify-tarball"),
+            auto_repair: !matches.opt_present("no-auto-repair"),
+            max_rust_version: matches
+                .opt_str("max-rust-version")
+                .map(|x| match parse_rust_version(&x) {
+                         Some(v) => v,
+                         None => {
+                             error!("Invalid value for --max-rust-version: {}, expected e.g. 1.56",
+                                    x)
+                         },
+                     }),
+            diff: opt_expanded("diff").map(PathBuf::from),
+            diff_json: matches.opt_present("diff-json"),
+            compare_checksums_file: opt_expanded("compare-checksums-file").map(PathBuf::from),
+            from_names,
+            cargo_lock,
+            reverify_after: matches.opt_str("reverify-after").map(|x| {
+                match parse_duration(&x) {
+                    Some(x) => x,
+                    None => {
+                        error!("Invalid value for --reverify-after: {}, expected e.g. 90s, 30m or 30d",
+                               x)
+                    },
+                }
+            }),
+            max_index_age: matches.opt_str("max-index-age").map(|x| {
+                match parse_duration(&x) {
+                    Some(x) => x,
+                    None => {
+                        error!("Invalid value for --max-index-age: {}, expected e.g. 12h or 2d",
+                               x)
+                    },
+                }
+            }),
+            json_lines_output: opt_expanded("json-lines-output"),
+            index_commit: matches.opt_str("index-commit"),
+            min_free_space: matches.opt_str("min-free-space").map(|x| {
+                match parse_size(&x) {
+                    Some(x) => x,
+                    None => {
+                        error!("Invalid value for --min-free-space: {}, expected e.g. 500M or 20G",
+                               x)
+                    },
+                }
+            }),
+            verify_contents: matches.opt_present("verify-contents"),
+            skip_unavailable: !matches.opt_present("no-skip-unavailable"),
+            sha256sums: matches.opt_present("sha256sums"),
+            stable_only: matches.opt_present("stable-only"),
+            max_archive_size: matches.opt_str("max-archive-size").map(|x| {
+                match parse_size(&x) {
+                    Some(x) => x,
+                    None => {
+                        error!("Invalid value for --max-archive-size: {}, expected e.g. 500M or 20G",
+                               x)
+                    },
+                }
+            }),
+            priority: opt_expanded("priority-file").map(|x| {
+                let names = read_names_file(Path::new(&x));
+                let mut ret = BTreeMap::new();
+                for (i, name) in names.into_iter().enumerate() {
+                    ret.entry(name).or_insert(i);
+                }
+                ret
+            }),
+            dependency_closure: matches.opt_present("dependency-closure"),
+            unix_socket: opt_expanded("unix-socket"),
+            etags: matches.opt_present("etags"),
+            verify_against_index: matches.opt_present("verify-against-index"),
+            audit_json: matches.opt_present("audit-json"),
+            only_missing_report: matches.opt_present("only-missing-report"),
+            verbose_errors: matches.opt_present("verbose-errors"),
+            sort_order: parse_opt(matches, "sort-order").unwrap_or(SortOrder::Index),
+            limit: parse_opt(matches, "limit"),
+            header_rules: opt_expanded("header-rules").map(|x| {
+                match header_rules::HeaderRules::read(Path::new(&x)) {
+                    Ok(x) => x,
+                    Err(e) => error!("Invalid value for --header-rules: {}", e),
+                }
+            }),
+            socks5: matches.opt_str("socks5").map(|x| {
+                match x.rsplit_once(':') {
+                    Some((host, port)) if !host.is_empty() &&
+                                          port.parse::<u16>().is_ok() => x,
+                    _ => error!("Invalid value for --socks5: {}, expected HOST:PORT", x),
+                }
+            }),
+            metrics_file: opt_expanded("metrics-file").map(PathBuf::from),
+            since: matches.opt_str("since").map(|x| match parse_date(&x) {
+                Some(x) => x,
+                None => {
+                    error!("Invalid value for --since: {}, expected a date like 2024-01-01 or 2024-01-01T12:00:00",
+                           x)
+                },
+            }),
+            skip_larger_than: matches.opt_str("skip-larger-than").map(|x| {
+                match parse_size(&x) {
+                    Some(x) => x,
+                    None => {
+                        error!("Invalid value for --skip-larger-than: {}, expected e.g. 500K or 20M",
+                               x)
+                    },
+                }
+            }),
+            prefer_orig_on_cdn_failure: matches.opt_present("prefer-orig-on-cdn-failure"),
+            pack: opt_expanded("pack").map(PathBuf::from),
+            unpack: opt_expanded("unpack").map(PathBuf::from),
+            head_check: matches.opt_present("head-check"),
+            shard: parse_opt(matches, "shard"),
+            name_prefix: parse_opt(matches, "name-prefix"),
+            index_tarball: opt_expanded("index-tarball").map(PathBuf::from),
+            file_mode: match matches.opt_str("file-mode") {
+                Some(x) => {
+                    match u32::from_str_radix(&x, 8) {
+                        Ok(x) if x <= 0o7777 => x,
+                        _ => error!("Invalid value for --file-mode: {}, expected an octal mode like 644", x),
+                    }
+                },
+                None => 0o644,
+            },
+            write_buffer_size: match matches.opt_str("write-buffer-size") {
+                Some(x) => {
+                    match parse_size(&x) {
+                        Some(x) if x > 0 && x <= usize::MAX as u64 => x as usize,
+                        _ => {
+                            error!("Invalid value for --write-buffer-size: {}, expected e.g. 64K or 1M",
+                                   x)
+                        },
+                    }
+                },
+                None => 64 << 10,
+            },
+            depends_on: parse_opt(matches, "depends-on"),
+            force: matches.opt_present("force"),
+            registries: {
+                let registries: Vec<registry::Registry> = matches
+                    .opt_strs("registry")
+                    .into_iter()
+                    .map(|x| match registry::Registry::parse(&expanded("registry", x)) {
+                             Ok(x) => x,
+                             Err(e) => error!("Invalid value for --registry: {}", e),
+                         })
+                    .collect();
+                if let Err(e) = registry::check_unique(&registries) {
+                    error!("Invalid value for --registry: {}", e);
+                }
+                registries
+            },
+            serve: matches.opt_str("serve"),
+            ip_version: match parse_opt(matches, "ip-version") {
+                Some(x) if x != 4 && x != 6 => {
+                    error!("Invalid value for --ip-version: {}, expected 4 or 6",
+                           x)
+                },
+                x => x,
+            },
+            tiers: matches
+                .opt_strs("tier")
+                .into_iter()
+                .map(|x| match tiers::Tier::parse(&expanded("tier", x)) {
+                         Ok(x) => x,
+                         Err(e) => error!("Invalid value for --tier: {}", e),
+                     })
+                .collect(),
+        }
+    }
+}
+
+/// Read a file with one crate name per line, skipping empty lines and lines
+/// starting with #
+fn read_names_file(path: &Path) -> Vec<String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => error!("Error reading {}: {}", path.to_string_lossy(), e),
+    };
+    let mut ret = Vec::new();
+    for name in contents.lines().map(|x| x.trim()) {
+        if name.is_empty() || name.starts_with('#') {
+            continue;
+        }
+        if !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            error!("Invalid crate name {} in {}", name, path.to_string_lossy());
+        }
+        ret.push(name.to_string());
+    }
+    ret
+}
+
+/// Parse an author of the form `Name <email>`
+fn parse_author(author: &str) -> Option<(String, String)> {
+    let author = author.trim();
+    if !author.ends_with('>') {
+        return None;
+    }
+    let start = author.find('<')?;
+    let name = author[..start].trim();
+    let email = author[start + 1..author.len() - 1].trim();
+    if name.is_empty() || email.is_empty() || email.contains(['<', '>']) {
+        return None;
+    }
+    Some((name.to_string(), email.to_string()))
+}
+
+/// Parse a duration given as a number followed by s, m, h or d. Plain numbers
+/// are seconds.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: u64 = number.parse().ok()?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(multiplier)?))
+}
+
+/// Parse a UTC date of the form YYYY-MM-DD, optionally followed by a time of
+/// the form HH:MM:SS after a T or a space, into seconds since the unix epoch
+fn parse_date(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let s = s.strip_suffix('Z').unwrap_or(s);
+    let (date, time) = match s.find(['T', ' ']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let number = |x: Option<&str>, len| -> Option<i64> {
+        let x = x?;
+        if x.len() != len || !x.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        x.parse().ok()
+    };
+
+    let mut parts = date.split('-');
+    let (year, month, day) = (number(parts.next(), 4)?,
+                              number(parts.next(), 2)?,
+                              number(parts.next(), 2)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if parts.next().is_some() || !(1..=12).contains(&month) || day < 1 ||
+       day > month_days[month as usize - 1] {
+        return None;
+    }
+    let seconds = match time {
+        Some(time) => {
+            let mut parts = time.split(':');
+            let (hours, minutes, seconds) = (number(parts.next(), 2)?,
+                                             number(parts.next(), 2)?,
+                                             number(parts.next(), 2)?);
+            if parts.next().is_some() || hours > 23 || minutes > 59 || seconds > 59 {
+                return None;
+            }
+            hours * 60 * 60 + minutes * 60 + seconds
+        },
+        None => 0,
+    };
+
+    /* The number of days since 1970-01-01, counting in eras of 400 years
+     * that start in March so that leap days come at the end of a year */
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 +
+                     day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Some(days * 24 * 60 * 60 + seconds)
+}
+
+/// Parse a Rust version of the form X, X.Y or X.Y.Z, where missing parts are 0
+fn parse_rust_version(s: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = s.trim().split('.').map(|x| x.parse::<u32>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+    let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+/// Parse a number of bytes, optionally followed by K, M, G or T for KiB, MiB,
+/// GiB or TiB.
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let number: u64 = number.parse().ok()?;
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+    number.checked_mul(1 << shift)
+}
+
+/// Whether the version has a semver pre-release part, like 1.0.0-beta.1. The
+/// build metadata after a + may contain dashes too, and doesn't count.
+fn is_prerelease(vers: &str) -> bool {
+    let vers = vers.split('+').next().unwrap_or(vers);
+    vers.contains('-')
+}
+
+/// Replace the $VAR and ${VAR} references in the string with the values of
+/// the environment variables, failing if any of them isn't set
+fn expand_env(s: &str) -> Result<String, String> {
+    let mut ret = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        ret.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, after) = if rest.starts_with('{') {
+            match rest.find('}') {
+                Some(end) => (&rest[1..end], &rest[end + 1..]),
+                None => return Err(format!("unterminated ${{ in {}", s)),
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        /* A $ that isn't followed by a name is kept as it is */
+        if name.is_empty() && !rest.starts_with('{') {
+            ret.push('$');
+            continue;
+        }
+        match env::var(name) {
+            Ok(x) => ret.push_str(&x),
+            Err(_) => {
+                return Err(format!("environment variable {} in {} is not set",
+                                   name,
+                                   s))
+            },
+        }
+        rest = after;
+    }
+    ret.push_str(rest);
+    Ok(ret)
+}
+
+/// Parse the value of the given option, exiting if the value is invalid
+fn parse_opt<T>(matches: &getopts::Matches, name: &str) -> Option<T>
+    where T: FromStr,
+          T::Err: Display
+{
+    match matches.opt_get(name) {
+        Ok(x) => x,
+        Err(e) => error!("Invalid value for --{}: {}", name, e),
+    }
+}
+
+/// The output formats for --list-crates
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ListFormat {
+    Csv,
+    Json,
+}
+impl FromStr for ListFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "csv" => Ok(ListFormat::Csv),
+            "json" => Ok(ListFormat::Json),
+            _ => Err(format!("unknown format {}, expected csv or json", s)),
+        }
+    }
+}
+
+/// The orders crates can be fetched in, for --sort-order
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    /// The order of the index directories, with the versions of each crate
+    /// sorted. Unlike the others this doesn't need the whole index to be read
+    /// before the first crate is fetched.
+    Index,
+    Name,
+    Version,
+}
+impl FromStr for SortOrder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "index" => Ok(SortOrder::Index),
+            "name" => Ok(SortOrder::Name),
+            "version" => Ok(SortOrder::Version),
+            _ => Err(format!("unknown order {}, expected index, name or version", s)),
+        }
+    }
+}
+impl SortOrder {
+    /// Compare two crates, comparing versions by semver where possible. Index
+    /// compares like Name, since it is only used for the versions in one
+    /// index file.
+    fn compare(self, a: &Crate, b: &Crate) -> Ordering {
+        let versions = match (version_req::Version::parse(&a.vers),
+                              version_req::Version::parse(&b.vers)) {
+            (Some(x), Some(y)) => x.cmp(&y).then_with(|| a.vers.cmp(&b.vers)),
+            _ => a.vers.cmp(&b.vers),
+        };
+        match self {
+            SortOrder::Index | SortOrder::Name => a.name.cmp(&b.name).then(versions),
+            SortOrder::Version => versions.then_with(|| a.name.cmp(&b.name)),
+        }
+    }
+}
+
+/// Represents information about a single .crate file
+#[derive(Deserialize, Serialize, Debug, Clone, Eq)]
+pub struct Crate {
+    pub name: String,
+    pub vers: String,
+    pub yanked: bool,
+    pub cksum: String,
+    /// The algorithm of cksum, absent means sha256
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cksum_algo: Option<checksum::Algorithm>,
+    /// The index schema version of this entry, absent means version 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<u32>,
+    /// The oldest Rust version the crate supports, if it declares one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rust_version: Option<String>,
+    /// The line this crate was read from, if it is needed for --save-metadata
+    #[serde(skip)]
+    index_line: Option<String>,
+    /// The dependencies of the crate, only kept for --dependency-closure
+    #[serde(default, skip_serializing)]
+    deps: Vec<deps::Dependency>,
+}
+impl Crate {
+    fn new(name: &str, vers: &str) -> Self {
+        Crate {
+            name: name.to_string(),
+            vers: vers.to_string(),
+            yanked: true,
+            cksum: String::new(),
+            cksum_algo: None,
+            v: None,
+            rust_version: None,
+            index_line: None,
+            deps: Vec::new(),
+        }
+    }
+    fn checksum_algorithm(&self) -> checksum::Algorithm {
+        self.cksum_algo.unwrap_or_default()
+    }
+    /// The URL of the crate according to the dl field of a config.json
+    ///
+    /// As in cargo, dl may have the markers {crate}, {version}, {prefix},
+    /// {lowerprefix} and {sha256-checksum}, and without any of them
+    /// /{crate}/{version}/download is appended to it.
+    fn registry_url(&self, dl: &str) -> String {
+        let markers = ["{crate}",
+                       "{version}",
+                       "{prefix}",
+                       "{lowerprefix}",
+                       "{sha256-checksum}"];
+        if !markers.iter().any(|x| dl.contains(x)) {
+            return format!("{}/{}/{}/download", dl, self.name, self.vers);
+        }
+        let prefix = index_prefix(&self.name);
+        dl.replace("{crate}", &self.name)
+            .replace("{version}", &self.vers)
+            .replace("{lowerprefix}", &prefix.to_ascii_lowercase())
+            .replace("{prefix}", &prefix)
+            .replace("{sha256-checksum}", &self.cksum)
+    }
+    /// Return the URL which should be used to download the crate from
+    ///
+    /// static.crates.io only hosts crates.io crates, so other registries
+    /// download from the URL in their config.json unless given --dl-template
+    fn download_url(&self, config: &ConfigJsonFile, settings: &Settings) -> String {
+        if settings.use_orig_dl {
+            self.registry_url(&config.dl)
+        } else if let Some(ref template) = settings.dl_template {
+            template
+                .replace("{crate}", &self.name)
+                .replace("{version}", &self.vers)
+        } else if settings.index_url != CRATES_IO_INDEX {
+            self.registry_url(&config.dl)
+        } else {
+            format!("https://static.crates.io/crates/{}/{}-{}.crate",
+                    self.name,
+                    self.name,
+                    self.vers)
+        }
+    }
+}
+impl PartialEq for Crate {
+    fn eq(&self, other: &Crate) -> bool {
+        self.name == other.name && self.vers == other.vers
+    }
+}
+impl Ord for Crate {
+    fn cmp(&self, other: &Crate) -> Ordering {
+        match self.name.cmp(&other.name) {
+            Ordering::Equal => self.vers.cmp(&other.vers),
+            x => x,
+        }
+    }
+}
+impl PartialOrd for Crate {
+    fn partial_cmp(&self, other: &Crate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Something that happened to a crate during fetch_crates, reported to the
+/// callback given to it
+#[derive(Debug, PartialEq)]
+pub enum MirrorEvent<'a> {
+    /// A download is starting
+    Started { krate: &'a Crate, url: &'a str },
+    /// A crate was downloaded and verified, duration is the time the
+    /// download itself took
+    Downloaded {
+        krate: &'a Crate,
+        bytes: usize,
+        duration: Duration,
+    },
+    /// A crate was already in the archive
+    Skipped { krate: &'a Crate },
+    /// The downloaded crate didn't match the checksum in the index
+    Mismatch {
+        krate: &'a Crate,
+        expected: &'a str,
+        actual: &'a str,
+    },
+    /// The crate could not be found upstream
+    Unavailable { krate: &'a Crate },
+    /// The crate wasn't downloaded because it is larger than
+    /// --skip-larger-than
+    TooLarge { krate: &'a Crate, bytes: u64 },
+    /// Downloading the crate from the CDN failed, and it is downloaded from
+    /// url from config.json instead
+    Fallback {
+        krate: &'a Crate,
+        url: &'a str,
+        error: &'a str,
+    },
+    /// The pause file appeared, and krate is the next crate that will be
+    /// fetched once it is removed
+    Paused { krate: &'a Crate },
+    /// The pause file was removed, and fetching continues with krate
+    Resumed { krate: &'a Crate },
+    /// Transferring the crate failed, action is e.g. "downloading"
+    Failed {
+        krate: &'a Crate,
+        action: &'a str,
+        error: &'a str,
+    },
+}
+
+/// Print the event, this is the output of the command line program
+///
+/// The index is read on its own thread, which prints warnings while crates
+/// are fetched. Every line is written with a single println!, which holds
+/// the lock on stdout for the whole line, so lines from the two threads
+/// never mix. The lists that span several lines are only printed once the
+/// index has been read.
+fn print_event(event: MirrorEvent, verbose: bool) {
+    match event {
+        MirrorEvent::Started { krate, url } => {
+            println!("Fetching {} version {} from {}", krate.name, krate.vers, url)
+        },
+        MirrorEvent::Downloaded {
+            krate,
+            bytes,
+            duration,
+        } => {
+            if verbose {
+                println!("Downloaded {}-{}, {} bytes in {:.2}s ({:.2} MB/s)",
+                         krate.name,
+                         krate.vers,
+                         bytes,
+                         duration.as_secs_f64(),
+                         megabytes_per_second(bytes, duration));
+            }
+        },
+        MirrorEvent::Skipped { .. } => (),
+        MirrorEvent::Mismatch {
+            krate,
+            expected,
+            actual,
+        } => {
+            println!("Warning: Checksum mismatch in {}-{}. Expected hash {} but received file with hash {}",
+                     krate.name,
+                     krate.vers,
+                     expected,
+                     actual)
+        },
+        MirrorEvent::Unavailable { krate } => {
+            println!("Warning: crate {}-{} could not be downloaded!",
+                     krate.name,
+                     krate.vers)
+        },
+        MirrorEvent::TooLarge { krate, bytes } => {
+            println!("Info: Skipping {}-{}, which is {} bytes, larger than --skip-larger-than",
+                     krate.name,
+                     krate.vers,
+                     bytes)
+        },
+        MirrorEvent::Fallback { krate, url, error } => {
+            println!("Info: Downloading {}-{} from the CDN failed: {}, retrying from {}",
+                     krate.name,
+                     krate.vers,
+                     error,
+                     url)
+        },
+        MirrorEvent::Paused { .. } => {
+            println!("Info: Pausing until {} is removed from the archive directory",
+                     PAUSE_FILE)
+        },
+        MirrorEvent::Resumed { .. } => println!("Info: {} was removed, resuming", PAUSE_FILE),
+        MirrorEvent::Failed {
+            krate,
+            action,
+            error,
+        } => {
+            println!("Warning: Error {} {}-{}: {}",
+                     action,
+                     krate.name,
+                     krate.vers,
+                     error)
+        },
+    }
+}
+
+fn megabytes_per_second(bytes: usize, duration: Duration) -> f64 {
+    bytes as f64 / 1_000_000.0 / duration.as_secs_f64().max(0.001)
+}
+
+/// The problems encountered by fetch_crates, used to determine the
+/// exit code
+#[derive(Default)]
+pub struct FetchResults {
+    /// Crates whose checksum didn't match, along with the received hash
+    checksum_mismatches: Vec<(Crate, String)>,
+    /// Crates that crates.io reports as not found
+    unavailable: Vec<Crate>,
+    /// Crates that failed to download, along with the error
+    download_failures: Vec<(Crate, String)>,
+    /// The filenames of crates verified to be in the archive or staging
+    /// directory, along with their checksum and the algorithm it is made with
+    verified: Vec<(String, checksum::Algorithm, String)>,
+    /// Whether the run was stopped by --max-duration, --limit or
+    /// --min-free-space before all crates were processed
+    stopped_early: bool,
+    /// Whether the run was stopped because there were more failures than
+    /// --max-failures
+    aborted: bool,
+    /// Crates that took longer than --slow-download to download, along with
+    /// their size and how long they took
+    slow_downloads: Vec<(Crate, usize, Duration)>,
+    /// Crates that matched their checksum but aren't valid tarballs, along
+    /// with the problem
+    malformed: Vec<(Crate, String)>,
+    /// The crates that were downloaded and saved
+    downloaded: stats::DownloadStats,
+    /// The number of crates from the index that were gone through
+    crates: u64,
+    /// Crates skipped for being larger than --skip-larger-than, along with
+    /// their size
+    too_large: Vec<(Crate, u64)>,
+    /// Crates downloaded from the URL in config.json because downloading
+    /// them from the CDN failed, with --prefer-orig-on-cdn-failure
+    cdn_fallbacks: Vec<Crate>,
+    /// Crates that weren't fetched because their name or version can't be
+    /// used in a file name, along with the problem
+    invalid_names: Vec<(Crate, String)>,
+}
+impl FetchResults {
+    /// Add the index lines skipped while reading the crates, so that crates
+    /// that can't be stored safely count for the exit code like the ones
+    /// fetch_crates skips
+    fn add_skipped(&mut self, skipped: SkippedLines) {
+        if skipped.malformed > 0 {
+            println!("Warning: Skipped {} lines in the index that could not be parsed",
+                     skipped.malformed);
+        }
+        if !skipped.invalid_names.is_empty() {
+            println!("Warning: Skipped {} crates in the index because they can't be stored safely",
+                     skipped.invalid_names.len());
+        }
+        self.invalid_names.extend(skipped.invalid_names);
+    }
+
+    /// Record that transferring the crate failed, or exit if in strict mode
+    fn network_failure(&mut self,
+                       c: &Crate,
+                       action: &str,
+                       e: String,
+                       settings: &Settings,
+                       on_event: &mut dyn FnMut(MirrorEvent)) {
+        if settings.strict_mode {
+            error!(exit EXIT_NETWORK,
+                   "Error {} {}-{}: {}",
+                   action,
+                   c.name,
+                   c.vers,
+                   e);
+        }
+        on_event(MirrorEvent::Failed {
+                     krate: c,
+                     action,
+                     error: &e,
+                 });
+        self.download_failures.push((c.clone(), e));
+    }
+
+    /// The exit code for the run. If there are several kinds of problems,
+    /// stopping early takes precedence over network failures, which take
+    /// precedence over unavailable crates, which take precedence over
+    /// checksum mismatches and crates with invalid names. Both of the latter
+    /// mean that the index can't be trusted.
+    pub fn exit_code(&self) -> i32 {
+        if self.stopped_early {
+            EXIT_PARTIAL
+        } else if !self.download_failures.is_empty() {
+            EXIT_NETWORK
+        } else if !self.unavailable.is_empty() {
+            EXIT_UNAVAILABLE
+        } else if !self.checksum_mismatches.is_empty() || !self.invalid_names.is_empty() {
+            EXIT_MISMATCH
+        } else {
+            EXIT_OK
+        }
+    }
+}
+
+/// The command line options accepted by the program
+fn options() -> getopts::Options {
+    let mut opts = getopts::Options::new();
+    opts.optflag("", "no-update-index", "Don't update the index");
+    opts.optflag("", "yanked", "Also download yanked .crate files");
+    opts.optflag("",
+                 "no-check-sums",
+                 "Don't verify the checksums of already downloaded .crate files, only of the ones downloaded in this run");
+    opts.optflag("",
+                 "checksum-only-new",
+                 "same as --no-check-sums");
+    opts.optopt("",
+                "replace",
+                "Specify the URL to replace the index repository dl url",
+                "URL");
+    opts.optflag("", "strict", "exit immediately on any error/checksum mismatch");
+    opts.optflag("", "download-old", "download old versions of crates, default is to only download newest version of every crate");
+    opts.optflag("", "use-orig-dl", "download from the URL specified in the upstream index repository. May help if unable to download crates, but will likely cause the download counter to be incremented and should normally not be used.");
+    opts.optopt("",
+                "user-agent",
+                "the User-Agent to send when downloading crates, default is crates-ectype/VERSION",
+                "STRING");
+    opts.optflag("",
+                 "force-verify",
+                 "verify the checksums of all already downloaded crates, even those that haven't changed since they were last verified");
+    opts.optopt("",
+                "staging",
+                "download new crates into DIR, and only move them into the archive once every crate has been downloaded and verified",
+                "DIR");
+    opts.optopt("",
+                "max-crate-size",
+                "abort downloads of crates larger than BYTES, default is 100 MiB",
+                "BYTES");
+    opts.optopt("",
+                "index-url",
+                "mirror the registry with the index repository at URL instead of crates.io. Crates are downloaded from the dl URL in its config.json",
+                "URL");
+    opts.optopt("",
+                "registry-name",
+                "the name of the mirrored registry used in output, default is crates.io",
+                "NAME");
+    opts.optopt("",
+                "list-crates",
+                "print the crates in the index that would be mirrored, after filtering, as csv or json, and exit without downloading anything",
+                "FORMAT");
+    opts.optopt("",
+                "commit-author",
+                "the author of the commit made by --replace, default is \"crates-ectype <crates-ectype@localhost>\"",
+                "\"NAME <EMAIL>\"");
+    opts.optopt("",
+                "commit-message",
+                "the message of the commit made by --replace",
+                "MSG");
+    opts.optopt("",
+                "push",
+                "after updating the index (and replacing the DL url), push the index repository to the git repository at URL, which the index can then be served from",
+                "URL");
+    opts.optflag("",
+                 "force-push",
+                 "with --push, replace the branch at URL even if it has commits the index doesn't, which is needed after every index update since that resets the index to upstream");
+    opts.optopt("",
+                "cacert",
+                "verify TLS certificates against the CA bundle at PATH, for both downloads and git",
+                "PATH");
+    opts.optopt("",
+                "capath",
+                "verify TLS certificates against the CA certificates in DIR, for both downloads and git",
+                "DIR");
+    opts.optflag("",
+                 "insecure",
+                 "don't verify TLS certificates at all. Only use this if you have some other way of trusting the downloads");
+    opts.optflag("",
+                 "dedup",
+                 "after downloading, hardlink verified crate files with identical contents to save space");
+    opts.optopt("",
+                "expected-index-commit",
+                "exit with an error if the index isn't at the commit HASH after updating it, so that only a reviewed state of the index is mirrored",
+                "HASH");
+    opts.optflag("",
+                 "quiet-existing",
+                 "don't print anything about crates that are already in the archive and verify correctly, only about new downloads, mismatches and the final summary");
+    opts.optopt("",
+                "dl-template",
+                "download crates from URL, where {crate} and {version} are replaced with the name and version of the crate, e.g. https://my-cdn/{crate}/{crate}-{version}.crate",
+                "URL");
+    opts.optopt("",
+                "s3-bucket",
+                "store the crates in the S3 bucket BUCKET instead of the archive directory, which then only holds the index. Credentials are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY",
+                "BUCKET");
+    opts.optopt("",
+                "s3-endpoint",
+                "the URL of the S3 compatible service to use with --s3-bucket, default is https://s3.amazonaws.com",
+                "URL");
+    opts.optopt("",
+                "s3-region",
+                "the region of the bucket given by --s3-bucket, default is us-east-1",
+                "REGION");
+    opts.optopt("",
+                "s3-prefix",
+                "prefix the keys of crates stored with --s3-bucket with PREFIX, e.g. crates/",
+                "PREFIX");
+    opts.optopt("",
+                "verify-jobs",
+                "verify the checksums of already downloaded crates on N threads, default is 1",
+                "N");
+    opts.optflag("",
+                 "only-yanked",
+                 "only download yanked .crate files, by default only the newest yanked version of every crate");
+    opts.optopt("",
+                "quarantine",
+                "save downloads whose checksum doesn't match the index in DIR, along with a json file with the expected and received hash, instead of discarding them",
+                "DIR");
+    opts.optflag("",
+                 "allow-stale-index",
+                 "if updating the index fails, continue with the index that is already checked out instead of exiting");
+    opts.optflag("",
+                 "save-metadata",
+                 "save the index entry of every mirrored crate, with its dependencies and features, in metadata/NAME/VERSION.json in the archive");
+    opts.optflag("",
+                 "provenance",
+                 "record the URL, status and Server header of the response, the time and the sha256sum of every crate downloaded, in provenance/NAME/VERSION.json in the archive");
+    opts.optopt("",
+                "max-index-age",
+                "warn if the commit checked out in the index is older than DURATION, e.g. 2d, or exit with an error in strict mode. Catches a mirror that keeps running on an index that is no longer updated",
+                "DURATION");
+    opts.optopt("",
+                "max-duration",
+                "stop starting new downloads once the run has taken longer than DURATION, e.g. 6h, and exit with code 5. The next run continues where this one stopped",
+                "DURATION");
+    opts.optopt("",
+                "max-redirects",
+                "follow at most N redirects per download, default is 10",
+                "N");
+    opts.optmulti("",
+                  "redirect-host",
+                  "allow downloads to be redirected to HOST, can be given multiple times. By default downloads can only be redirected to the same host, except that crates.io downloads can be redirected anywhere",
+                  "HOST");
+    opts.optflag("",
+                 "shallow",
+                 "only clone and fetch the newest commit of the index instead of its whole history, which is much faster on the first run");
+    opts.optopt("",
+                "max-failures",
+                "stop the run once more than N crates have failed to download or had checksum mismatches, since that usually means something is broken rather than the crates",
+                "N");
+    opts.optflag("",
+                 "verify-tarball",
+                 "check that every downloaded crate is a valid gzipped tarball, in addition to checking its checksum");
+    opts.optflag("",
+                 "no-auto-repair",
+                 "exit with an error when an already downloaded crate fails checksum verification, instead of downloading it again");
+    opts.optflag("",
+                 "stable-only",
+                 "skip pre-release versions such as 1.0.0-beta.1, so that the newest stable version of every crate is mirrored. Crates with only pre-release versions are left out");
+    opts.optopt("",
+                "max-rust-version",
+                "skip crate versions that require a newer Rust than VERSION, e.g. 1.56, and mirror the newest version that supports it instead. Versions that don't declare a rust version are kept",
+                "VERSION");
+    opts.optopt("",
+                "diff",
+                "compare the crate files in the archive with the ones in OTHER_DIR and exit, listing the ones that were added, removed or changed. Exits with code 2 if there are differences",
+                "OTHER_DIR");
+    opts.optopt("",
+                "compare-checksums-file",
+                "check the crate files in the archive against the sha256sums in FILE, e.g. the SHA256SUMS of another mirror, instead of the index, and exit, listing the files that don't match and the ones that are missing. Exits with code 2 if any file doesn't match and 5 if any is missing",
+                "FILE");
+    opts.optflag("",
+                 "diff-json",
+                 "print the report of --diff as json");
+    opts.optopt("",
+                "from-names-file",
+                "only mirror the crates listed in PATH, with one crate name per line. Only the index files of those crates are read, which is much faster than reading the whole index",
+                "PATH");
+    opts.optopt("",
+                "from-cargo-lock",
+                "only mirror the exact versions of the crates that the Cargo.lock at PATH depends on, including yanked ones, and check their checksums against the index",
+                "PATH");
+    opts.optopt("",
+                "reverify-after",
+                "hash already downloaded crates again if they were last verified longer than DURATION ago, e.g. 30d, even if they haven't changed. This spreads the verification of a large archive over several runs",
+                "DURATION");
+    opts.optopt("",
+                "index-commit",
+                "mirror the index as it was at the commit or tag REV instead of the newest commit, for a mirror of a known point in time",
+                "REV");
+    opts.optopt("",
+                "json-lines-output",
+                "append a line of json to PATH for every crate that is downloaded, skipped or fails, as it happens, or write them to stderr if PATH is -",
+                "PATH");
+    opts.optopt("",
+                "min-free-space",
+                "don't start downloading if less than SIZE, e.g. 20G, is free in the archive directory, and stop the run once it drops below that and exit with code 5. The next run continues where this one stopped",
+                "SIZE");
+    opts.optflag("",
+                 "verify-contents",
+                 "check that the name and version in the Cargo.toml of every downloaded crate are the ones that were requested, and treat the download as failed otherwise");
+    opts.optflag("",
+                 "no-skip-unavailable",
+                 "try to download the crates that are listed in the crates.io index but known to be unavailable, instead of leaving them out. Useful for other registries that may have crates with the same names");
+    opts.optflag("",
+                 "sha256sums",
+                 "keep a SHA256SUMS file in the archive with the sha256sum of every verified crate, which can be checked with sha256sum -c");
+    opts.optopt("",
+                "max-archive-size",
+                "keep the crates in the archive under SIZE, e.g. 50G, by removing the ones that were downloaded longest ago to make room for new ones. Crates needed by the current run are kept, and the run stops once nothing else can be removed. Only useful when the selected crates don't all fit",
+                "SIZE");
+    opts.optopt("",
+                "priority-file",
+                "mirror the crates listed in PATH first, in the order they are listed with one crate name per line, and the rest by name afterwards. The whole index is read before downloading starts",
+                "PATH");
+    opts.optflag("",
+                 "dependency-closure",
+                 "also mirror the versions that the selected crates depend on, even if they would otherwise be left out, e.g. because they are yanked or old, so that cargo can resolve the selected crates from the mirror");
+    opts.optopt("",
+                "unix-socket",
+                "download crates through the HTTP proxy listening on the Unix domain socket at PATH instead of connecting to their host, keeping the URLs as they are. This doesn't affect updating the index or uploading to S3",
+                "PATH");
+    opts.optflag("",
+                 "etags",
+                 "remember the ETag of every downloaded crate, and ask the server whether crates that are already in the archive have changed with If-None-Match, downloading them again if they have. Only useful for registries whose crate files can change, and makes a request for every crate with a known ETag");
+    opts.optflag("",
+                 "verify-against-index",
+                 "instead of mirroring, check the archive against the index and report the crates whose file matches, doesn't match or is missing, without downloading anything. Exits with code 2 if any file doesn't match, and 5 if any is missing");
+    opts.optflag("",
+                 "audit-json",
+                 "print the report of --verify-against-index, --compare-checksums-file, --only-missing-report or --head-check as json");
+    opts.optflag("",
+                 "only-missing-report",
+                 "instead of mirroring, list the selected crates that have no file in the archive, without reading the files that are there or downloading anything. Exits with code 5 if any is missing");
+    opts.optflag("",
+                 "verbose-errors",
+                 "include the start of the response body in errors about failed downloads, which often explains the problem with custom CDNs. With --verbose the whole body is printed");
+    opts.optopt("",
+                "sort-order",
+                "fetch and list crates in ORDER, which is index (the default), name or version. By index, crates come in the order of the index directories, which puts names shorter than four characters first, and the versions of a crate are sorted. By name or version, the whole index is read before downloading starts",
+                "ORDER");
+    opts.optopt("",
+                "limit",
+                "stop once N crates have been downloaded in this run, not counting crates that were already in the archive, and exit with code 5 if there are more to download. The next run continues where this one stopped",
+                "N");
+    opts.optopt("",
+                "header-rules",
+                "send extra headers when downloading the crates matched by the rules in the toml FILE, e.g. for credentials that differ between crates. The first rule matching a crate is used",
+                "FILE");
+    opts.optopt("",
+                "socks5",
+                "download crates through the SOCKS5 proxy at HOST:PORT, e.g. 127.0.0.1:9050 for Tor, which also resolves their host names. libgit2 can't use SOCKS proxies, so this doesn't affect updating the index",
+                "HOST:PORT");
+    opts.optopt("",
+                "metrics-file",
+                "write metrics about the run to PATH in the Prometheus text format at the end of the run, for the textfile collector of node_exporter. A run that stops with an error leaves last_run_success at 0",
+                "PATH");
+    opts.optopt("",
+                "since",
+                "only consider the crates whose index file was changed by a commit after DATE, e.g. 2024-01-01 or 2024-01-01T12:00:00 in UTC, assuming that the others are already mirrored. Useful when there is no earlier run to continue from but the date of the last mirror is known",
+                "DATE");
+    opts.optopt("",
+                "skip-larger-than",
+                "don't download crates larger than SIZE, e.g. 20M, which are often ones that bundle binaries by accident. Their size is asked for with a HEAD request first, and they are listed at the end of the run",
+                "SIZE");
+    opts.optflag("",
+                 "prefer-orig-on-cdn-failure",
+                 "retry crates that fail to download from the CDN once from the download URL in config.json, which, unlike the CDN, counts the download. Without it, only one of them is used, see --use-orig-dl");
+    opts.optopt("",
+                "pack",
+                "after mirroring, also write every crate in the archive into the single tar OUTPUT, with a manifest of their checksums, for shipping the mirror as one file. The tar is gzipped if OUTPUT ends with .gz or .tgz",
+                "OUTPUT");
+    opts.optopt("",
+                "unpack",
+                "instead of mirroring, restore the crates in INPUT, written by --pack, into the archive, checking them against its manifest",
+                "INPUT");
+    opts.optflag("",
+                 "head-check",
+                 "instead of mirroring, make a HEAD request for every selected crate and report which ones can be downloaded, without downloading anything. Exits with code 3 if any can't be, and 4 if any request failed");
+    opts.optopt("",
+                "shard",
+                "only mirror part K of the index split into N parts, e.g. 1/4, for spreading a mirror over N machines with their own archives. The parts are disjoint and together have every crate, as long as every machine is given the same N",
+                "K/N");
+    opts.optopt("",
+                "name-prefix",
+                "only mirror the crates whose name starts with a character in RANGE, e.g. a-m or s",
+                "RANGE");
+    opts.optopt("",
+                "index-tarball",
+                "read the index from the snapshot in the tar PATH instead of cloning or updating it with git, e.g. for air-gapped machines. The tar is gzipped if PATH ends with .gz or .tgz, and may have the index in a single top-level directory",
+                "PATH");
+    opts.optopt("",
+                "file-mode",
+                "give the crate files that are written, including the ones restored by --unpack, the permissions MODE in octal, default is 644, instead of ones depending on the umask. This has no effect on Windows",
+                "MODE");
+    opts.optopt("",
+                "write-buffer-size",
+                "write crate files SIZE bytes at a time, default is 64K. Larger writes can be faster on network filesystems",
+                "SIZE");
+    opts.optopt("",
+                "depends-on",
+                "only mirror the versions of crates that list a dependency on the crate NAME, of any kind. With NAME@REQ, e.g. tokio@1, only the ones whose requirement allows a version of it that REQ allows too",
+                "NAME[@REQ]");
+    opts.optflag("",
+                 "force",
+                 "when the index repository has uncommitted changes or a detached HEAD, e.g. from checking out another commit by hand, discard them before updating it instead of refusing to update it");
+    opts.optmulti("",
+                  "registry",
+                  "mirror the registry with the index repository at INDEX-URL into the directory NAME of the archive, instead of mirroring a single registry. Can be given several times, each registry is mirrored in turn with the same options",
+                  "NAME=INDEX-URL");
+    opts.optopt("",
+                "serve",
+                "serve the mirror as a sparse registry over HTTP on ADDR, e.g. 127.0.0.1:8080, instead of mirroring, for trying it out with cargo",
+                "ADDR");
+    opts.optopt("",
+                "ip-version",
+                "only download crates over IPv4 or IPv6, given as 4 or 6, for networks where one of them is unreliable. By default either is used. This doesn't affect updating the index",
+                "VERSION");
+    opts.optmulti("",
+                  "tier",
+                  "store crates matching RULE in DIR instead of the archive directory, where RULE is a comma separated list of crate name patterns, or @FILE for a file with one crate name per line. Can be given multiple times, the first matching tier is used",
+                  "DIR=RULE");
+    opts.optflag("v",
+                 "verbose",
+                 "print more details, such as the size and speed of every download");
+    opts.optopt("",
+                "slow-download",
+                "list crates that took longer than DURATION to download at the end of the run, default is 30s",
+                "DURATION");
+    opts.optopt("",
+                "config",
+                "read options from the TOML file at PATH, where the keys are the names of long options, e.g. yanked = true or max-crate-size = 1000000. Options given on the command line take precedence",
+                "PATH");
+    opts.optflag("h", "help", "print the help menu");
+    opts.optflag("", "version", "print program version");
+    opts.optflag("",
+                 "dump-config",
+                 "print the settings in effect after reading --config and the command line, as json, and exit without doing anything");
+    opts.optflag("",
+                 "self-test",
+                 "mirror a tiny registry made up on the spot into a temporary directory, checking that the crates are read, downloaded, verified and skipped on the next run as they should be, without using the network. No archive location is needed");
+    opts
+}
+
+/// Parse the command line arguments, along with the options in the --config
+/// file if one is given
+fn parse_args(opts: &getopts::Options, args: &[String]) -> getopts::Matches {
+    let matches = match opts.parse(args) {
+        Ok(x) => x,
+        Err(e) => error!("Error parsing options: {}", e),
+    };
+
+    let path = match matches.opt_str("config") {
+        Some(x) => PathBuf::from(x),
+        None => return matches,
+    };
+
+    /* Options from the file are only used if they weren't given on the
+     * command line, so they can just be parsed together with it */
+    let mut args = args.to_vec();
+    args.extend(config_file_args(&path, &matches));
+    match opts.parse(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Error parsing options in {}: {}", path.to_string_lossy(), e)
+        },
+    }
+}
+
+/// Turn the options in the config file into command line arguments, skipping
+/// those already given on the command line
+///
+/// The archive directory can be given with the key `archive`.
+fn config_file_args(path: &Path, matches: &getopts::Matches) -> Vec<String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => error!("Error reading {}: {}", path.to_string_lossy(), e),
+    };
+    let table: toml::value::Table = match toml::from_str(&contents) {
+        Ok(x) => x,
+        Err(e) => error!("Error parsing {}: {}", path.to_string_lossy(), e),
+    };
+
+    let mut ret = Vec::new();
+    for (key, value) in table {
+        if key == "archive" {
+            match value {
+                toml::Value::String(ref x) if matches.free.is_empty() => {
+                    ret.push(x.clone())
+                },
+                toml::Value::String(_) => (),
+                _ => {
+                    error!("Error in {}: archive must be a string",
+                           path.to_string_lossy())
+                },
+            }
+            continue;
+        }
+        if key == "config" || !matches.opt_defined(&key) {
+            error!("Error in {}: unknown option {}", path.to_string_lossy(), key);
+        }
+        if matches.opt_present(&key) {
+            continue;
+        }
+
+        let values = match value {
+            toml::Value::Array(x) => x,
+            x => vec![x],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => ret.push(format!("--{}", key)),
+                toml::Value::Boolean(false) => (),
+                toml::Value::String(x) => ret.push(format!("--{}={}", key, x)),
+                toml::Value::Integer(x) => ret.push(format!("--{}={}", key, x)),
+                toml::Value::Float(x) => ret.push(format!("--{}={}", key, x)),
+                _ => {
+                    error!("Error in {}: invalid value for {}",
+                           path.to_string_lossy(),
+                           key)
+                },
+            }
+        }
+    }
+    ret
+}
+
+/// Run crates-ectype with the command line arguments of the process
+pub fn run() {
+    let run_start = Instant::now();
+    let args: Vec<String> = env::args().collect();
+
+    let opts = options();
+
+    let matches = parse_args(&opts, &args[1..]);
+
+    let settings = Settings::from(&matches);
+
+    if settings.help {
+        let brief = "Usage: crates-ectype [options] ARCHIVE-DIRECTORY";
+        print!("{}", opts.usage(brief));
+        return;
+    }
+
+    if settings.version {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        println!("{}", env!("CARGO_PKG_HOMEPAGE"));
+        return;
+    }
+
+    if settings.dump_config {
+        println!("{}",
+                 serde_json::to_string_pretty(&settings).expect("Error encoding settings"));
+        return;
+    }
+
+    if settings.self_test {
+        match self_test::run() {
+            Ok(()) => println!("Self-test passed"),
+            Err(e) => error!("Self-test failed: {}", e),
+        }
+        return;
+    }
+
+    match matches.free.len() {
+        0 => error!("You must specify an archive location."),
+        1 => (),
+        _ => error!("You cannot specify more than one archive location."),
+    }
+
+    /* Comparing archives doesn't need the index */
+    if let Some(ref other) = settings.diff {
+        let diff = diff::diff_archives(&settings.archive, other);
+        if settings.diff_json {
+            println!("{}",
+                     serde_json::to_string_pretty(&diff)
+                         .expect("Error encoding archive diff"));
+        } else {
+            print!("{}", diff.to_text());
+        }
+        ::std::process::exit(if diff.is_empty() {
+                                 EXIT_OK
+                             } else {
+                                 EXIT_MISMATCH
+                             });
+    }
+
+    /* Neither does checking it against a SHA256SUMS file */
+    if let Some(ref sums) = settings.compare_checksums_file {
+        let audit = match audit::compare_checksums(sums, &settings) {
+            Ok(x) => x,
+            Err(e) => error!("{}", e),
+        };
+        if settings.audit_json {
+            println!("{}",
+                     serde_json::to_string_pretty(&audit)
+                         .expect("Error encoding checksums report"));
+        } else {
+            print!("{}", audit.to_text());
+        }
+        ::std::process::exit(audit.exit_code());
+    }
+
+    if settings.s3_bucket.is_some() {
+        if settings.staging.is_some() {
+            error!("--staging cannot be used with --s3-bucket");
+        }
+        if settings.dedup {
+            error!("--dedup cannot be used with --s3-bucket");
+        }
+        if !settings.tiers.is_empty() {
+            error!("--tier cannot be used with --s3-bucket");
+        }
+        if settings.sha256sums {
+            error!("--sha256sums cannot be used with --s3-bucket");
+        }
+    }
+    if settings.staging.is_some() && !settings.tiers.is_empty() {
+        error!("--tier cannot be used with --staging");
+    }
+    if settings.pack.is_some() {
+        if settings.s3_bucket.is_some() {
+            error!("--pack cannot be used with --s3-bucket");
+        }
+        if settings.staging.is_some() {
+            error!("--pack cannot be used with --staging");
+        }
+        /* Only crates whose checksums were checked in this run are known
+         * to be in the archive */
+        if !settings.check_sums {
+            error!("--pack cannot be used with --no-check-sums");
+        }
+    }
+    if settings.max_archive_size.is_some() {
+        if settings.s3_bucket.is_some() {
+            error!("--max-archive-size cannot be used with --s3-bucket");
+        }
+        if settings.staging.is_some() {
+            error!("--max-archive-size cannot be used with --staging");
+        }
+        if !settings.tiers.is_empty() {
+            error!("--max-archive-size cannot be used with --tier");
+        }
+    }
+
+    if settings.shallow && settings.index_commit.is_some() {
+        error!("--shallow cannot be used with --index-commit, since a shallow index only has the newest commit");
+    }
+    if settings.shallow && settings.push.is_some() {
+        error!("--shallow cannot be used with --push, since a shallow index can't be pushed to another repository");
+    }
+    if settings.force_push && settings.push.is_none() {
+        error!("--force-push can only be used with --push");
+    }
+
+    if settings.index_tarball.is_some() {
+        /* These need the git history, or the index files on disk */
+        let needs_git = [("--since", settings.since.is_some()),
+                         ("--dependency-closure", settings.dependency_closure),
+                         ("--index-commit", settings.index_commit.is_some()),
+                         ("--expected-index-commit",
+                          settings.expected_index_commit.is_some()),
+                         ("--max-index-age", settings.max_index_age.is_some()),
+                         ("--replace", settings.replace.is_some()),
+                         ("--push", settings.push.is_some()),
+                         ("--serve", settings.serve.is_some())];
+        if let Some(x) = needs_git.iter().find(|x| x.1) {
+            error!("--index-tarball cannot be used with {}", x.0);
+        }
+    }
+
+    if settings.socks5.is_some() && settings.unix_socket.is_some() {
+        error!("--socks5 cannot be used with --unix-socket");
+    }
+    if settings.socks5.is_some() && settings.update_index {
+        println!("Warning: The index is updated without going through --socks5, since libgit2 doesn't support SOCKS proxies. Run with --no-update-index to avoid connecting directly");
+    }
+
+    if settings.insecure {
+        println!("WARNING: TLS certificate verification is disabled by --insecure, the index and crates may be tampered with in transit!");
+    }
+    /* Downloads get these through curl, libgit2 has its own global setting
+     * for them. Setting it is only unsafe if other threads are using libgit2
+     * at the same time, and nothing has been started yet. */
+    if let Some(ref cacert) = settings.cacert {
+        if let Err(e) = unsafe { git2::opts::set_ssl_cert_file(cacert) } {
+            error!("Error setting --cacert {}: {}", cacert.to_string_lossy(), e);
+        }
+    }
+    if let Some(ref capath) = settings.capath {
+        if let Err(e) = unsafe { git2::opts::set_ssl_cert_dir(capath) } {
+            error!("Error setting --capath {}: {}", capath.to_string_lossy(), e);
+        }
+    }
+
+    let verbose = settings.verbose;
+    let mut on_event = |e: MirrorEvent| print_event(e, verbose);
+    if settings.registries.is_empty() {
+        ::std::process::exit(mirror(settings, run_start, &mut on_event)
+                                 .map_or(EXIT_OK, |x| x.exit_code()));
+    }
+
+    /* Each registry gets the archive directory, index and state files of a
+     * single registry in its own directory, so options that would have them
+     * share anything else, or that don't mirror, can't be used */
+    let shared = [("--index-url", matches.opt_present("index-url")),
+                  ("--registry-name", matches.opt_present("registry-name")),
+                  ("--dl-template", settings.dl_template.is_some()),
+                  ("--index-tarball", settings.index_tarball.is_some()),
+                  ("--index-commit", settings.index_commit.is_some()),
+                  ("--expected-index-commit", settings.expected_index_commit.is_some()),
+                  ("--replace", settings.replace.is_some()),
+                  ("--push", settings.push.is_some()),
+                  ("--s3-bucket", settings.s3_bucket.is_some()),
+                  ("--staging", settings.staging.is_some()),
+                  ("--tier", !settings.tiers.is_empty()),
+                  ("--pack", settings.pack.is_some()),
+                  ("--unpack", settings.unpack.is_some()),
+                  ("--metrics-file", settings.metrics_file.is_some()),
+                  ("--serve", settings.serve.is_some()),
+                  ("--list-crates", settings.list_crates.is_some()),
+                  ("--verify-against-index", settings.verify_against_index),
+                  ("--only-missing-report", settings.only_missing_report),
+                  ("--head-check", settings.head_check),
+                  ("--max-archive-size", settings.max_archive_size.is_some())];
+    if let Some(x) = shared.iter().find(|x| x.1) {
+        error!("--registry cannot be used with {}", x.0);
+    }
+
+    let mut mirrored: Vec<(&String, FetchResults)> = Vec::new();
+    for registry in &settings.registries {
+        let mut registry_settings = Settings::from(&matches);
+        registry_settings.archive = settings.archive.join(&registry.name);
+        registry_settings.index_url = registry.index_url.clone();
+        registry_settings.registry_name = registry.name.clone();
+        /* --max-duration and --limit are for all registries together */
+        registry_settings.max_duration = settings
+            .max_duration
+            .map(|x| x.saturating_sub(run_start.elapsed()));
+        let downloaded: u64 = mirrored.iter().map(|x| x.1.downloaded.crates).sum();
+        registry_settings.limit = settings.limit.map(|x| x.saturating_sub(downloaded));
+        println!("Mirroring {} into {}",
+                 registry.name,
+                 registry_settings.archive.to_string_lossy());
+        match mirror(registry_settings, Instant::now(), &mut on_event) {
+            Some(x) => mirrored.push((&registry.name, x)),
+            None => unreachable!("Only mirroring is allowed with --registry"),
+        }
+    }
+
+    let mut total = stats::DownloadStats::default();
+    println!("Mirrored {} registries:", mirrored.len());
+    for &(name, ref results) in &mirrored {
+        total.crates += results.downloaded.crates;
+        total.bytes += results.downloaded.bytes;
+        println!("  {}: downloaded {}, {}",
+                 name,
+                 results.downloaded.to_text(),
+                 match results.exit_code() {
+                     EXIT_OK => "complete".to_string(),
+                     x => format!("incomplete with exit code {}", x),
+                 });
+    }
+    println!("Downloaded {} from all registries", total.to_text());
+
+    /* Like for a single run, the highest code is used */
+    ::std::process::exit(mirrored
+                             .iter()
+                             .map(|x| x.1.exit_code())
+                             .max()
+                             .unwrap_or(EXIT_OK));
+}
+
+/// Mirror the registry of the settings into their archive directory, or do
+/// whatever else they ask for instead
+///
+/// The results are returned unless the settings ask for something other than
+/// mirroring, which is then done without returning any. on_event is called
+/// with the progress of every crate, and run_start is when the run started
+/// for --max-duration.
+pub fn mirror(mut settings: Settings,
+              run_start: Instant,
+              on_event: &mut dyn FnMut(MirrorEvent))
+              -> Option<FetchResults> {
+    create_dir(&settings.archive);
+    /* Resolve symlinks once, so that the index and everything else in the
+     * archive is found in the same place no matter how it was reached */
+    settings.archive = resolve_dir(&settings.archive);
+    if let Some(ref staging) = settings.staging {
+        create_dir(staging);
+    }
+    if let Some(ref quarantine) = settings.quarantine {
+        create_dir(quarantine);
+    }
+    for tier in &settings.tiers {
+        create_dir(&tier.dir);
+    }
+
+    let mut git_dir = settings.archive.clone();
+    git_dir.push("index");
+    check_symlink(&git_dir);
+    if git_dir.exists() {
+        git_dir = resolve_dir(&git_dir);
+    }
+
+    if let Some(ref addr) = settings.serve {
+        if !git_dir.join("config.json").is_file() {
+            error!("There is no index in {} to serve",
+                   settings.archive.to_string_lossy());
+        }
+        serve::Server {
+                archive: &settings.archive,
+                git_dir: &git_dir,
+                tiers: &settings.tiers,
+            }
+            .run(addr);
+        return None;
+    }
+
+    if let Some(ref input) = settings.unpack {
+        match pack::unpack_crates(input, &settings.archive, settings.file_mode) {
+            Ok(x) => {
+                println!("Unpacked {} crates into {}",
+                         x,
+                         settings.archive.to_string_lossy())
+            },
+            Err(e) => error!(exit EXIT_MISMATCH, "{}", e),
+        }
+        return None;
+    }
+
+    /* Any error from here on exits without reaching the end of the run, so
+     * the file says the run failed until the end overwrites it */
+    if let Some(ref path) = settings.metrics_file {
+        metrics::RunMetrics::default().write(path);
+    }
+
+    if settings.update_index && settings.index_tarball.is_none() {
+        match update_git_repo(&git_dir, &settings.index_url, &settings) {
+            Ok(()) => (),
+            /* The index is only usable if it was cloned successfully at
+             * some point */
+            Err(ref e) if settings.allow_stale_index &&
+                          git_dir.join("config.json").is_file() => {
+                println!("Warning: {}", e);
+                println!("Warning: Continuing with the existing index at commit {}",
+                         index_commit(&git_dir)
+                             .unwrap_or_else(|| "unknown".to_string()));
+            },
+            Err(e) => error!(exit EXIT_NETWORK, "{}", e),
+        }
+    }
+
+    /* The pinned commit is checked out even without updating the index */
+    if !settings.update_index && settings.index_commit.is_some() {
+        let reset = Repository::open(&git_dir)
+            .map_err(|e| e.to_string())
+            .and_then(|repo| reset_index(&repo, &settings));
+        match reset {
+            Ok(()) => (),
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    if let Some(ref expected) = settings.expected_index_commit {
+        match index_commit(&git_dir) {
+            Some(ref x) if x == expected => (),
+            Some(x) => {
+                error!("Error: The index is at commit {}, but --expected-index-commit is {}",
+                       x,
+                       expected)
+            },
+            None => {
+                error!("Error: Unable to get the index commit to compare with --expected-index-commit")
+            },
+        }
+    }
+
+    if let Some(max_age) = settings.max_index_age {
+        match index_age(&git_dir, SystemTime::now()) {
+            Some(age) if age > max_age => {
+                let msg = format!("The index at commit {} is {} hours old, which is older than --max-index-age",
+                                  index_commit(&git_dir).unwrap_or_else(|| "unknown".to_string()),
+                                  age.as_secs() / 3600);
+                if settings.strict_mode {
+                    error!("Error: {}", msg);
+                }
+                println!("Warning: {}", msg);
+            },
+            Some(_) => (),
+            None => {
+                error!("Error: Unable to get the time of the index commit to compare with --max-index-age")
+            },
+        }
+    }
+
+    let config = match settings.index_tarball {
+        Some(ref x) => ConfigJsonFile::read_tarball(x),
+        None => ConfigJsonFile::read(&git_dir),
+    };
+
+    if let Some(format) = settings.list_crates {
+        let crates = read_crate_index(&git_dir, &settings);
+        print!("{}", format_crate_list(&crates, format));
+        return None;
+    }
+
+    if settings.verify_against_index {
+        let crates = read_crate_index(&git_dir, &settings);
+        let audit = audit::audit_archive(crates, &settings);
+        if settings.audit_json {
+            println!("{}",
+                     serde_json::to_string_pretty(&audit)
+                         .expect("Error encoding audit report"));
+        } else {
+            print!("{}", audit.to_text());
+        }
+        ::std::process::exit(audit.exit_code());
+    }
+
+    if settings.only_missing_report {
+        let crates = read_crate_index(&git_dir, &settings);
+        let report = audit::missing_crates(crates, &settings);
+        if settings.audit_json {
+            println!("{}",
+                     serde_json::to_string_pretty(&report)
+                         .expect("Error encoding missing crates report"));
+        } else {
+            print!("{}", report.to_text());
+        }
+        ::std::process::exit(report.exit_code());
+    }
+
+    if settings.head_check {
+        let crates = read_crate_index(&git_dir, &settings);
+        let report = head_check::head_check(crates,
+                                            &config,
+                                            &settings,
+                                            &mut CurlDownloader::new(&settings));
+        if settings.audit_json {
+            println!("{}",
+                     serde_json::to_string_pretty(&report)
+                         .expect("Error encoding head check report"));
+        } else {
+            print!("{}", report.to_text());
+        }
+        ::std::process::exit(if !report.failed.is_empty() {
+                                 EXIT_NETWORK
+                             } else if !report.unavailable.is_empty() {
+                                 EXIT_UNAVAILABLE
+                             } else {
+                                 EXIT_OK
+                             });
+    }
+
+    if settings.s3_bucket.is_none() {
+        let dirs = Some(&settings.archive)
+            .into_iter()
+            .chain(settings.staging.as_ref())
+            .chain(settings.tiers.iter().map(|x| &x.dir));
+        for dir in dirs {
+            if !has_free_space(dir, &settings) {
+                error!("Error: There is less free space than --min-free-space in {}",
+                       dir.to_string_lossy());
+            }
+        }
+    }
+
+    let mut downloader = CurlDownloader::new(&settings);
+    /* All registries log to the same place */
+    let registry = Some(settings.registry_name.as_str())
+        .filter(|_| !settings.registries.is_empty());
+    let mut event_log = settings
+        .json_lines_output
+        .as_ref()
+        .map(|x| event_log::EventLog::open(x, registry));
+
+    let storage: Box<dyn Storage> = match settings.s3_bucket {
+        Some(ref bucket) => Box::new(S3Storage::new(&settings, bucket)),
+        None => Box::new(LocalStorage::new(&settings)),
+    };
+
+    /* Start downloading as soon as the first crates have been read from the
+     * index, instead of reading the whole index first */
+    let (mut results, skipped) = thread::scope(|s| {
+        let (tx, rx) = mpsc::sync_channel(INDEX_CHANNEL_SIZE);
+        let (git_dir, settings) = (&git_dir, &settings);
+        let index = s.spawn(move || {
+            walk_crate_index(git_dir, settings, |c| {
+                /* Sending only fails if fetch_crates is no longer
+                 * receiving, in which case the rest of the index isn't
+                 * needed */
+                tx.send(c).is_ok()
+            })
+        });
+        let results = fetch_crates(rx,
+                                   &config,
+                                   settings,
+                                   &*storage,
+                                   &mut downloader,
+                                   &mut |e| {
+                                       if let Some(ref mut log) = event_log {
+                                           log.log(&e);
+                                       }
+                                       on_event(e)
+                                   });
+        (results, index.join().expect("Error reading the index"))
+    });
+    results.add_skipped(skipped);
+
+    let lifetime = stats::update_lifetime_stats(&settings.archive,
+                                                results.downloaded);
+    println!("Downloaded {} in this run, and {} in all runs",
+             results.downloaded.to_text(),
+             lifetime.to_text());
+    if let Some(ref mut log) = event_log {
+        log.log_summary(results.downloaded, lifetime);
+    }
+    if let Some(ref staging) = settings.staging {
+        if results.exit_code() == EXIT_OK {
+            promote_staged_crates(staging, &settings.archive);
+        } else {
+            println!("Warning: Not all crates were downloaded successfully, leaving new crates in {}",
+                     staging.to_string_lossy());
+        }
+    }
+
+    if settings.dedup {
+        dedup::dedup_crates(&settings.archive, &results.verified);
+    }
+
+    if settings.sha256sums {
+        /* Crates that the index has another checksum for can't be listed */
+        let verified: Vec<(String, String)> = results
+            .verified
+            .iter()
+            .filter(|x| x.1 == checksum::Algorithm::Sha256)
+            .map(|x| (x.0.clone(), x.2.clone()))
+            .collect();
+        manifest::update_manifest(&settings.archive,
+                                  &settings.tiers,
+                                  &verified,
+                                  settings.sort_order != SortOrder::Index);
+    }
+
+    if let Some(ref output) = settings.pack {
+        if results.exit_code() != EXIT_OK {
+            println!("Warning: Not all crates were mirrored, {} will only have the ones in the archive",
+                     output.to_string_lossy());
+        }
+        let count = pack::pack_crates(output,
+                                      &settings.archive,
+                                      &settings.tiers,
+                                      &results.verified);
+        println!("Packed {} crates into {}", count, output.to_string_lossy());
+    }
+
+    /* A tarball has no commit to mark the archive complete at */
+    if results.exit_code() == EXIT_OK && settings.index_tarball.is_none() {
+        match index_commit(&git_dir) {
+            Some(x) => run_state::write_complete_marker(&settings.archive, &x),
+            None => println!("Warning: Unable to get the index commit, not marking the archive as complete"),
+        }
+    }
+
+    if let Some(ref new_url) = settings.replace {
+        match replace_url(new_url, &git_dir, &settings) {
+            Ok(Replaced::Unchanged) => println!("The DL url is already {}", new_url),
+            Ok(Replaced::Committed(commit)) => {
+                println!("Replaced DL url with {} in commit {}", new_url, commit)
+            },
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    if let Some(ref url) = settings.push {
+        match push_index(&git_dir, url, &settings) {
+            Ok(()) => (),
+            Err(e) => error!(exit EXIT_NETWORK, "{}", e),
+        }
+    }
+
+    if let Some(ref path) = settings.metrics_file {
+        metrics::RunMetrics {
+                crates_total: results.crates,
+                crates_downloaded: results.downloaded.crates,
+                crates_mismatched: results.checksum_mismatches.len() as u64,
+                bytes_downloaded: results.downloaded.bytes,
+                run_duration: run_start.elapsed(),
+                success: results.exit_code() == EXIT_OK,
+            }
+            .write(path);
+    }
+
+    Some(results)
+}
+
+/// Write the contents to path through a temporary file next to it, so that
+/// an interrupted write never leaves half of a file behind
+fn write_atomically<C: AsRef<[u8]>>(path: &Path, contents: C) -> Result<(), String> {
+    write_atomically_with(path, |partfile| {
+        fs::write(partfile, contents)
+            .map_err(|e| format!("Error writing to {}: {}", partfile.to_string_lossy(), e))
+    })
+}
+
+/// Like write_atomically, for files that are written as they are generated
+/// instead of from memory. write is given the temporary file to write to.
+fn write_atomically_with<F>(path: &Path, write: F) -> Result<(), String>
+    where F: FnOnce(&Path) -> Result<(), String>
+{
+    let partfile = path.with_extension("part");
+    write(&partfile)?;
+    fs::rename(&partfile, path).map_err(|e| {
+                                             format!("Error renaming {} to {}: {}",
+                                                     partfile.to_string_lossy(),
+                                                     path.to_string_lossy(),
+                                                     e)
+                                         })
+}
+
+/// Create the directory along with any missing parents, unless it already
+/// exists
+fn create_dir(path: &Path) {
+    check_symlink(path);
+    if !path.is_dir() {
+        if path.exists() {
+            error!("File already exists: {}", path.to_string_lossy());
+        } else {
+            match fs::create_dir_all(path) {
+                Ok(()) => (),
+                Err(e) => {
+                    error!("Error creating directory {}: {}",
+                           path.to_string_lossy(),
+                           e)
+                },
+            }
+        }
+    }
+}
+
+/// Exit if the path is a symlink to something that doesn't exist, since it
+/// would otherwise look like there's nothing there
+fn check_symlink(path: &Path) {
+    if let Ok(target) = fs::read_link(path) {
+        if !path.exists() {
+            error!("{} is a symlink to {}, which doesn't exist",
+                   path.to_string_lossy(),
+                   target.to_string_lossy());
+        }
+    }
+}
+
+/// The absolute path of the directory with all symlinks resolved, exiting if
+/// it isn't a directory
+fn resolve_dir(path: &Path) -> PathBuf {
+    let resolved = match fs::canonicalize(path) {
+        Ok(x) => x,
+        Err(e) => error!("Error resolving {}: {}", path.to_string_lossy(), e),
+    };
+    if !resolved.is_dir() {
+        error!("{} resolves to {}, which isn't a directory",
+               path.to_string_lossy(),
+               resolved.to_string_lossy());
+    }
+    resolved
+}
+
+/// Whether the filesystem of the directory has at least --min-free-space
+/// free, or whether it can't be determined
+fn has_free_space(dir: &Path, settings: &Settings) -> bool {
+    match (settings.min_free_space, free_space::available(dir)) {
+        (Some(min), Some(available)) => available >= min,
+        _ => true,
+    }
+}
+
+/// Clone the index repository into git_dir, or update it if it already
+/// exists
+fn update_git_repo(git_dir: &Path,
+                   url: &str,
+                   settings: &Settings)
+                   -> Result<(), String> {
+    let path = git_dir.as_os_str();
+
+    if git_dir.is_dir() {
+        match Repository::open(path) {
+            Ok(mut x) => {
+                if let Err(e) = check_working_tree(&x, settings.force) {
+                    error!("{}", e);
+                }
+                git_pull(&mut x, settings)
+            },
+            Err(e) => {
+                Err(format!("Error opening index repository at {}: {}",
+                            git_dir.to_string_lossy(),
+                            e))
+            },
+        }
+    } else {
+        println!("Cloning index directory into {}", git_dir.to_string_lossy());
+        match git2::build::RepoBuilder::new()
+                  .fetch_options(fetch_options(settings))
+                  .clone(url, git_dir) {
+            Ok(repo) => {
+                println!("Done cloning index directory");
+                if settings.index_commit.is_some() {
+                    reset_index(&repo, settings)?;
+                }
+                Ok(())
+            },
+            Err(e) => Err(format!("Error cloning index repository: {}", e)),
+        }
+    }
+}
+
+/// Make sure that updating the index won't throw away changes made to it by
+/// hand, since it is hard reset to the newest commit. A detached HEAD, e.g.
+/// from checking out an older commit, and uncommitted changes are refused,
+/// unless force is given to check out master again and remove files that
+/// aren't in the index. Changes to tracked files are then undone by the
+/// reset.
+fn check_working_tree(repo: &Repository, force: bool) -> Result<(), String> {
+    let git_dir = repo.workdir().unwrap_or_else(|| repo.path()).to_string_lossy();
+    let mut problems = Vec::new();
+
+    let detached = repo.head_detached().unwrap_or(false);
+    if detached {
+        let commit = repo.head()
+            .ok()
+            .and_then(|x| x.target())
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        problems.push(format!("HEAD is detached at commit {}", commit));
+    }
+
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true);
+    let statuses = match repo.statuses(Some(&mut options)) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Error getting the status of the index repository at {}: {}",
+                               git_dir,
+                               e))
+        },
+    };
+    let changed: Vec<(String, bool)> = statuses
+        .iter()
+        .filter_map(|x| {
+            x.path().map(|p| (p.to_string(), x.status() == git2::Status::WT_NEW))
+        })
+        .collect();
+    if !changed.is_empty() {
+        let mut names: Vec<&str> = changed.iter().take(3).map(|x| x.0.as_str()).collect();
+        if changed.len() > names.len() {
+            names.push("...");
+        }
+        problems.push(format!("{} files have uncommitted changes: {}",
+                              changed.len(),
+                              names.join(", ")));
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if !force {
+        return Err(format!("Error: The index repository at {} has been changed by hand, and updating it would discard the changes: {}. Undo the changes, or run with --force to discard them",
+                           git_dir,
+                           problems.join(", ")));
+    }
+
+    println!("Warning: Discarding the changes to the index repository at {} because of --force: {}",
+             git_dir,
+             problems.join(", "));
+    if detached {
+        let head = repo.head()
+            .and_then(|x| x.peel_to_commit())
+            .map_err(|e| format!("Error getting the HEAD of the index repository: {}", e))?;
+        if repo.find_branch("master", git2::BranchType::Local).is_err() {
+            repo.branch("master", &head, false)
+                .map_err(|e| format!("Error creating the master branch of the index repository: {}", e))?;
+        }
+        repo.set_head("refs/heads/master")
+            .map_err(|e| format!("Error checking out master in the index repository: {}", e))?;
+    }
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    for (path, _) in changed.iter().filter(|x| x.1) {
+        let path = workdir.join(path);
+        if let Err(e) = fs::remove_file(&path) {
+            return Err(format!("Error removing {}: {}", path.to_string_lossy(), e));
+        }
+    }
+    Ok(())
+}
+
+/// The callbacks used for all git operations with remotes
+fn remote_callbacks(settings: &Settings) -> git2::RemoteCallbacks<'static> {
+    let mut callbacks = git2::RemoteCallbacks::new();
+    if settings.insecure {
+        callbacks.certificate_check(|_, _| {
+            Ok(git2::CertificateCheckStatus::CertificateOk)
+        });
+    }
+    callbacks
+}
+
+/// The options used when fetching the index
+fn fetch_options(settings: &Settings) -> git2::FetchOptions<'static> {
+    let mut fetch_options = git2::FetchOptions::new();
+    fetch_options.remote_callbacks(remote_callbacks(settings));
+    /* Only the newest commit is ever used, since every update resets the
+     * index to it */
+    if settings.shallow {
+        fetch_options.depth(1);
+    }
+    /* --index-commit may be given a tag */
+    if settings.index_commit.is_some() {
+        fetch_options.download_tags(git2::AutotagOption::All);
+    }
+    fetch_options
+}
+
+/// Equivalent to doing git pull on the crates.io-index repository
+fn git_pull(repo: &mut Repository, settings: &Settings) -> Result<(), String> {
+    println!("Updating index repository");
+    let remote = match repo.remotes() {
+        Ok(ref remotes) if remotes.is_empty() => {
+            return Err("index repository has zero remotes".to_string());
+        },
+        Ok(ref remotes) if remotes.len() == 1 => {
+            remotes.get(0).expect("git_pull index error").to_string()
+        },
+        Ok(_) => {
+            return Err("index has more than 1 remote".to_string());
+        },
+        Err(e) => {
+            return Err(format!("index error getting remotes: {}", e));
+        },
+    };
+    let mut remote = match repo.find_remote(&remote) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("index error getting remote: {}", e)),
+    };
+
+    match remote.fetch(&[] as &[&str], Some(&mut fetch_options(settings)), None) {
+        Ok(()) => (),
+        Err(e) => return Err(format!("index error fetching from remote: {}", e)),
+    }
+
+    reset_index(repo, settings)?;
+
+    println!("Done updating index repository");
+    Ok(())
+}
+
+/// Hard reset the index repository to the commit given with --index-commit,
+/// or the newest commit on origin/master otherwise
+fn reset_index(repo: &Repository, settings: &Settings) -> Result<(), String> {
+    let object = match settings.index_commit {
+        Some(ref spec) => {
+            match repo.revparse_single(spec)
+                      .and_then(|x| x.peel(git2::ObjectType::Commit)) {
+                Ok(x) => x,
+                Err(e) => {
+                    return Err(format!("Error: --index-commit {} is not in the index repository after fetching: {}",
+                                       spec,
+                                       e))
+                },
+            }
+        },
+        None => {
+            let oid = match repo.refname_to_id("refs/remotes/origin/master") {
+                Ok(x) => x,
+                Err(e) => {
+                    return Err(format!("Error getting refs/remotes/origin/master ref: {}",
+                                       e))
+                },
+            };
+            repo.find_object(oid, None)
+                .expect("git_pull error getting object")
+        },
+    };
+    repo.reset(&object, git2::ResetType::Hard, None)
+        .expect("git_pull error doing hard reset");
+    Ok(())
+}
+
+/// The hash of the commit currently checked out in the index repository
+fn index_commit(git_dir: &Path) -> Option<String> {
+    let repo = Repository::open(git_dir).ok()?;
+    let head = repo.head().ok()?;
+    head.target().map(|x| x.to_string())
+}
+
+/// How long before now the commit checked out in the index was made
+///
+/// The commit time is used instead of when the index was last fetched, since
+/// an updater that keeps fetching a remote that is no longer updated is just
+/// as broken.
+fn index_age(git_dir: &Path, now: SystemTime) -> Option<Duration> {
+    let repo = Repository::open(git_dir).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    /* A commit from the future, e.g. made with a skewed clock, isn't old */
+    Some(Duration::from_secs((now - commit.time().seconds()).max(0) as u64))
+}
+
+/// The index files changed by the commits after the given unix time, going
+/// back from the commit that is checked out
+///
+/// A shallow index has no parent for its oldest commit, which then counts
+/// as changing every file.
+fn index_files_changed_since(git_dir: &Path, since: i64) -> Result<BTreeSet<PathBuf>, String> {
+    let git_error = |e: git2::Error| {
+        format!("Error reading the history of the index for --since: {}", e)
+    };
+    let repo = Repository::open(git_dir).map_err(git_error)?;
+    let mut revwalk = repo.revwalk().map_err(git_error)?;
+    revwalk.set_sorting(git2::Sort::TIME).map_err(git_error)?;
+    revwalk.push_head().map_err(git_error)?;
+
+    let mut ret = BTreeSet::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid.map_err(git_error)?)
+            .map_err(git_error)?;
+        if commit.time().seconds() <= since {
+            break;
+        }
+        let parent_tree = match commit.parents().next() {
+            Some(x) => Some(x.tree().map_err(git_error)?),
+            None => None,
+        };
+        let tree = commit.tree().map_err(git_error)?;
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+            .map_err(git_error)?;
+        for delta in diff.deltas() {
+            if let Some(path) = delta.new_file().path() {
+                ret.insert(git_dir.join(path));
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Read the index directory, returning all the Crates in the --sort-order
+pub fn read_crate_index(git_dir: &Path, settings: &Settings) -> Vec<Crate> {
+    let mut ret = Vec::new();
+    walk_crate_index(git_dir, settings, |c| {
+        ret.push(c);
+        true
+    });
+    ret
+}
+
+/// The index lines that walk_crate_index skipped
+#[derive(Default, Debug)]
+struct SkippedLines {
+    /// Lines that couldn't be parsed
+    malformed: usize,
+    /// Crates whose name or version can't be used in a file name, along with
+    /// the problem
+    invalid_names: Vec<(Crate, String)>,
+}
+
+/// Read the index directory, calling found with each Crate that should be
+/// mirrored as soon as it has been read, until it returns false. Returns the
+/// lines that were skipped because they couldn't be parsed or mirrored.
+///
+/// Each index file has all the versions of one crate, so only one file has to
+/// be kept in memory to pick the newest version.
+fn walk_crate_index<F>(git_dir: &Path, settings: &Settings, mut found: F) -> SkippedLines
+    where F: FnMut(Crate) -> bool
+{
+    println!("Reading the {} index", settings.registry_name);
+    let mut count = 0;
+    let mut skipped = SkippedLines::default();
+    /* With --priority-file, --dependency-closure and --sort-order other
+     * than index the whole index has to be read before the first crate can
+     * be passed on */
+    let buffered = settings.priority.is_some() || settings.dependency_closure ||
+                   settings.sort_order != SortOrder::Index;
+    let mut kept = Vec::new();
+
+    let ignore_rules = IgnoreRules::read(&settings.archive);
+    let unavailable_crates = if settings.skip_unavailable {
+        unavailable_crates()
+    } else {
+        Vec::new()
+    };
+    /* The versions from the Cargo.lock that are in the index */
+    let mut locked_found = BTreeSet::new();
+
+    /* With --since only the files changed after it are read, the rest are
+     * assumed to be mirrored already */
+    let changed = settings.since.map(|x| match index_files_changed_since(git_dir, x) {
+        Ok(x) => x,
+        Err(e) => error!("{}", e),
+    });
+    let keep = |x: &Path| {
+        let name = x.file_name().unwrap_or_default().to_string_lossy();
+        changed.as_ref().is_none_or(|c| c.contains(x)) &&
+        settings.shard.is_none_or(|s| s.contains(&name)) &&
+        settings.name_prefix.is_none_or(|r| r.contains(&name))
+    };
+
+    let mut tarball = settings.index_tarball.as_ref().map(|x| match index_tarball::open(x) {
+        Ok(tar) => (tar, x),
+        Err(e) => error!("{}", e),
+    });
+    let files: Box<dyn Iterator<Item = (PathBuf, Box<dyn BufRead + '_>)> + '_> = match tarball {
+        /* The files are read straight from the tar, which is only read
+         * once */
+        Some((ref mut tar, path)) => {
+            let names: Option<BTreeSet<PathBuf>> = settings.from_names.as_ref().map(|x| {
+                x.iter().map(|x| git_dir.join(index_path(x))).collect()
+            });
+            Box::new(index_tarball::index_files(tar, path, git_dir).filter(move |x| {
+                names.as_ref().is_none_or(|n| n.contains(&x.0)) && keep(&x.0)
+            }))
+        },
+        None => Box::new(index_dir_files(git_dir, settings).filter(|x| keep(x)).map(|path| {
+            let f = match File::open(&path) {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("Error opening file {}: {}", path.display(), e)
+                },
+            };
+            (path, Box::new(BufReader::new(f)) as Box<dyn BufRead>)
+        })),
+    };
+
+    for (path, f) in files {
+        let mut versions = Vec::new();
+        /* The name, version and checksum of every version in the file, by
+         * their lowercase name and version, to detect duplicates */
+        let mut checksums = BTreeMap::new();
+        for (line_number, line) in f.lines().enumerate() {
+
+            let line = match line {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("read_crate_index error reading line in {}: {}",
+                           path.display(),
+                           e)
+                },
+            };
+            /* serde_json reports the column and the offending field, but
+             * since we parse line by line we have to add the line number */
+            let mut crate_info: Crate = match serde_json::from_str(&line) {
+                Ok(x) => x,
+                Err(e) => {
+                    if settings.strict_mode {
+                        error!("Error parsing json in {} on line {}: {}",
+                               path.display(),
+                               line_number + 1,
+                               e)
+                    }
+                    /* A single bad line, e.g. from a file that was being
+                     * written to, shouldn't stop the whole mirror */
+                    println!("Warning: Skipping line {} in {}, error parsing json: {}: {}",
+                             line_number + 1,
+                             path.display(),
+                             e,
+                             line);
+                    skipped.malformed += 1;
+                    continue;
+                },
+            };
+
+            if crate_info.v.unwrap_or(1) > MAX_INDEX_SCHEMA_VERSION {
+                println!("Warning: Skipping {}-{} in {}, it uses unsupported index schema version {}",
+                         crate_info.name,
+                         crate_info.vers,
+                         path.display(),
+                         crate_info.v.unwrap_or(1));
+                continue;
+            }
+
+            if let Err(e) = check_crate_filename(&crate_info.name, &crate_info.vers) {
+                if settings.strict_mode {
+                    error!("Error: Invalid crate on line {} in {}: {}",
+                           line_number + 1,
+                           path.display(),
+                           e)
+                }
+                println!("Warning: Skipping line {} in {}, {}",
+                         line_number + 1,
+                         path.display(),
+                         e);
+                skipped.invalid_names.push((crate_info, e));
+                continue;
+            }
+
+            if ignore_rules.is_ignored(&crate_info.name) {
+                continue;
+            }
+
+            if settings.depends_on.as_ref().is_some_and(|x| !x.matches(&crate_info)) {
+                continue;
+            }
+
+            if let Some(ref locked) = settings.cargo_lock {
+                let key = (crate_info.name.clone(), crate_info.vers.clone());
+                let cksum = match locked.get(&key) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                locked_found.insert(key);
+                match *cksum {
+                    Some(ref cksum) if cksum != &crate_info.cksum => {
+                        if settings.strict_mode {
+                            error!(exit EXIT_MISMATCH,
+                                   "Error: The Cargo.lock has checksum {} for {}-{}, but the index has {}",
+                                   cksum,
+                                   crate_info.name,
+                                   crate_info.vers,
+                                   crate_info.cksum);
+                        }
+                        println!("Warning: The Cargo.lock has checksum {} for {}-{}, but the index has {}, skipping it",
+                                 cksum,
+                                 crate_info.name,
+                                 crate_info.vers,
+                                 crate_info.cksum);
+                        continue;
+                    },
+                    _ => (),
+                }
+            }
+
+            if settings.stable_only && is_prerelease(&crate_info.vers) {
+                continue;
+            }
+
+            if let Some(max) = settings.max_rust_version {
+                if crate_info
+                       .rust_version
+                       .as_ref()
+                       .and_then(|x| parse_rust_version(x))
+                       .is_some_and(|x| x > max) {
+                    continue;
+                }
+            }
+
+            /* Crates whose names only differ in case are in the same file,
+             * and so are versions that only differ in case. Their crate files
+             * would overwrite each other on case-insensitive filesystems. */
+            let id = format!("{}-{}", crate_info.name, crate_info.vers);
+            let key = id.to_ascii_lowercase();
+            /* Duplicates with different checksums mean the index is
+             * corrupt, and we can't tell which one is right */
+            match checksums.get(&key) {
+                Some((other, _)) if other != &id => {
+                    println!("Warning: {} and {} in {} only differ in case, and their crate files would overwrite each other on case-insensitive filesystems, skipping line {}",
+                             other,
+                             id,
+                             path.display(),
+                             line_number + 1);
+                    continue;
+                },
+                Some((_, cksum)) if cksum != &crate_info.cksum => {
+                    if settings.strict_mode {
+                        error!("Error: {}-{} is listed more than once in {} with different checksums, {} and {}",
+                               crate_info.name,
+                               crate_info.vers,
+                               path.display(),
+                               cksum,
+                               crate_info.cksum);
+                    }
+                    println!("Warning: {}-{} is listed more than once in {} with different checksums, {} and {}, skipping line {}",
+                             crate_info.name,
+                             crate_info.vers,
+                             path.display(),
+                             cksum,
+                             crate_info.cksum,
+                             line_number + 1);
+                    continue;
+                },
+                Some(_) => continue,
+                None => (),
+            }
+            checksums.insert(key, (id, crate_info.cksum.clone()));
+
+            if settings.save_metadata {
+                crate_info.index_line = Some(line);
+            }
+            if !settings.dependency_closure {
+                crate_info.deps = Vec::new();
+            }
+            versions.push(crate_info);
+        }
+
+        /* The versions in a Cargo.lock are needed even if they are yanked
+         * or old */
+        let mut versions = if settings.cargo_lock.is_some() {
+            versions
+        } else {
+            select_versions(versions, settings)
+        };
+        versions.sort_by(|a, b| settings.sort_order.compare(a, b));
+        for c in versions {
+            if !unavailable_crates.contains(&c) {
+                count += 1;
+                if buffered {
+                    kept.push(c);
+                } else if !found(c) {
+                    return skipped;
+                }
+            }
+        }
+    }
+
+    if settings.dependency_closure {
+        let added = deps::dependency_closure(&kept, |name| {
+            if ignore_rules.is_ignored(name) {
+                return Vec::new();
+            }
+            index_versions(git_dir, name, settings)
+                .into_iter()
+                .filter(|x| !unavailable_crates.contains(x))
+                .collect()
+        });
+        println!("Added {} versions that the selected crates depend on",
+                 added.len());
+        count += added.len();
+        kept.extend(added);
+    }
+
+    /* Crates that aren't in the --priority-file come last. In index order the
+     * crates are already sorted within each rank, since the sort is stable. */
+    let rank = |c: &Crate| match settings.priority {
+        Some(ref priority) => priority.get(&c.name).cloned().unwrap_or(usize::MAX),
+        None => 0,
+    };
+    kept.sort_by(|a, b| {
+                     let ret = rank(a).cmp(&rank(b));
+                     if settings.sort_order == SortOrder::Index {
+                         ret
+                     } else {
+                         ret.then_with(|| settings.sort_order.compare(a, b))
+                     }
+                 });
+    for mut c in kept {
+        c.deps = Vec::new();
+        if !found(c) {
+            return skipped;
+        }
+    }
+
+    if let Some(ref locked) = settings.cargo_lock {
+        for (name, vers) in locked.keys().filter(|x| !locked_found.contains(x)) {
+            println!("Warning: {}-{} from the Cargo.lock is not in the index",
+                     name,
+                     vers);
+        }
+    }
+
+    println!("Finished reading {} index", settings.registry_name);
+    println!("Found info for {} .crate files", count);
+    skipped
+}
+
+/// Every version of the crate in its index file, for adding dependencies that
+/// were left out by the other options
+fn index_versions(git_dir: &Path, name: &str, settings: &Settings) -> Vec<Crate> {
+    let contents = match fs::read_to_string(git_dir.join(index_path(name))) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut c: Crate = serde_json::from_str(line).ok()?;
+            /* The file also has the crates whose names only differ in case */
+            if c.name != name || c.v.unwrap_or(1) > MAX_INDEX_SCHEMA_VERSION ||
+               check_crate_filename(&c.name, &c.vers).is_err() {
+                return None;
+            }
+            if settings.save_metadata {
+                c.index_line = Some(line.to_string());
+            }
+            Some(c)
+        })
+        .collect()
+}
+
+/// The index files in the index directory, in the order of their paths
+///
+/// With --from-names-file only the files of those crates are read, since
+/// their paths follow from their names.
+fn index_dir_files<'a>(git_dir: &'a Path,
+                       settings: &'a Settings)
+                       -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+    match settings.from_names {
+        Some(ref names) => {
+            let mut paths: Vec<PathBuf> =
+                names.iter().map(|x| git_dir.join(index_path(x))).collect();
+            paths.sort();
+            paths.dedup();
+            Box::new(paths
+                         .into_iter()
+                         .filter(|x| {
+                let exists = x.is_file();
+                if !exists {
+                    println!("Warning: {} is not in the index",
+                             x.file_name().unwrap_or_default().to_string_lossy());
+                }
+                exists
+            }))
+        },
+        /* Iterate over all files in the index, skipping config.json */
+        None => {
+            Box::new(WalkDir::new(git_dir)
+                         .sort_by(|a, b| a.cmp(b))
+                         .into_iter()
+                         .filter_entry(|e| {
+                let filename = match e.file_name().to_str() {
+                    Some(x) => x,
+                    None => return false,
+                };
+                !(filename.starts_with(".") || filename == "config.json")
+            })
+                         .filter_map(|e| e.ok())
+                         .filter(|e| e.file_type().is_file())
+                         .map(|e| e.path().to_path_buf()))
+        },
+    }
+}
+
+/// Check that the name and version of a crate are safe to use in the names
+/// of its files in the archive, so that an entry in the index of a registry
+/// can't make us write outside of the archive, or to a file whose name is
+/// too long to be created
+///
+/// Cargo only allows ASCII letters, digits, - and _ in the names of crates in
+/// registries, and versions are semver, so no crate that cargo could use is
+/// rejected.
+fn check_crate_filename(name: &str, vers: &str) -> Result<(), String> {
+    if name.is_empty() ||
+       !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("invalid crate name {:?}", name));
+    }
+    if !vers.starts_with(|c: char| c.is_ascii_digit()) || vers.contains("..") ||
+       !vers.chars().all(|c| c.is_ascii_alphanumeric() || ".-+".contains(c)) {
+        return Err(format!("invalid version {:?} of crate {}", vers, name));
+    }
+    let len = name.len() + vers.len() + "-.crate".len();
+    if len > MAX_CRATE_FILENAME_LEN {
+        return Err(format!("the file name of {} version {} would be {} bytes long, more than the {} allowed",
+                           name,
+                           vers,
+                           len,
+                           MAX_CRATE_FILENAME_LEN));
+    }
+    Ok(())
+}
+
+/// The path of the index file of the crate with the given name, relative to
+/// the root of the index
+fn index_path(name: &str) -> PathBuf {
+    let name = name.to_ascii_lowercase();
+    Path::new(&index_prefix(&name)).join(&name)
+}
+
+/// The directory of the index file of the crate with the given name, keeping
+/// the case of the name, which is the {prefix} of a config.json
+fn index_prefix(name: &str) -> String {
+    /* The byte offset of the nth character, so that names that haven't been
+     * checked yet can't split a character */
+    let end = |n| name.char_indices().nth(n).map_or(name.len(), |(i, _)| i);
+    match name.chars().count() {
+        1 => "1".to_string(),
+        2 => "2".to_string(),
+        3 => format!("3/{}", &name[..end(1)]),
+        _ => format!("{}/{}", &name[..end(2)], &name[end(2)..end(4)]),
+    }
+}
+
+/// Choose which of the versions of a crate, in the order they are listed in
+/// its index file, should be mirrored
+fn select_versions(versions: Vec<Crate>, settings: &Settings) -> Vec<Crate> {
+    if settings.only_yanked {
+        let mut yanked: Vec<Crate> =
+            versions.into_iter().filter(|c| c.yanked).collect();
+        if !settings.download_old {
+            yanked = yanked.pop().into_iter().collect();
+        }
+        return yanked;
+    }
+
+    let last = versions.len().saturating_sub(1);
+    versions
+        .into_iter()
+        .enumerate()
+        /* Assume that the newest version is listed last in the index file */
+        .filter(|&(i, ref c)| {
+                    (settings.download_yanked || !c.yanked) &&
+                    (settings.download_old || i == last)
+                })
+        .map(|(_, c)| c)
+        .collect()
+}
+
+/// Crates which are listed in the crates.io index, but are unavailable for
+/// unknown reasons. They are skipped, since trying to download them results
+/// in an error, unless run with --no-skip-unavailable.
+fn unavailable_crates() -> Vec<Crate> {
+    vec![Crate::new("STD", "0.1.0"),
+         Crate::new("glib-2-0-sys", "0.0.1"),
+         Crate::new("glib-2-0-sys", "0.0.2"),
+         Crate::new("glib-2-0-sys", "0.0.3"),
+         Crate::new("glib-2-0-sys", "0.0.4"),
+         Crate::new("glib-2-0-sys", "0.0.5"),
+         Crate::new("glib-2-0-sys", "0.0.6"),
+         Crate::new("glib-2-0-sys", "0.0.7"),
+         Crate::new("glib-2-0-sys", "0.0.8"),
+         Crate::new("glib-2-0-sys", "0.1.0"),
+         Crate::new("glib-2-0-sys", "0.1.1"),
+         Crate::new("glib-2-0-sys", "0.1.2"),
+         Crate::new("glib-2-0-sys", "0.2.0"),
+         Crate::new("gobject-2-0-sys", "0.0.2"),
+         Crate::new("gobject-2-0-sys", "0.0.3"),
+         Crate::new("gobject-2-0-sys", "0.0.4"),
+         Crate::new("gobject-2-0-sys", "0.0.5"),
+         Crate::new("gobject-2-0-sys", "0.0.6"),
+         Crate::new("gobject-2-0-sys", "0.0.7"),
+         Crate::new("gobject-2-0-sys", "0.0.8"),
+         Crate::new("gobject-2-0-sys", "0.0.9"),
+         Crate::new("gobject-2-0-sys", "0.1.0"),
+         Crate::new("gobject-2-0-sys", "0.2.0"),
+         Crate::new("ojfiewijogwhiogerhiugerhiuegr", "0.1.0"),
+         Crate::new("ojfiewijogwhiogerhiugerhiuegr", "0.1.1"),
+         Crate::new("ojfiewijogwhiogerhiugerhiuegr", "0.1.2"),
+         Crate::new("rustbook", "0.1.0"),
+         Crate::new("rustbook", "0.2.0"),
+         Crate::new("rustbook", "0.3.0"),
+         Crate::new("cargo-ctags", "0.2.3"),
+         Crate::new("wright", "0.2.2"), /* https://github.com/rust-lang/crates.io/issues/1201 */
+         Crate::new("stitch", "0.1.0"), /* https://github.com/C4K3/crates-ectype/issues/1 */
+         ]
+}
+
+/// Format the crates for --list-crates
+fn format_crate_list(crates: &[Crate], format: ListFormat) -> String {
+    match format {
+        ListFormat::Csv => {
+            let mut ret = "name,version,yanked,cksum\n".to_string();
+            for c in crates {
+                ret.push_str(&format!("{},{},{},{}\n",
+                                      c.name,
+                                      c.vers,
+                                      c.yanked,
+                                      c.cksum));
+            }
+            ret
+        },
+        ListFormat::Json => {
+            let mut ret = serde_json::to_string_pretty(crates)
+                .expect("Error encoding crate list");
+            ret.push('\n');
+            ret
+        },
+    }
+}
+
+/// Download all the given crates that aren't already in storage, returning
+/// the problems encountered along the way. on_event is called with the
+/// progress of every crate.
+pub fn fetch_crates<I>(crates: I,
+                       config: &ConfigJsonFile,
+                       settings: &Settings,
+                       storage: &dyn Storage,
+                       downloader: &mut dyn Downloader,
+                       on_event: &mut dyn FnMut(MirrorEvent))
+                       -> FetchResults
+    where I: IntoIterator<Item = Crate>
+{
+    let crates_dir = &settings.archive;
+    let start = Instant::now();
+
+    let mut output = Vec::new();
+
+    let mut results = FetchResults::default();
+
+    let mut verify_cache = VerifyCache::read(crates_dir);
+    let mut etags = if settings.etags {
+        Some(etags::EtagCache::read(crates_dir))
+    } else {
+        None
+    };
+    let mut run_state = RunState::open(crates_dir, &run_description(settings));
+    let mut evictor = settings
+        .max_archive_size
+        .map(|x| Evictor::new(crates_dir, x));
+    let mut verify_pool = if settings.verify_jobs > 1 {
+        Some(VerifyPool::new(settings.verify_jobs))
+    } else {
+        None
+    };
+
+    /* Crates that are already in the archive are otherwise checked
+     * silently, so this is the only output about them */
+    if settings.check_sums && !settings.quiet_existing {
+        println!("Info: Checksum verification of already downloaded crates is enabled. If you have already downloaded many crates, this may take a long time. There will be no output when already downloaded crates are checked. Crates that haven't changed since they were last verified are skipped, unless run with --force-verify. To disable this, run with --no-check-sums (new crates will still have their checksum verified.)");
+    }
+
+    let mut crates = crates.into_iter();
+    /* Existing files that failed verification, to be downloaded again */
+    let mut repairs = VecDeque::new();
+    loop {
+        let c = match repairs
+                  .pop_front()
+                  .or_else(|| crates.next().inspect(|_| results.crates += 1)) {
+            Some(x) => x,
+            /* The last files on the pool may still need to be repaired */
+            None => {
+                match verify_pool.take() {
+                    Some(pool) => {
+                        for (job, hash) in pool.finish() {
+                            repairs.extend(check_existing(job,
+                                                          hash,
+                                                          settings,
+                                                          &mut verify_cache,
+                                                          &mut run_state,
+                                                          &mut results,
+                                                          on_event));
+                        }
+                        continue;
+                    },
+                    None => break,
+                }
+            },
+        };
+
+        /* The time spent paused counts towards --max-duration, which is about
+         * when the run has to be done by, so it ends a pause too */
+        let out_of_time = || settings.max_duration.is_some_and(|x| start.elapsed() >= x);
+        if crates_dir.join(PAUSE_FILE).exists() {
+            on_event(MirrorEvent::Paused { krate: &c });
+            while crates_dir.join(PAUSE_FILE).exists() && !out_of_time() {
+                thread::sleep(PAUSE_POLL_INTERVAL);
+            }
+            if !out_of_time() {
+                on_event(MirrorEvent::Resumed { krate: &c });
+            }
+        }
+
+        if out_of_time() {
+            println!("Warning: Stopping because the run has taken longer than --max-duration, the next run will continue from here");
+            results.stopped_early = true;
+            break;
+        }
+
+        let failures = results.download_failures.len() +
+                       results.checksum_mismatches.len();
+        if settings.max_failures.is_some_and(|x| failures > x) {
+            println!("Error: Stopping because {} crates have failed to download or had checksum mismatches, which is more than --max-failures",
+                     failures);
+            results.aborted = true;
+            break;
+        }
+
+        if let Some(ref pool) = verify_pool {
+            while let Some((job, hash)) = pool.try_result() {
+                repairs.extend(check_existing(job,
+                                              hash,
+                                              settings,
+                                              &mut verify_cache,
+                                              &mut run_state,
+                                              &mut results,
+                                              on_event));
+            }
+        }
+
+        /* Crates from the index have already been checked, this keeps any
+         * others from writing outside of the archive too */
+        if let Err(e) = check_crate_filename(&c.name, &c.vers) {
+            println!("Warning: Skipping crate, {}", e);
+            results.invalid_names.push((c.clone(), e));
+            continue;
+        }
+
+        let crate_name = format!("{}-{}.crate", c.name, c.vers);
+        /* The file may have been removed since it was confirmed, which
+         * the checks below report */
+        if run_state.is_done(&crate_name) && storage.exists(&c) == Ok(true) {
+            save_metadata(crates_dir, &c);
+            if settings.check_sums {
+                results.verified.push((crate_name, c.checksum_algorithm(), c.cksum.clone()));
+            }
+            on_event(MirrorEvent::Skipped { krate: &c });
+            continue;
+        }
+
+        let cratefile = storage.local_path(&c);
+        let mut url = c.download_url(config, settings);
+        if let Some(ref rules) = settings.header_rules {
+            downloader.set_headers(rules.headers_for(&c.name));
+        }
+        /* With --etags, ask the server whether crates that are already in
+         * the archive have changed, and keep the new download if they have.
+         * Crates without a known ETag are only checked locally. */
+        let mut changed = None;
+        let known_etag = etags.as_ref().and_then(|x| x.get(&crate_name));
+        let stored = match (known_etag, &cratefile) {
+            (None, _) => Ok(false),
+            (Some(_), Some(_)) => Ok(true),
+            (Some(_), None) => storage.exists(&c),
+        };
+        let stored = match stored {
+            Ok(x) => x,
+            Err(e) => {
+                results.network_failure(&c, "checking", e, settings, on_event);
+                continue;
+            },
+        };
+        if let (true, Some(etag)) = (stored, known_etag) {
+            output.clear();
+            let download_start = Instant::now();
+            let download_result =
+                downloader.download_if_changed(&url, Some(etag), &mut output);
+            match download_result {
+                Ok(Conditional::NotModified) => (),
+                Ok(Conditional::Modified(etag)) => {
+                    changed = Some((etag, download_start.elapsed()))
+                },
+                Err(e) => {
+                    results.network_failure(&c, "revalidating", e, settings, on_event);
+                    continue;
+                },
+            }
+        }
+
+        if let (Some(cratefile), None) = (cratefile, &changed) {
+            if settings.check_sums {
+                let metadata = match fs::metadata(&cratefile) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!("Error reading metadata of {}: {}",
+                               cratefile.to_string_lossy(),
+                               e)
+                    },
+                };
+                /* Trust files that haven't changed since they were last
+                 * verified */
+                if !settings.force_verify &&
+                   verify_cache.lookup(&crate_name,
+                                       &metadata,
+                                       settings.reverify_after) ==
+                   Some(&c.cksum) {
+                    save_metadata(crates_dir, &c);
+                    run_state.record(&crate_name);
+                    results.verified.push((crate_name, c.checksum_algorithm(), c.cksum.clone()));
+                    on_event(MirrorEvent::Skipped { krate: &c });
+                    continue;
+                }
+
+                let job = VerifyJob {
+                    krate: c,
+                    crate_name,
+                    path: cratefile,
+                    metadata,
+                };
+                match verify_pool {
+                    Some(ref pool) => pool.submit(job),
+                    None => {
+                        let hash = job.krate
+                            .checksum_algorithm()
+                            .hash_file(&job.path, &mut output);
+                        repairs.extend(check_existing(job,
+                                                      hash,
+                                                      settings,
+                                                      &mut verify_cache,
+                                                      &mut run_state,
+                                                      &mut results,
+                                                      on_event));
+                    },
+                }
+            } else {
+                save_metadata(crates_dir, &c);
+                run_state.record(&crate_name);
+                on_event(MirrorEvent::Skipped { krate: &c });
+            }
+            continue;
+        }
+
+        /* Storage without local files is verified where it is */
+        if changed.is_none() {
+            let matches = if settings.check_sums {
+                storage.verify(&c)
+            } else {
+                storage.exists(&c).map(|x| Some(true).filter(|_| x))
+            };
+            match matches {
+                Ok(Some(true)) => {
+                    save_metadata(crates_dir, &c);
+                    run_state.record(&crate_name);
+                    if settings.check_sums {
+                        results.verified.push((crate_name, c.checksum_algorithm(), c.cksum.clone()));
+                    }
+                    on_event(MirrorEvent::Skipped { krate: &c });
+                    continue;
+                },
+                Ok(Some(false)) => {
+                    if !settings.auto_repair {
+                        error!(exit EXIT_MISMATCH,
+                               "Checksum mismatch in the stored {}. Expected {}",
+                               crate_name,
+                               c.cksum);
+                    }
+                    println!("Warning: Checksum mismatch in the stored {}, downloading it again",
+                             crate_name);
+                },
+                Ok(None) => (),
+                Err(e) => {
+                    results.network_failure(&c, "checking", e, settings, on_event);
+                    continue;
+                },
+            }
+        }
+
+        /* Only stop for --limit once there is another crate to download, so
+         * that the run doesn't look partial when it is complete */
+        if changed.is_none() &&
+           settings.limit.is_some_and(|x| results.downloaded.crates >= x) {
+            println!("Warning: Stopping because {} crates have been downloaded, which is --limit, the next run will continue from here",
+                     results.downloaded.crates);
+            results.stopped_early = true;
+            break;
+        }
+
+        /* Stop cleanly instead of failing on a full disk halfway through a
+         * write */
+        if !storage.has_room(&c) {
+            println!("Warning: Stopping because there is less free space than --min-free-space where {} would be stored, the next run will continue from here",
+                     crate_name);
+            results.stopped_early = true;
+            break;
+        }
+
+        let (etag, duration) = match changed {
+            Some(x) => x,
+            None => {
+                /* Ask for the size first, so that large crates aren't
+                 * downloaded just to be thrown away */
+                if let Some(limit) = settings.skip_larger_than {
+                    match downloader.head(&url) {
+                        Ok(Head {
+                               content_length: Some(bytes), ..
+                           }) if bytes > limit => {
+                            on_event(MirrorEvent::TooLarge { krate: &c, bytes });
+                            results.too_large.push((c.clone(), bytes));
+                            continue;
+                        },
+                        Ok(_) => (),
+                        Err(e) => {
+                            results.network_failure(&c,
+                                                    "checking the size of",
+                                                    e,
+                                                    settings,
+                                                    on_event);
+                            continue;
+                        },
+                    }
+                }
+
+                on_event(MirrorEvent::Started {
+                             krate: &c,
+                             url: &url,
+                         });
+
+                /* Reuse the same vector */
+                output.clear();
+                let download_start = Instant::now();
+                let mut download_result =
+                    downloader.download_if_changed(&url, None, &mut output);
+                /* Only the crates missing from the CDN are downloaded through
+                 * config.json, which counts them as downloads */
+                if let (true, Err(e)) = (settings.prefer_orig_on_cdn_failure,
+                                         &download_result) {
+                    let orig = c.registry_url(&config.dl);
+                    if orig != url {
+                        on_event(MirrorEvent::Fallback {
+                                     krate: &c,
+                                     url: &orig,
+                                     error: e,
+                                 });
+                        output.clear();
+                        download_result =
+                            downloader.download_if_changed(&orig, None, &mut output);
+                        if download_result.is_ok() {
+                            results.cdn_fallbacks.push(c.clone());
+                        }
+                        url = orig;
+                    }
+                }
+                let duration = download_start.elapsed();
+                match download_result {
+                    Ok(Conditional::Modified(etag)) => (etag, duration),
+                    Ok(Conditional::NotModified) => {
+                        results.network_failure(&c,
+                                                "downloading",
+                                                "received 304 Not Modified without asking for it"
+                                                    .to_string(),
+                                                settings,
+                                                on_event);
+                        continue;
+                    },
+                    Err(e) => {
+                        results.network_failure(&c, "downloading", e, settings, on_event);
+                        continue;
+                    },
+                }
+            },
+        };
+
+        /* Taken before anything else is requested */
+        let response = downloader.last_response();
+
+        if duration >= settings.slow_download {
+            results.slow_downloads.push((c.clone(), output.len(), duration));
+        }
+        /* Servers don't have to send a Content-Length */
+        let bytes = output.len() as u64;
+        if settings.skip_larger_than.is_some_and(|x| bytes > x) {
+            on_event(MirrorEvent::TooLarge { krate: &c, bytes });
+            results.too_large.push((c.clone(), bytes));
+            continue;
+        }
+
+        let hash = c.checksum_algorithm().hash(&output);
+        /* That there is the sha256sum of the crate not found error message.
+         * Unfortunately crates.io returns 200 even when the crate can't be
+         * found, so this is an easy way of checking if the crate was not
+         * found */
+        let not_found = match c.checksum_algorithm() {
+            checksum::Algorithm::Sha256 => hash.clone(),
+            _ => sha256sum(&output),
+        };
+        if &not_found ==
+           "59d2652e67d6af1844f035488a12ecdd3c680554eff0bf982aad28814b5963a9" {
+            if settings.strict_mode {
+                error!(exit EXIT_UNAVAILABLE,
+                       "Error: crate {}-{} could not be downloaded!",
+                       c.name,
+                       c.vers);
+            }
+            on_event(MirrorEvent::Unavailable { krate: &c });
+            results.unavailable.push(c.clone());
+            continue;
+        }
+        if hash != c.cksum {
+            /* Check the downloaded file matches the hash in the registry */
+            if let Some(ref quarantine) = settings.quarantine {
+                quarantine_crate(quarantine, &c, &url, &output, &hash);
+            }
+            if settings.strict_mode {
+                error!(exit EXIT_MISMATCH,
+                       "Error: Checksum mismatch in {}-{}. Expected hash {} but received file with hash {}",
+                       c.name,
+                       c.vers,
+                       c.cksum,
+                       hash);
+            } else {
+                on_event(MirrorEvent::Mismatch {
+                             krate: &c,
+                             expected: &c.cksum,
+                             actual: &hash,
+                         });
+                results.checksum_mismatches.push((c.clone(), hash));
+                continue;
+            }
+        }
+
+        /* The index itself could be wrong, so even a crate matching its
+         * checksum may be broken */
+        if settings.verify_tarball {
+            if let Err(e) = tarball::list_entries(&output) {
+                if settings.strict_mode {
+                    error!(exit EXIT_MISMATCH,
+                           "Error: {}-{} is not a valid .crate file: {}",
+                           c.name,
+                           c.vers,
+                           e);
+                }
+                println!("Warning: {}-{} is not a valid .crate file: {}",
+                         c.name,
+                         c.vers,
+                         e);
+                results.malformed.push((c.clone(), e));
+            }
+        }
+
+        /* A CDN serving the wrong file would normally be caught by the
+         * checksum, but not if the index was tampered with too */
+        if settings.verify_contents {
+            let error = match tarball::package_info(&output) {
+                Ok((ref name, ref vers)) if name == &c.name &&
+                                            vers == &c.vers => None,
+                Ok((name, vers)) => {
+                    Some(format!("the download is {}-{} instead", name, vers))
+                },
+                Err(e) => Some(e),
+            };
+            if let Some(e) = error {
+                results.network_failure(&c,
+                                        "verifying the contents of",
+                                        e,
+                                        settings,
+                                        on_event);
+                continue;
+            }
+        }
+
+        /* Crates confirmed in this run are never removed, so that the run
+         * state and the verified crates stay true */
+        if let Some(ref mut evictor) = evictor {
+            if !evictor.make_room(output.len() as u64, |x| run_state.is_done(x)) {
+                println!("Warning: Stopping because {} doesn't fit under --max-archive-size without removing crates needed by this run, the next run will continue from here",
+                         crate_name);
+                results.stopped_early = true;
+                break;
+            }
+        }
+
+        if let Err(e) = storage.write(&c, &output) {
+            if !storage.is_remote() {
+                error!("{}", e);
+            }
+            results.network_failure(&c, "uploading", e, settings, on_event);
+            continue;
+        }
+
+        save_metadata(crates_dir, &c);
+        if settings.provenance {
+            save_provenance(crates_dir, &c, &url, response.as_ref(), &output);
+        }
+        if let Some(metadata) = storage.local_path(&c).and_then(|x| fs::metadata(x).ok()) {
+            verify_cache.insert(&crate_name, &metadata, &hash);
+        }
+        if let Some(ref mut etags) = etags {
+            etags.set(&crate_name, etag);
+        }
+        if let Some(ref mut evictor) = evictor {
+            evictor.add(&crate_name, output.len() as u64);
+        }
+        run_state.record(&crate_name);
+        on_event(MirrorEvent::Downloaded {
+                     krate: &c,
+                     bytes: output.len(),
+                     duration,
+                 });
+        results.downloaded.add(output.len());
+        results.verified.push((crate_name, c.checksum_algorithm(), hash));
+    }
+
+    if let Some(pool) = verify_pool {
+        for (job, hash) in pool.finish() {
+            repairs.extend(check_existing(job,
+                                          hash,
+                                          settings,
+                                          &mut verify_cache,
+                                          &mut run_state,
+                                          &mut results,
+                                          on_event));
+        }
+    }
+
+    verify_cache.write();
+    if let Some(ref mut etags) = etags {
+        etags.write();
+    }
+    /* Keep the state of an unfinished run so the next one can resume it */
+    if !results.stopped_early && !results.aborted {
+        run_state.finish();
+    }
+
+    if !results.checksum_mismatches.is_empty() {
+        println!("Warning: The following {} crates were not saved because their checksum did not match the checksum in the index:",
+                  results.checksum_mismatches.len());
+    }
+    for (c, downloaded_hash) in &results.checksum_mismatches {
+        println!("	{}-{} expected hash {} but received file with hash {}",
+                  c.name,
+                  c.vers,
+                  c.cksum,
+                  downloaded_hash);
+    }
+
+    if !results.unavailable.is_empty() {
+        println!("Warning: The following {} crates could not be found upstream:",
+                 results.unavailable.len());
+    }
+    for c in &results.unavailable {
+        println!("	{}-{}", c.name, c.vers);
+    }
+
+    if !results.download_failures.is_empty() {
+        println!("Warning: The following {} crates failed to transfer:",
+                 results.download_failures.len());
+    }
+    for (c, e) in &results.download_failures {
+        println!("	{}-{}: {}", c.name, c.vers, e);
+    }
+
+    if !results.malformed.is_empty() {
+        println!("Warning: The following {} crates matched their checksum, but are not valid .crate files:",
+                 results.malformed.len());
+    }
+    for (c, e) in &results.malformed {
+        println!("	{}-{}: {}", c.name, c.vers, e);
+    }
+
+    if !results.invalid_names.is_empty() {
+        println!("Warning: The following {} crates were skipped because they can't be stored safely:",
+                 results.invalid_names.len());
+    }
+    for (_, e) in &results.invalid_names {
+        println!("	{}", e);
+    }
+
+    if !results.too_large.is_empty() {
+        println!("Info: The following {} crates were skipped because they are larger than --skip-larger-than:",
+                 results.too_large.len());
+    }
+    for &(ref c, bytes) in &results.too_large {
+        println!("	{}-{}: {} bytes", c.name, c.vers, bytes);
+    }
+
+    if !results.cdn_fallbacks.is_empty() {
+        println!("Info: The following {} crates were downloaded from the URL in config.json because downloading them from the CDN failed:",
+                 results.cdn_fallbacks.len());
+    }
+    for c in &results.cdn_fallbacks {
+        println!("	{}-{}", c.name, c.vers);
+    }
+
+    if !results.slow_downloads.is_empty() {
+        println!("Info: The following {} crates took longer than {}s to download:",
+                 results.slow_downloads.len(),
+                 settings.slow_download.as_secs());
+    }
+    for &(ref c, bytes, duration) in &results.slow_downloads {
+        println!("	{}-{}: {} bytes in {:.2}s ({:.2} MB/s)",
+                 c.name,
+                 c.vers,
+                 bytes,
+                 duration.as_secs_f64(),
+                 megabytes_per_second(bytes, duration));
+    }
+
+    results
+}
+
+/// What a run goes through, the index commit and the options that choose the
+/// crates from it, so that an interrupted run is only resumed by a run of the
+/// same crates
+fn run_description(settings: &Settings) -> String {
+    let index = match settings.index_tarball {
+        Some(ref x) => {
+            let modified = fs::metadata(x)
+                .and_then(|x| x.modified())
+                .ok()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map(|x| x.as_secs());
+            serde_json::json!({"tarball": x, "modified": modified})
+        },
+        None => serde_json::json!(index_commit(&settings.archive.join("index"))),
+    };
+    serde_json::json!({
+        "index": index,
+        "download_yanked": settings.download_yanked,
+        "download_old": settings.download_old,
+        "only_yanked": settings.only_yanked,
+        "stable_only": settings.stable_only,
+        "max_rust_version": settings.max_rust_version,
+        "skip_unavailable": settings.skip_unavailable,
+        "from_names": settings.from_names,
+        "cargo_lock": settings.cargo_lock.as_ref().map(|x| x.keys().collect::<Vec<_>>()),
+        "since": settings.since,
+        "shard": settings.shard,
+        "name_prefix": settings.name_prefix,
+        "depends_on": settings.depends_on,
+        "dependency_closure": settings.dependency_closure,
+        "check_sums": settings.check_sums,
+    })
+    .to_string()
+}
+
+/// Check that the hash of an already downloaded crate file matches the index.
+/// If it doesn't, the file is removed and the crate is returned so that it can
+/// be downloaded again, or with --no-auto-repair the program exits.
+fn check_existing(job: VerifyJob,
+                  hash: String,
+                  settings: &Settings,
+                  verify_cache: &mut VerifyCache,
+                  run_state: &mut RunState,
+                  results: &mut FetchResults,
+                  on_event: &mut dyn FnMut(MirrorEvent))
+                  -> Option<Crate> {
+    if hash != job.krate.cksum {
+        if !settings.auto_repair {
+            error!(exit EXIT_MISMATCH,
+                   "Checksum mismatch in {}. Expected {} but file's hash is {}",
+                   job.path.to_string_lossy(),
+                   job.krate.cksum,
+                   hash);
+        }
+        /* Most likely bit rot or a file left over from a crash, which a
+         * fresh download fixes */
+        println!("Warning: Checksum mismatch in {}. Expected {} but file's hash is {}, removing it so it is downloaded again",
+                 job.path.to_string_lossy(),
+                 job.krate.cksum,
+                 hash);
+        match fs::remove_file(&job.path) {
+            Ok(()) => (),
+            Err(e) => {
+                error!("Error removing {}: {}", job.path.to_string_lossy(), e)
+            },
+        }
+        return Some(job.krate);
+    }
+    save_metadata(&settings.archive, &job.krate);
+    verify_cache.insert(&job.crate_name, &job.metadata, &hash);
+    run_state.record(&job.crate_name);
+    on_event(MirrorEvent::Skipped { krate: &job.krate });
+    results
+        .verified
+        .push((job.crate_name, job.krate.checksum_algorithm(), hash));
+    None
+}
+
+/// Write the index entry of the crate to metadata/{name}/{vers}.json in the
+/// archive, if it has changed. Only called once the crate is in the archive, so
+/// that there is no metadata for crates that failed to download.
+fn save_metadata(archive: &Path, c: &Crate) {
+    let index_line = match c.index_line {
+        Some(ref x) => x,
+        None => return,
+    };
+    let path = archive
+        .join("metadata")
+        .join(&c.name)
+        .join(format!("{}.json", c.vers));
+    let contents = format!("{}\n", index_line);
+    /* Entries only change when a crate is yanked or unyanked */
+    if fs::read_to_string(&path).ok().as_ref() == Some(&contents) {
+        return;
+    }
+
+    if let Some(parent) = path.parent() {
+        create_dir(parent);
+    }
+    if let Err(e) = write_atomically(&path, contents) {
+        error!("{}", e);
+    }
+}
+
+/// Where and when a crate file was downloaded, written by --provenance
+#[derive(Serialize)]
+struct Provenance<'a> {
+    /// The URL the file was received from, after any redirects
+    url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<&'a str>,
+    /// Seconds since the unix epoch
+    timestamp: f64,
+    sha256: String,
+}
+
+/// Record where the crate was downloaded from in
+/// provenance/{name}/{vers}.json in the archive. The URL that was requested
+/// is used if the downloader doesn't know where the response came from.
+fn save_provenance(archive: &Path,
+                   c: &Crate,
+                   url: &str,
+                   response: Option<&download::ResponseInfo>,
+                   data: &[u8]) {
+    let path = archive
+        .join("provenance")
+        .join(&c.name)
+        .join(format!("{}.json", c.vers));
+    let provenance = Provenance {
+        url: response.map_or(url, |x| &x.url),
+        status: response.map(|x| x.status),
+        server: response.and_then(|x| x.server.as_deref()),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs_f64())
+            .unwrap_or(0.0),
+        sha256: sha256sum(data),
+    };
+    let contents = serde_json::to_string_pretty(&provenance)
+        .expect("Error encoding provenance") + "\n";
+
+    if let Some(parent) = path.parent() {
+        create_dir(parent);
+    }
+    if let Err(e) = write_atomically(&path, contents) {
+        error!("{}", e);
+    }
+}
+
+/// The json file written next to quarantined downloads
+#[derive(Serialize)]
+struct QuarantineInfo<'a> {
+    name: &'a str,
+    vers: &'a str,
+    url: &'a str,
+    expected: &'a str,
+    received: &'a str,
+}
+
+/// Save a download whose checksum didn't match the index in the quarantine
+/// directory, as `{name}-{vers}.badsum` and `{name}-{vers}.json`
+fn quarantine_crate(quarantine: &Path,
+                    c: &Crate,
+                    url: &str,
+                    data: &[u8],
+                    hash: &str) {
+    let base = format!("{}-{}", c.name, c.vers);
+    let info = QuarantineInfo {
+        name: &c.name,
+        vers: &c.vers,
+        url,
+        expected: &c.cksum,
+        received: hash,
+    };
+    let info = serde_json::to_string_pretty(&info)
+        .expect("Error encoding quarantine info");
+
+    for &(ref path, contents) in
+        &[(quarantine.join(format!("{}.badsum", base)), data),
+          (quarantine.join(format!("{}.json", base)), info.as_bytes())] {
+        match fs::write(path, contents) {
+            Ok(()) => (),
+            Err(e) => {
+                error!("Error writing to {}: {}", path.to_string_lossy(), e)
+            },
+        }
+    }
+    println!("Saved the received file in {}",
+             quarantine.join(format!("{}.badsum", base)).to_string_lossy());
+}
+
+/// Move all the .crate files in the staging directory into the archive
+///
+/// Each file is hard linked into the archive where possible, so that nothing
+/// is copied when the staging directory is on the same filesystem, and
+/// copied otherwise. Either way it only appears under its name in the archive
+/// once it is complete.
+fn promote_staged_crates(staging: &Path, archive: &Path) {
+    let entries = match fs::read_dir(staging) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Error reading directory {}: {}",
+                   staging.to_string_lossy(),
+                   e)
+        },
+    };
+
+    let mut count = 0;
+    for entry in entries {
+        let entry = match entry {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error reading directory {}: {}",
+                       staging.to_string_lossy(),
+                       e)
+            },
+        };
+        let staged = entry.path();
+        if staged.extension().is_none_or(|x| x != "crate") {
+            continue;
+        }
+        let target = archive.join(entry.file_name());
+        let partfile = target.with_extension("crate.part");
+
+        /* Hard linking fails if the staging directory is on a different
+         * filesystem, or one without hard links, or if a partfile was left
+         * behind by an interrupted run, in which case we have to copy the
+         * file over */
+        if fs::hard_link(&staged, &partfile).is_err() {
+            match fs::copy(&staged, &partfile) {
+                Ok(_) => (),
+                Err(e) => {
+                    error!("Error copying {} to {}: {}",
+                           staged.to_string_lossy(),
+                           partfile.to_string_lossy(),
+                           e)
+                },
+            }
+        }
+        match fs::rename(&partfile, &target) {
+            Ok(()) => (),
+            Err(e) => {
+                error!("Error renaming {} to {}: {}",
+                       partfile.to_string_lossy(),
+                       target.to_string_lossy(),
+                       e)
+            },
+        }
+        match fs::remove_file(&staged) {
+            Ok(()) => (),
+            Err(e) => {
+                error!("Error removing {}: {}", staged.to_string_lossy(), e)
+            },
+        }
+        count += 1;
+    }
+
+    println!("Moved {} new crates from {} into {}",
+             count,
+             staging.to_string_lossy(),
+             archive.to_string_lossy());
+}
+
+/// What replace_url did
+#[derive(Debug, PartialEq)]
+enum Replaced {
+    /// config.json already had the URL
+    Unchanged,
+    /// config.json was changed, in the commit with this hash
+    Committed(String),
+}
+
+/// Replace the download URL in config.json with new_url and commit it, so
+/// that cargo downloads the crates from the mirror. The original URL is kept
+/// in config.json too.
+fn replace_url(new_url: &str, git_dir: &Path, settings: &Settings) -> Result<Replaced, String> {
+    /* First we edit the actual file (if need be) */
+    let mut config = ConfigJsonFile::read(git_dir);
+
+    if new_url == config.dl {
+        return Ok(Replaced::Unchanged);
+    }
+
+    let dl_orig = if let Some(x) = config.dl_orig {
+        x
+    } else {
+        config.dl
+    };
+
+    config.dl = new_url.to_string();
+    config.dl_orig = Some(dl_orig);
+
+    config.write(git_dir);
+
+    /* Now we commit the changes */
+    let failed = |action: &str, e: git2::Error| {
+        format!("Error {} in the index repository at {}: {}",
+                action,
+                git_dir.to_string_lossy(),
+                e)
+    };
+    let repo = Repository::open(git_dir).map_err(|e| failed("opening it", e))?;
+
+    let mut index = repo.index().map_err(|e| failed("getting the index", e))?;
+
+    /* git add config.json */
+    index.add_path(Path::new("config.json"))
+        .and_then(|()| index.write())
+        .map_err(|e| failed("adding config.json", e))?;
+    let tree = index.write_tree()
+        .and_then(|x| repo.find_tree(x))
+        .map_err(|e| failed("writing the tree", e))?;
+
+    /* git commit -m "crates-ectype updating DL location" */
+    let (ref author_name, ref author_email) = settings.commit_author;
+    let parent = repo.head()
+        .and_then(|x| x.peel_to_commit())
+        .map_err(|e| failed("getting the HEAD commit", e))?;
+    let sig = git2::Signature::now(author_name, author_email)
+        .map_err(|e| failed("creating the signature of the commit", e))?;
+    let commit = repo.commit(Some("HEAD"),
+                             &sig,
+                             &sig,
+                             &settings.commit_message,
+                             &tree,
+                             &[&parent])
+        .map_err(|e| failed("committing the new URL", e))?;
+
+    Ok(Replaced::Committed(commit.to_string()))
+}
+
+/// Whether the string looks like an absolute URL, with a scheme and a host
+fn is_url(s: &str) -> bool {
+    let (scheme, rest) = match s.split_once("://") {
+        Some(x) => x,
+        None => return false,
+    };
+    let host = rest.split(['/', '?', '#']).next().unwrap_or("");
+    /* Only file URLs may leave out the host */
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic()) &&
+    scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) &&
+    (!host.is_empty() || scheme == "file") && !s.contains(char::is_whitespace)
+}
+
+/// Push the checked out branch of the index repository to the given URL
+///
+/// This is only a force push with --force-push. Every update of the index
+/// resets it to upstream, so the commit made by replace_url is usually not a
+/// descendant of the previously pushed one, but replacing a branch someone
+/// else may have pushed to shouldn't happen without asking for it.
+fn push_index(git_dir: &Path, url: &str, settings: &Settings) -> Result<(), String> {
+    let repo = match Repository::open(git_dir) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Error opening index repository at {}: {}",
+                               git_dir.to_string_lossy(),
+                               e))
+        },
+    };
+    let branch = match repo.head() {
+        Ok(ref x) if x.is_branch() => {
+            x.name().expect("push_index branch name is not utf-8").to_string()
+        },
+        Ok(_) => return Err("Error pushing index: HEAD is not a branch".to_string()),
+        Err(e) => return Err(format!("Error getting index HEAD: {}", e)),
+    };
+    let config = match repo.config() {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Error reading index git config: {}", e)),
+    };
+
+    println!("Pushing index to {}", url);
+
+    /* The rejection reasons reported by the remote, if any */
+    let mut rejected = Vec::new();
+    let result = {
+        let mut callbacks = remote_callbacks(settings);
+        callbacks.credentials(|url, username, allowed| {
+            if allowed.contains(git2::CredentialType::SSH_KEY) {
+                git2::Cred::ssh_key_from_agent(username.unwrap_or("git"))
+            } else if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+                git2::Cred::credential_helper(&config, url, username)
+            } else {
+                git2::Cred::default()
+            }
+        });
+        callbacks.push_update_reference(|refname, status| {
+            if let Some(status) = status {
+                rejected.push(format!("{}: {}", refname, status));
+            }
+            Ok(())
+        });
+        let mut push_options = git2::PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+
+        let mut remote = match repo.remote_anonymous(url) {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Invalid push URL {}: {}", url, e)),
+        };
+        let force = if settings.force_push { "+" } else { "" };
+        remote.push(&[&format!("{}{}:{}", force, branch, branch)],
+                    Some(&mut push_options))
+    };
+
+    match result {
+        Ok(()) if rejected.is_empty() => {
+            println!("Done pushing index");
+            Ok(())
+        },
+        Ok(()) if !settings.force_push &&
+                  rejected.iter().any(|x| x.contains("fast-forward") || x.contains("fetch first")) => {
+            Err(format!("Error pushing index, the remote rejected the update of {}, pass --force-push to replace the branch",
+                        rejected.join(", ")))
+        },
+        Ok(()) => {
+            Err(format!("Error pushing index, the remote rejected the update of {}",
+                        rejected.join(", ")))
+        },
+        Err(ref e) if e.code() == git2::ErrorCode::Auth => {
+            Err(format!("Error pushing index, authentication failed: {}", e))
+        },
+        Err(ref e) if e.code() == git2::ErrorCode::NotFastForward => {
+            Err(format!("Error pushing index, the remote rejected a non-fast-forward update, pass --force-push to replace the branch: {}",
+                        e))
+        },
+        Err(e) => Err(format!("Error pushing index: {}", e)),
+    }
+}
+
+/// Read the file into buf and return its sha256sum, exiting if it can't be
+/// read
+fn sha256sum_file(path: &Path, buf: &mut Vec<u8>) -> String {
+    checksum::Algorithm::Sha256.hash_file(path, buf)
+}
+
+/// Calculate the sha256sum of the data, returning it as a hex string
+fn sha256sum(data: &[u8]) -> String {
+    checksum::Algorithm::Sha256.hash(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use download::MockDownloader;
+    use storage::MemoryStorage;
+
+    /// Create an empty directory for a test to use as its archive
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("crates-ectype-test-{}-{}",
+                                               name,
+                                               ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub(crate) fn test_settings(archive: &Path, args: &[&str]) -> Settings {
+        let mut args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        args.push(archive.to_string_lossy().into_owned());
+        Settings::parse(&args)
+    }
+
+    fn test_config() -> ConfigJsonFile {
+        ConfigJsonFile {
+            dl: "https://crates.io/api/v1/crates".to_string(),
+            api: Some("https://crates.io".to_string()),
+            dl_orig: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    /// A crate whose index checksum matches the given contents
+    pub(crate) fn test_crate(name: &str, vers: &str, contents: &[u8]) -> Crate {
+        Crate {
+            yanked: false,
+            cksum: sha256sum(contents),
+            ..Crate::new(name, vers)
+        }
+    }
+
+    #[test]
+    fn parse_index_json() {
+        let c: Crate = serde_json::from_str(r#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"abc","features":{},"yanked":false}"#).unwrap();
+        assert_eq!(c.name, "foo");
+        assert_eq!(c.vers, "0.1.0");
+        assert_eq!(c.cksum, "abc");
+        assert!(!c.yanked);
+        assert_eq!(c.v, None);
+
+        /* Alternative registries may have fields we don't know about */
+        let c: Crate = serde_json::from_str(r#"{"name":"bar","vers":"1.0.0","deps":[],"cksum":"def","features":{},"yanked":true,"links":"bar","v":2,"features2":{}}"#).unwrap();
+        assert_eq!(c.name, "bar");
+        assert_eq!(c.v, Some(2));
+
+        /* config.json files without dl_orig are the upstream ones */
+        let config: ConfigJsonFile = serde_json::from_str(r#"{"dl":"https://crates.io/api/v1/crates","api":"https://crates.io"}"#).unwrap();
+        assert_eq!(config.dl_orig, None);
+
+        let replaced = ConfigJsonFile {
+            dl: "http://localhost/crates".to_string(),
+            dl_orig: Some(config.dl),
+            ..config
+        };
+        let json = serde_json::to_string(&replaced).unwrap();
+        let config: ConfigJsonFile = serde_json::from_str(&json).unwrap();
+        assert_eq!(config.dl, "http://localhost/crates");
+        assert_eq!(config.dl_orig,
+                   Some("https://crates.io/api/v1/crates".to_string()));
+
+        /* Fields we don't know about are written back unchanged, and
+         * registries don't need an api */
+        let config: ConfigJsonFile = serde_json::from_str(r#"{"dl":"https://dl.example.com","auth-required":true}"#).unwrap();
+        assert_eq!(config.api, None);
+        assert_eq!(serde_json::to_value(&config).unwrap(),
+                   serde_json::json!({"dl": "https://dl.example.com",
+                                      "dl_orig": null,
+                                      "auth-required": true}));
+    }
+
+    #[test]
+    fn read_index_selects_newest() {
+        let dir = test_dir("index");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        test_config().write(&git_dir);
+        fs::write(git_dir.join("3/f/foo"),
+                  concat!(r#"{"name":"foo","vers":"0.1.0","cksum":"a","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"foo","vers":"0.2.0","cksum":"b","yanked":false}"#,
+                          "\n"))
+            .unwrap();
+        fs::write(git_dir.join("3/f/bar"),
+                  concat!(r#"{"name":"bar","vers":"1.0.0","cksum":"c","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"bar","vers":"1.1.0","cksum":"d","yanked":true}"#,
+                          "\n"))
+            .unwrap();
+
+        let crates = read_crate_index(&git_dir, &test_settings(&dir, &[]));
+        assert_eq!(crates.into_iter().collect::<Vec<_>>(),
+                   vec![Crate::new("foo", "0.2.0")]);
+
+        let settings = test_settings(&dir, &["--save-metadata"]);
+        let crates = read_crate_index(&git_dir, &settings);
+        assert_eq!(crates[0].index_line,
+                   Some(r#"{"name":"foo","vers":"0.2.0","cksum":"b","yanked":false}"#.to_string()));
+        /* Nothing is written for crates that fail to download */
+        let mut downloader = MockDownloader::new();
+        fetch_crates(crates.clone(),
+                     &test_config(),
+                     &settings,
+                     &LocalStorage::new(&settings),
+                     &mut downloader,
+                     &mut |_| ());
+        assert!(!dir.join("metadata").exists());
+
+        let settings = test_settings(&dir, &["--save-metadata", "--no-check-sums"]);
+        let storage = LocalStorage::new(&settings);
+        storage.write(&Crate::new("foo", "0.2.0"), b"foo").unwrap();
+        fetch_crates(crates,
+                     &test_config(),
+                     &settings,
+                     &storage,
+                     &mut downloader,
+                     &mut |_| ());
+        assert_eq!(fs::read_to_string(dir.join("metadata/foo/0.2.0.json"))
+                       .unwrap(),
+                   "{\"name\":\"foo\",\"vers\":\"0.2.0\",\"cksum\":\"b\",\"yanked\":false}\n");
+
+        let crates = read_crate_index(&git_dir,
+                                      &test_settings(&dir,
+                                                     &["--download-old",
+                                                       "--yanked"]));
+        assert_eq!(crates.len(), 4);
+
+        /* The first of duplicate entries is used */
+        fs::write(git_dir.join("3/f/bar"),
+                  concat!(r#"{"name":"bar","vers":"1.0.0","cksum":"c","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"bar","vers":"1.0.0","cksum":"e","yanked":false}"#,
+                          "\n"))
+            .unwrap();
+        let crates = read_crate_index(&git_dir, &test_settings(&dir, &[]));
+        let bar = crates.iter().find(|c| c.name == "bar").unwrap();
+        assert_eq!(bar.cksum, "c");
+
+        /* Crates only differing in case would share the same crate file */
+        fs::write(git_dir.join("3/f/bar"),
+                  concat!(r#"{"name":"bar","vers":"1.0.0","cksum":"c","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"Bar","vers":"1.0.0","cksum":"e","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"Bar","vers":"1.1.0","cksum":"f","yanked":false}"#,
+                          "\n"))
+            .unwrap();
+        let crates = read_crate_index(&git_dir,
+                                      &test_settings(&dir, &["--download-old"]));
+        let bars: Vec<(&str, &str)> = crates
+            .iter()
+            .filter(|c| c.name.eq_ignore_ascii_case("bar"))
+            .map(|c| (c.name.as_str(), c.vers.as_str()))
+            .collect();
+        assert_eq!(bars, vec![("Bar", "1.1.0"), ("bar", "1.0.0")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_max_rust_version() {
+        let dir = test_dir("rust-version");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        test_config().write(&git_dir);
+        fs::write(git_dir.join("3/f/foo"),
+                  concat!(r#"{"name":"foo","vers":"0.1.0","cksum":"a","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"foo","vers":"0.2.0","cksum":"b","yanked":false,"rust_version":"1.56"}"#,
+                          "\n",
+                          r#"{"name":"foo","vers":"0.3.0","cksum":"c","yanked":false,"rust_version":"1.70.0"}"#,
+                          "\n"))
+            .unwrap();
+        fs::write(git_dir.join("3/f/bar"),
+                  concat!(r#"{"name":"bar","vers":"1.0.0","cksum":"d","yanked":false}"#,
+                          "\n"))
+            .unwrap();
+
+        let settings = test_settings(&dir, &["--max-rust-version", "1.60"]);
+        let crates = read_crate_index(&git_dir, &settings);
+        assert_eq!(crates.into_iter().collect::<Vec<_>>(),
+                   vec![Crate::new("bar", "1.0.0"), Crate::new("foo", "0.2.0")]);
+
+        assert_eq!(parse_rust_version("1.56"), Some((1, 56, 0)));
+        assert_eq!(parse_rust_version("1.56.1"), Some((1, 56, 1)));
+        assert_eq!(parse_rust_version("1"), Some((1, 0, 0)));
+        assert_eq!(parse_rust_version("1.56.1.2"), None);
+        assert_eq!(parse_rust_version("1.x"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_from_names() {
+        assert_eq!(index_path("a"), Path::new("1/a"));
+        assert_eq!(index_path("ab"), Path::new("2/ab"));
+        assert_eq!(index_path("Foo"), Path::new("3/f/foo"));
+        assert_eq!(index_path("serde"), Path::new("se/rd/serde"));
+
+        let dir = test_dir("from-names");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        fs::create_dir_all(git_dir.join("se/rd")).unwrap();
+        test_config().write(&git_dir);
+        fs::write(git_dir.join("3/f/foo"),
+                  r#"{"name":"foo","vers":"0.1.0","cksum":"a","yanked":false}"#)
+            .unwrap();
+        fs::write(git_dir.join("se/rd/serde"),
+                  r#"{"name":"serde","vers":"1.0.0","cksum":"b","yanked":false}"#)
+            .unwrap();
+        let names = dir.join("names");
+        fs::write(&names, "# wanted crates\nserde\nmissing\n").unwrap();
+
+        let settings = test_settings(&dir,
+                                     &["--from-names-file",
+                                       &names.to_string_lossy()]);
+        let crates = read_crate_index(&git_dir, &settings);
+        assert_eq!(crates.into_iter().collect::<Vec<_>>(),
+                   vec![Crate::new("serde", "1.0.0")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_from_cargo_lock() {
+        let dir = test_dir("from-cargo-lock");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        fs::create_dir_all(git_dir.join("se/rd")).unwrap();
+        test_config().write(&git_dir);
+        fs::write(git_dir.join("3/f/foo"),
+                  concat!(r#"{"name":"foo","vers":"0.1.0","cksum":"a","yanked":true}"#,
+                          "\n",
+                          r#"{"name":"foo","vers":"0.2.0","cksum":"b","yanked":false}"#,
+                          "\n"))
+            .unwrap();
+        fs::write(git_dir.join("se/rd/serde"),
+                  r#"{"name":"serde","vers":"1.0.0","cksum":"c","yanked":false}"#)
+            .unwrap();
+        let lockfile = dir.join("Cargo.lock");
+        fs::write(&lockfile,
+                  r#"
+[[package]]
+name = "foo"
+version = "0.1.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "a"
+
+[[package]]
+name = "serde"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "tampered"
+"#)
+            .unwrap();
+
+        let settings = test_settings(&dir,
+                                     &["--from-cargo-lock",
+                                       &lockfile.to_string_lossy()]);
+        let crates = read_crate_index(&git_dir, &settings);
+        assert_eq!(crates.into_iter().collect::<Vec<_>>(),
+                   vec![Crate::new("foo", "0.1.0")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_symlinked_archive() {
+        let dir = test_dir("symlinks");
+        let real = dir.join("real");
+        let link = dir.join("link");
+        fs::create_dir(&real).unwrap();
+        ::std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        create_dir(&link);
+        assert_eq!(resolve_dir(&link), fs::canonicalize(&real).unwrap());
+        assert_eq!(resolve_dir(&link.join("../link")),
+                   fs::canonicalize(&real).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_unavailable_crates() {
+        let dir = test_dir("unavailable");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/s")).unwrap();
+        test_config().write(&git_dir);
+        fs::write(git_dir.join("3/s/std"),
+                  r#"{"name":"STD","vers":"0.1.0","cksum":"a","yanked":false}"#)
+            .unwrap();
+
+        assert!(read_crate_index(&git_dir, &test_settings(&dir, &[])).is_empty());
+        let settings = test_settings(&dir, &["--no-skip-unavailable"]);
+        assert_eq!(read_crate_index(&git_dir, &settings).len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_stable_only() {
+        assert!(is_prerelease("1.0.0-beta.1"));
+        assert!(is_prerelease("1.0.0-rc.1+build-5"));
+        assert!(!is_prerelease("1.0.0"));
+        assert!(!is_prerelease("1.0.0+build-5"));
+
+        let dir = test_dir("stable-only");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        test_config().write(&git_dir);
+        fs::write(git_dir.join("3/f/foo"),
+                  concat!(r#"{"name":"foo","vers":"0.1.0","cksum":"a","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"foo","vers":"0.2.0-alpha.1","cksum":"b","yanked":false}"#,
+                          "\n"))
+            .unwrap();
+        fs::write(git_dir.join("3/f/bar"),
+                  r#"{"name":"bar","vers":"1.0.0-rc.1","cksum":"c","yanked":false}"#)
+            .unwrap();
+
+        let crates = read_crate_index(&git_dir,
+                                      &test_settings(&dir, &["--stable-only"]));
+        assert_eq!(crates.into_iter().collect::<Vec<_>>(),
+                   vec![Crate::new("foo", "0.1.0")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_malformed_lines() {
+        let dir = test_dir("malformed-lines");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        test_config().write(&git_dir);
+        fs::write(git_dir.join("3/f/foo"),
+                  concat!(r#"{"name":"foo","vers":"0.1.0","cksum":"a","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"foo","vers":"0.2.0","cks"#,
+                          "\n"))
+            .unwrap();
+
+        let mut crates = Vec::new();
+        let skipped = walk_crate_index(&git_dir,
+                                       &test_settings(&dir, &[]),
+                                       |c| {
+                                           crates.push(c);
+                                           true
+                                       });
+        assert_eq!(skipped.malformed, 1);
+        assert_eq!(crates, vec![Crate::new("foo", "0.1.0")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn crate_filenames() {
+        assert!(check_crate_filename("serde_json", "1.0.0").is_ok());
+        assert!(check_crate_filename("foo-bar", "0.1.0-alpha.1+build.5").is_ok());
+
+        for name in &["",
+                      "..",
+                      "../../etc/passwd",
+                      "foo/bar",
+                      "foo\\bar",
+                      "/absolute",
+                      "foo\0",
+                      "foo\nbar",
+                      "foo\u{1b}[31m",
+                      "f\u{f3}o"] {
+            assert!(check_crate_filename(name, "0.1.0").is_err(), "{:?}", name);
+        }
+        for vers in &["", "..", "1..0", "../../x", "1.0.0/../../x", "v1.0.0", "1.0.0\n", "1.0 .0"] {
+            assert!(check_crate_filename("foo", vers).is_err(), "{:?}", vers);
+        }
+        assert!(check_crate_filename(&"a".repeat(200), "1.0.0").is_ok());
+        assert!(check_crate_filename(&"a".repeat(300), "1.0.0").is_err());
+        assert!(check_crate_filename("foo", &format!("1.0.0-{}", "a".repeat(300))).is_err());
+    }
+
+    #[test]
+    fn read_index_adversarial_names() {
+        let dir = test_dir("adversarial-names");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        test_config().write(&git_dir);
+        /* Only the valid crate is in the archive, with the right checksum */
+        fs::write(git_dir.join("3/f/foo"),
+                  format!(r#"{{"name":"foo","vers":"0.1.0","cksum":"{}","yanked":false}}"#,
+                          sha256sum(b"foo")) +
+                  concat!("\n",
+                          r#"{"name":"../../foo","vers":"0.2.0","cksum":"a","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"foo","vers":"0.3.0/../../../x","cksum":"a","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"foo\u0000","vers":"0.4.0","cksum":"a","yanked":false}"#,
+                          "\n"))
+            .unwrap();
+
+        let settings = test_settings(&dir, &["--download-old"]);
+        let mut crates = Vec::new();
+        let skipped = walk_crate_index(&git_dir,
+                                       &settings,
+                                       |c| {
+                                           crates.push(c);
+                                           true
+                                       });
+        assert_eq!(skipped.malformed, 0);
+        assert_eq!(skipped.invalid_names.len(), 3);
+        assert_eq!(crates, vec![Crate::new("foo", "0.1.0")]);
+
+        /* The skipped crates make the run exit with EXIT_MISMATCH, even
+         * though fetch_crates never sees them */
+        let storage = LocalStorage::new(&settings);
+        storage.write(&crates[0], b"foo").unwrap();
+        let mut results = fetch_crates(crates,
+                                       &test_config(),
+                                       &settings,
+                                       &storage,
+                                       &mut MockDownloader::new(),
+                                       &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+        results.add_skipped(skipped);
+        assert_eq!(results.exit_code(), EXIT_MISMATCH);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_adversarial_names() {
+        let dir = test_dir("fetch-adversarial-names");
+        let archive = dir.join("archive");
+        fs::create_dir_all(&archive).unwrap();
+        let settings = test_settings(&archive, &["--save-metadata"]);
+        let config = test_config();
+
+        let mut downloader = MockDownloader::new();
+        let mut crates = Vec::new();
+        for &(name, vers) in &[("../escaped", "0.1.0"), ("foo", "0.1.0/../../escaped")] {
+            let mut c = test_crate(name, vers, b"evil");
+            c.index_line = Some("{}".to_string());
+            downloader
+                .responses
+                .insert(c.download_url(&config, &settings), b"evil".to_vec());
+            crates.push(c);
+        }
+        let results = fetch_crates(crates,
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_MISMATCH);
+        assert_eq!(results.invalid_names.len(), 2);
+        assert!(downloader.requested.is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(!archive.join("metadata").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_priority_file() {
+        let dir = test_dir("priority-file");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        fs::create_dir_all(git_dir.join("3/b")).unwrap();
+        fs::create_dir_all(git_dir.join("se/rd")).unwrap();
+        test_config().write(&git_dir);
+        for (path, name) in &[("3/f/foo", "foo"),
+                              ("3/b/bar", "bar"),
+                              ("se/rd/serde", "serde")] {
+            fs::write(git_dir.join(path),
+                      format!(r#"{{"name":"{}","vers":"1.0.0","cksum":"a","yanked":false}}"#,
+                              name))
+                .unwrap();
+        }
+        let priority = dir.join("priority");
+        fs::write(&priority, "serde\nmissing\nfoo\n").unwrap();
+
+        let settings = test_settings(&dir,
+                                     &["--priority-file",
+                                       &priority.to_string_lossy()]);
+        let mut crates = Vec::new();
+        walk_crate_index(&git_dir, &settings, |c| {
+            crates.push(c.name);
+            true
+        });
+        assert_eq!(crates, vec!["serde", "foo", "bar"]);
+
+        /* Nothing more is passed on once found returns false */
+        let mut crates = Vec::new();
+        walk_crate_index(&git_dir, &settings, |c| {
+            crates.push(c.name);
+            false
+        });
+        assert_eq!(crates, vec!["serde"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_sort_order() {
+        let dir = test_dir("sort-order");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        fs::create_dir_all(git_dir.join("3/b")).unwrap();
+        test_config().write(&git_dir);
+        fs::write(git_dir.join("3/f/foo"),
+                  concat!(r#"{"name":"foo","vers":"1.10.0","cksum":"a","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"foo","vers":"1.2.0","cksum":"b","yanked":false}"#))
+            .unwrap();
+        fs::write(git_dir.join("3/b/bar"),
+                  concat!(r#"{"name":"bar","vers":"1.5.0","cksum":"c","yanked":false}"#,
+                          "\n",
+                          r#"{"name":"bar","vers":"1.5.0-rc.1","cksum":"d","yanked":false}"#))
+            .unwrap();
+        /* Short names come first in the index, but not by name */
+        fs::create_dir_all(git_dir.join("2")).unwrap();
+        fs::create_dir_all(git_dir.join("aa/aa")).unwrap();
+        fs::write(git_dir.join("2/zz"),
+                  r#"{"name":"zz","vers":"1.0.0","cksum":"e","yanked":false}"#)
+            .unwrap();
+        fs::write(git_dir.join("aa/aa/aaaa"),
+                  r#"{"name":"aaaa","vers":"1.0.0","cksum":"f","yanked":false}"#)
+            .unwrap();
+
+        let walk = |args: &[&str]| {
+            let settings = test_settings(&dir, args);
+            let mut crates = Vec::new();
+            walk_crate_index(&git_dir, &settings, |c| {
+                crates.push(format!("{}-{}", c.name, c.vers));
+                true
+            });
+            crates
+        };
+        assert_eq!(walk(&["--download-old"]),
+                   vec!["zz-1.0.0",
+                        "bar-1.5.0-rc.1",
+                        "bar-1.5.0",
+                        "foo-1.2.0",
+                        "foo-1.10.0",
+                        "aaaa-1.0.0"]);
+        assert_eq!(walk(&["--download-old", "--sort-order", "name"]),
+                   vec!["aaaa-1.0.0",
+                        "bar-1.5.0-rc.1",
+                        "bar-1.5.0",
+                        "foo-1.2.0",
+                        "foo-1.10.0",
+                        "zz-1.0.0"]);
+        assert_eq!(walk(&["--download-old", "--sort-order", "version"]),
+                   vec!["aaaa-1.0.0",
+                        "zz-1.0.0",
+                        "foo-1.2.0",
+                        "bar-1.5.0-rc.1",
+                        "bar-1.5.0",
+                        "foo-1.10.0"]);
+        /* The reports list the crates in the same order */
+        let settings = test_settings(&dir, &["--download-old", "--sort-order", "name"]);
+        assert_eq!(format_crate_list(&read_crate_index(&git_dir, &settings),
+                                     ListFormat::Csv)
+                       .lines()
+                       .nth(1),
+                   Some("aaaa,1.0.0,false,f"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_tarball() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let dir = test_dir("index-tarball");
+        let tarball = dir.join("index.tar.gz");
+        let config = serde_json::to_string(&test_config()).unwrap();
+        let files = [("crates.io-index/config.json", config.as_str()),
+                     ("crates.io-index/README.md", "not an index file"),
+                     ("crates.io-index/3/f/foo",
+                      r#"{"name":"foo","vers":"0.1.0","cksum":"a","yanked":false}"#),
+                     ("crates.io-index/.git/foo", "not in the index either"),
+                     ("crates.io-index/3/b/bar",
+                      r#"{"name":"bar","vers":"0.2.0","cksum":"b","yanked":false}"#)];
+        let mut builder =
+            tar::Builder::new(GzEncoder::new(File::create(&tarball).unwrap(),
+                                             Compression::default()));
+        for &(path, contents) in &files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(ConfigJsonFile::read_tarball(&tarball).dl, test_config().dl);
+
+        /* The index directory doesn't have to exist */
+        let git_dir = dir.join("index");
+        let walk = |args: &[&str]| {
+            let mut args = args.to_vec();
+            let tarball = tarball.to_string_lossy();
+            args.extend(&["--index-tarball", &tarball]);
+            let settings = test_settings(&dir, &args);
+            let mut crates = Vec::new();
+            walk_crate_index(&git_dir, &settings, |c| {
+                crates.push(format!("{}-{}", c.name, c.vers));
+                true
+            });
+            crates
+        };
+        assert_eq!(walk(&[]), vec!["foo-0.1.0", "bar-0.2.0"]);
+        assert_eq!(walk(&["--name-prefix", "a-c"]), vec!["bar-0.2.0"]);
+
+        let settings = test_settings(&dir, &["--index-tarball", &tarball.to_string_lossy()]);
+        let mut crates = Vec::new();
+        walk_crate_index(&git_dir, &settings, |c| {
+            crates.push(c.name);
+            false
+        });
+        assert_eq!(crates, vec!["foo"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_dependency_closure() {
+        let dir = test_dir("dependency-closure");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        fs::create_dir_all(git_dir.join("3/b")).unwrap();
+        test_config().write(&git_dir);
+        fs::write(git_dir.join("3/f/foo"),
+                  r#"{"name":"foo","vers":"1.0.0","cksum":"a","yanked":false,"deps":[{"name":"bar","req":"=0.1.0"}]}"#)
+            .unwrap();
+        fs::write(git_dir.join("3/b/bar"),
+                  concat!(r#"{"name":"bar","vers":"0.1.0","cksum":"b","yanked":true}"#,
+                          "\n",
+                          r#"{"name":"bar","vers":"0.2.0","cksum":"c","yanked":false}"#,
+                          "\n"))
+            .unwrap();
+
+        let crates = read_crate_index(&git_dir,
+                                      &test_settings(&dir, &["--dependency-closure"]));
+        /* In index order the added versions come after the selected ones */
+        assert_eq!(crates,
+                   vec![Crate::new("bar", "0.2.0"),
+                        Crate::new("foo", "1.0.0"),
+                        Crate::new("bar", "0.1.0")]);
+        let crates = read_crate_index(&git_dir,
+                                      &test_settings(&dir,
+                                                     &["--dependency-closure",
+                                                       "--sort-order",
+                                                       "name"]));
+        assert_eq!(crates,
+                   vec![Crate::new("bar", "0.1.0"),
+                        Crate::new("bar", "0.2.0"),
+                        Crate::new("foo", "1.0.0")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn download_urls() {
+        let dir = Path::new("archive");
+        let config = test_config();
+        let c = Crate::new("foo", "0.1.0");
+
+        assert_eq!(c.download_url(&config, &test_settings(dir, &[])),
+                   "https://static.crates.io/crates/foo/foo-0.1.0.crate");
+        assert_eq!(c.download_url(&config,
+                                  &test_settings(dir, &["--use-orig-dl"])),
+                   "https://crates.io/api/v1/crates/foo/0.1.0/download");
+        assert_eq!(c.download_url(&config,
+                                  &test_settings(dir,
+                                                 &["--dl-template",
+                                                   "https://cdn/{crate}/{crate}-{version}.crate"])),
+                   "https://cdn/foo/foo-0.1.0.crate");
+
+        let config = ConfigJsonFile {
+            dl: "https://dl.example.com/{prefix}/{lowerprefix}/{crate}/{crate}-{version}.crate?{sha256-checksum}"
+                .to_string(),
+            ..test_config()
+        };
+        let c = Crate {
+            cksum: "abc".to_string(),
+            ..Crate::new("Serde", "1.0.0")
+        };
+        assert_eq!(c.download_url(&config,
+                                  &test_settings(dir, &["--use-orig-dl"])),
+                   "https://dl.example.com/Se/rd/se/rd/Serde/Serde-1.0.0.crate?abc");
+        assert_eq!(c.download_url(&config,
+                                  &test_settings(dir,
+                                                 &["--index-url",
+                                                   "https://example.com/index"])),
+                   "https://dl.example.com/Se/rd/se/rd/Serde/Serde-1.0.0.crate?abc");
+        assert_eq!(Crate::new("ab", "1.0.0").registry_url("https://x/{prefix}/{crate}"),
+                   "https://x/2/ab");
+        assert_eq!(Crate::new("Foo", "1.0.0").registry_url("https://x/{prefix}/{lowerprefix}"),
+                   "https://x/3/F/3/f");
+        assert_eq!(index_path("Serde"), Path::new("se/rd/serde"));
+        assert_eq!(index_prefix("ßerde"), "ße/rd");
+    }
+
+    /// The versions of the crates, for comparing in tests
+    fn versions(crates: &[Crate]) -> Vec<&str> {
+        crates.iter().map(|c| c.vers.as_str()).collect()
+    }
+
+    #[test]
+    fn select_versions_yanked() {
+        let dir = Path::new("archive");
+        let index = vec![test_crate("foo", "0.1.0", b"a"),
+                         Crate::new("foo", "0.2.0"),
+                         test_crate("foo", "0.3.0", b"c"),
+                         Crate::new("foo", "0.4.0")];
+
+        /* The newest version is yanked, so nothing is mirrored unless yanked
+         * crates are */
+        let kept = select_versions(index.clone(), &test_settings(dir, &[]));
+        assert!(kept.is_empty());
+        let kept = select_versions(index.clone(),
+                                   &test_settings(dir, &["--yanked"]));
+        assert_eq!(versions(&kept), vec!["0.4.0"]);
+
+        let kept = select_versions(index.clone(),
+                                   &test_settings(dir, &["--download-old"]));
+        assert_eq!(versions(&kept), vec!["0.1.0", "0.3.0"]);
+        let kept = select_versions(index,
+                                   &test_settings(dir,
+                                                  &["--download-old",
+                                                    "--yanked"]));
+        assert_eq!(versions(&kept), vec!["0.1.0", "0.2.0", "0.3.0", "0.4.0"]);
+    }
+
+    #[test]
+    fn select_versions_only_yanked() {
+        let dir = Path::new("archive");
+        let index = vec![Crate::new("foo", "0.1.0"),
+                         Crate::new("foo", "0.2.0"),
+                         test_crate("foo", "0.3.0", b"c")];
+
+        let kept = select_versions(index.clone(),
+                                   &test_settings(dir, &["--only-yanked"]));
+        assert_eq!(versions(&kept), vec!["0.2.0"]);
+        let kept = select_versions(index.clone(),
+                                   &test_settings(dir,
+                                                  &["--only-yanked",
+                                                    "--download-old"]));
+        assert_eq!(versions(&kept), vec!["0.1.0", "0.2.0"]);
+        let kept = select_versions(vec![test_crate("foo", "0.1.0", b"a")],
+                                   &test_settings(dir, &["--only-yanked"]));
+        assert!(kept.is_empty());
+    }
+
+    #[test]
+    fn select_versions_newest() {
+        let dir = Path::new("archive");
+        let settings = test_settings(dir, &[]);
+        let index = vec![test_crate("foo", "0.1.0", b"a"),
+                         test_crate("foo", "0.2.0", b"b")];
+        assert_eq!(versions(&select_versions(index, &settings)), vec!["0.2.0"]);
+        assert!(select_versions(Vec::new(), &settings).is_empty());
+
+        /* The last line is taken to be the newest, even when the lines are
+         * out of order, since that's how the index is written */
+        let index = vec![test_crate("foo", "1.0.0", b"a"),
+                         test_crate("foo", "0.9.0", b"b")];
+        assert_eq!(versions(&select_versions(index.clone(), &settings)),
+                   vec!["0.9.0"]);
+        assert_eq!(versions(&select_versions(index,
+                                             &test_settings(dir,
+                                                            &["--download-old"]))),
+                   vec!["1.0.0", "0.9.0"]);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("20G"), Some(20 << 30));
+        assert_eq!(parse_size("500m"), Some(500 << 20));
+        assert_eq!(parse_size("20 GB"), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("6h"), Some(Duration::from_secs(6 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(parse_duration("6 hours"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2024-01-01"), Some(1_704_067_200));
+        assert_eq!(parse_date("2024-02-29T12:30:15Z"), Some(1_709_209_815));
+        assert_eq!(parse_date("2000-03-01 00:00:01"), Some(951_868_801));
+        assert_eq!(parse_date("1969-12-31"), Some(-86_400));
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("2024-1-1"), None);
+        assert_eq!(parse_date("2024-01-01T25:00:00"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn urls() {
+        assert!(is_url("https://mirror.example.com/{crate}/{version}"));
+        assert!(is_url("http://127.0.0.1:8080"));
+        assert!(is_url("file:///srv/crates"));
+        assert!(is_url("s3+https://bucket.example.com?x"));
+        assert!(!is_url("mirror.example.com/crates"));
+        assert!(!is_url("https://"));
+        assert!(!is_url("https://mirror example.com"));
+        assert!(!is_url("1http://mirror"));
+    }
+
+    #[test]
+    fn dump_config() {
+        let settings = test_settings(Path::new("/srv/crates"),
+                                     &["--max-duration", "2h", "--sort-order", "version"]);
+        let json = serde_json::to_value(&settings).unwrap();
+        assert_eq!(json["archive"], "/srv/crates");
+        assert_eq!(json["max_duration"], 7200.0);
+        assert_eq!(json["reverify_after"], serde_json::Value::Null);
+        assert_eq!(json["sort_order"], "version");
+    }
+
+    #[test]
+    fn env_expansion() {
+        env::set_var("ECTYPE_TEST_DIR", "/srv");
+        assert_eq!(expand_env("$ECTYPE_TEST_DIR/crates").unwrap(), "/srv/crates");
+        assert_eq!(expand_env("${ECTYPE_TEST_DIR}crates").unwrap(), "/srvcrates");
+        assert_eq!(expand_env("http://x/$").unwrap(), "http://x/$");
+        assert_eq!(expand_env("plain").unwrap(), "plain");
+        assert!(expand_env("$ECTYPE_TEST_UNSET/crates").is_err());
+        assert!(expand_env("${ECTYPE_TEST_DIR").is_err());
+
+        let settings = test_settings(Path::new("$ECTYPE_TEST_DIR/crates"),
+                                     &["--staging", "${ECTYPE_TEST_DIR}/staging"]);
+        assert_eq!(settings.archive, Path::new("/srv/crates"));
+        assert_eq!(settings.staging, Some(PathBuf::from("/srv/staging")));
+    }
+
+    #[test]
+    fn config_file() {
+        let dir = test_dir("config");
+        let path = dir.join("config.toml");
+        fs::write(&path,
+                  concat!("archive = \"/srv/crates\"\n",
+                          "yanked = true\n",
+                          "download-old = false\n",
+                          "max-crate-size = 1000\n",
+                          "user-agent = \"mirror\"\n",
+                          "redirect-host = [\"a\", \"b\"]\n"))
+            .unwrap();
+        let config = format!("--config={}", path.to_string_lossy());
+
+        let args = vec![config.clone()];
+        let settings = Settings::from(&parse_args(&options(), &args));
+        assert_eq!(settings.archive, PathBuf::from("/srv/crates"));
+        assert!(settings.download_yanked);
+        assert!(!settings.download_old);
+        assert_eq!(settings.max_crate_size, 1000);
+        assert_eq!(settings.user_agent, "mirror");
+        assert_eq!(settings.redirect_hosts, vec!["a", "b"]);
+
+        /* The command line takes precedence */
+        let args = vec![config,
+                        "--max-crate-size=5".to_string(),
+                        "--redirect-host=c".to_string(),
+                        "/tmp/archive".to_string()];
+        let settings = Settings::from(&parse_args(&options(), &args));
+        assert_eq!(settings.archive, PathBuf::from("/tmp/archive"));
+        assert!(settings.download_yanked);
+        assert_eq!(settings.max_crate_size, 5);
+        assert_eq!(settings.redirect_hosts, vec!["c"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn commit_author() {
+        assert_eq!(parse_author("Jane Doe <jane@example.com>"),
+                   Some(("Jane Doe".to_string(), "jane@example.com".to_string())));
+        assert_eq!(parse_author(" mirror<mirror@example.com> "),
+                   Some(("mirror".to_string(), "mirror@example.com".to_string())));
+        assert_eq!(parse_author("Jane Doe"), None);
+        assert_eq!(parse_author("<jane@example.com>"), None);
+        assert_eq!(parse_author("Jane <>"), None);
+    }
+
+    #[test]
+    fn crate_list_formats() {
+        let crates = vec![test_crate("foo", "0.1.0", b"foo"),
+                          test_crate("bar", "0.2.0", b"bar")];
+
+        assert_eq!(format_crate_list(&crates, ListFormat::Csv),
+                   format!("name,version,yanked,cksum\nfoo,0.1.0,false,{}\nbar,0.2.0,false,{}\n",
+                           sha256sum(b"foo"),
+                           sha256sum(b"bar")));
+
+        let json: Vec<Crate> =
+            serde_json::from_str(&format_crate_list(&crates, ListFormat::Json))
+                .unwrap();
+        assert_eq!(json, crates);
+    }
+
+    #[test]
+    fn create_nested_dir() {
+        let dir = test_dir("create-dir");
+        let nested = dir.join("new/path/archive");
+        create_dir(&nested);
+        assert!(nested.is_dir());
+        /* Already existing directories are fine */
+        create_dir(&nested);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn push_index_to_local_repo() {
+        let dir = test_dir("push");
+        let git_dir = dir.join("index");
+        let repo = Repository::init(&git_dir).unwrap();
+        test_config().write(&git_dir);
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("config.json")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@localhost").unwrap();
+        let commit = repo.commit(Some("HEAD"), &sig, &sig, "test", &tree, &[])
+            .unwrap();
+
+        let remote_dir = dir.join("remote.git");
+        let remote = Repository::init_bare(&remote_dir).unwrap();
+        push_index(&git_dir,
+                   &remote_dir.to_string_lossy(),
+                   &test_settings(&dir, &[]))
+            .unwrap();
+        assert_eq!(remote.refname_to_id("refs/heads/master").unwrap(), commit);
+
+        /* A new root commit, like the one after an index update */
+        let update = repo.commit(None, &sig, &sig, "update", &tree, &[]).unwrap();
+        repo.reference("refs/heads/master", update, true, "update").unwrap();
+        let err = push_index(&git_dir,
+                             &remote_dir.to_string_lossy(),
+                             &test_settings(&dir, &[]))
+            .unwrap_err();
+        assert!(err.contains("--force-push"), "{}", err);
+        assert_eq!(remote.refname_to_id("refs/heads/master").unwrap(), commit);
+        push_index(&git_dir,
+                   &remote_dir.to_string_lossy(),
+                   &test_settings(&dir, &["--force-push"]))
+            .unwrap();
+        assert_eq!(remote.refname_to_id("refs/heads/master").unwrap(), update);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clone_pinned_index_commit() {
+        let dir = test_dir("index-commit");
+        let upstream_dir = dir.join("upstream");
+        let repo = Repository::init(&upstream_dir).unwrap();
+        let sig = git2::Signature::now("test", "test@localhost").unwrap();
+        let mut parent = None;
+        let mut commits = Vec::new();
+        for vers in &["0.1.0", "0.2.0"] {
+            fs::write(upstream_dir.join("foo"), vers).unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new("foo")).unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let parents: Vec<git2::Commit> = parent.iter()
+                .map(|x| repo.find_commit(*x).unwrap())
+                .collect();
+            let parents: Vec<&git2::Commit> = parents.iter().collect();
+            let commit = repo.commit(Some("HEAD"),
+                                     &sig,
+                                     &sig,
+                                     vers,
+                                     &tree,
+                                     &parents)
+                .unwrap();
+            parent = Some(commit);
+            commits.push(commit);
+        }
+        let first = repo.find_object(commits[0], None).unwrap();
+        repo.tag_lightweight("v1", &first, false).unwrap();
+
+        let git_dir = dir.join("index");
+        let settings = test_settings(&dir, &["--index-commit", "v1"]);
+        update_git_repo(&git_dir, &upstream_dir.to_string_lossy(), &settings)
+            .unwrap();
+        assert_eq!(index_commit(&git_dir), Some(commits[0].to_string()));
+        assert_eq!(fs::read_to_string(git_dir.join("foo")).unwrap(), "0.1.0");
+
+        let settings = test_settings(&dir, &["--index-commit", "nonexistent"]);
+        assert!(update_git_repo(&git_dir,
+                                &upstream_dir.to_string_lossy(),
+                                &settings)
+                        .is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_since() {
+        let dir = test_dir("since");
+        let git_dir = dir.join("index");
+        let repo = Repository::init(&git_dir).unwrap();
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        fs::create_dir_all(git_dir.join("3/b")).unwrap();
+        test_config().write(&git_dir);
+        let mut parent = None;
+        for &(path, name, time) in &[("3/f/foo", "foo", 1_704_000_000),
+                                     ("3/b/bar", "bar", 1_705_000_000)] {
+            fs::write(git_dir.join(path),
+                      format!(r#"{{"name":"{}","vers":"1.0.0","cksum":"a","yanked":false}}"#,
+                              name))
+                .unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new(path)).unwrap();
+            index.write().unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let sig = git2::Signature::new("test",
+                                           "test@localhost",
+                                           &git2::Time::new(time, 0))
+                .unwrap();
+            let parents: Vec<git2::Commit> = parent.iter()
+                .map(|x| repo.find_commit(*x).unwrap())
+                .collect();
+            let parents: Vec<&git2::Commit> = parents.iter().collect();
+            parent = Some(repo.commit(Some("HEAD"), &sig, &sig, name, &tree, &parents)
+                              .unwrap());
+        }
+
+        let names = |args: &[&str]| -> Vec<String> {
+            read_crate_index(&git_dir, &test_settings(&dir, args))
+                .into_iter()
+                .map(|c| c.name)
+                .collect()
+        };
+        assert_eq!(names(&["--since", "2024-01-05"]), vec!["bar"]);
+        assert_eq!(names(&["--since", "2023-12-01"]), vec!["bar", "foo"]);
+        assert!(names(&["--since", "2024-02-01"]).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_age_from_commit() {
+        let dir = test_dir("index-age");
+        let repo = Repository::init(&dir).unwrap();
+        assert_eq!(index_age(&dir, SystemTime::now()), None);
+
+        let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::new("test",
+                                       "test@localhost",
+                                       &git2::Time::new(1_704_000_000, 0))
+            .unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(index_age(&dir, at(1_704_000_000 + 3 * 86400)),
+                   Some(Duration::from_secs(3 * 86400)));
+        assert_eq!(index_age(&dir, at(1_703_000_000)), Some(Duration::from_secs(0)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_working_tree() {
+        let dir = test_dir("working-tree");
+        let repo = Repository::init(&dir).unwrap();
+        repo.set_head("refs/heads/master").unwrap();
+        fs::write(dir.join("config.json"), "{}").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("config.json")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@localhost").unwrap();
+        let commit = repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+            .unwrap();
+        assert!(check_working_tree(&repo, false).is_ok());
+
+        repo.set_head_detached(commit).unwrap();
+        fs::write(dir.join("config.json"), "{\"dl\":\"x\"}").unwrap();
+        fs::write(dir.join("notes"), "mine").unwrap();
+        let e = check_working_tree(&repo, false).unwrap_err();
+        assert!(e.contains("detached"), "{}", e);
+        assert!(e.contains("2 files have uncommitted changes"), "{}", e);
+
+        check_working_tree(&repo, true).unwrap();
+        assert!(!repo.head_detached().unwrap());
+        assert!(!dir.join("notes").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace_dl_url() {
+        let dir = test_dir("replace");
+        let git_dir = dir.join("index");
+        let repo = Repository::init(&git_dir).unwrap();
+        test_config().write(&git_dir);
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("config.json")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@localhost").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+            .unwrap();
+
+        let settings = test_settings(&dir, &[]);
+        let url = "https://mirror.example.com/crates";
+        let commit = match replace_url(url, &git_dir, &settings).unwrap() {
+            Replaced::Committed(x) => x,
+            x => panic!("{:?}", x),
+        };
+        assert_eq!(index_commit(&git_dir), Some(commit));
+        let config = ConfigJsonFile::read(&git_dir);
+        assert_eq!(config.dl, url);
+        assert_eq!(config.dl_orig, Some(test_config().dl));
+        assert_eq!(replace_url(url, &git_dir, &settings), Ok(Replaced::Unchanged));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A short description of the event, for comparing in tests
+    fn event_summary(event: MirrorEvent) -> String {
+        match event {
+            MirrorEvent::Started { krate, .. } => format!("started {}", krate.name),
+            MirrorEvent::Downloaded { krate, bytes, .. } => {
+                format!("downloaded {} {}", krate.name, bytes)
+            },
+            MirrorEvent::Skipped { krate } => format!("skipped {}", krate.name),
+            x => format!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn fetch_crates_downloads_and_verifies() {
+        let dir = test_dir("downloads");
+        let settings = test_settings(&dir, &[]);
+        let config = test_config();
+
+        let mut crates = BTreeSet::new();
+        let mut downloader = MockDownloader::new();
+        for &(name, contents) in &[("foo", &b"foo contents"[..]),
+                                   ("bar", &b"bar contents"[..])] {
+            let c = test_crate(name, "1.0.0", contents);
+            downloader
+                .responses
+                .insert(c.download_url(&config, &settings), contents.to_vec());
+            crates.insert(c);
+        }
+
+        let mut events = Vec::new();
+        let results = fetch_crates(crates.iter().cloned(),
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |e| events.push(event_summary(e)));
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert_eq!(events,
+                   vec!["started bar", "downloaded bar 12", "started foo",
+                        "downloaded foo 12"]);
+        assert_eq!(fs::read(dir.join("foo-1.0.0.crate")).unwrap(),
+                   b"foo contents");
+        assert_eq!(fs::read(dir.join("bar-1.0.0.crate")).unwrap(),
+                   b"bar contents");
+        assert!(!dir.join("foo-1.0.0.crate.part").exists());
+
+        /* Nothing should be downloaded again on a second run */
+        downloader.requested.clear();
+        let mut events = Vec::new();
+        let results = fetch_crates(crates.iter().cloned(),
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |e| events.push(event_summary(e)));
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert!(downloader.requested.is_empty());
+        assert_eq!(events, vec!["skipped bar", "skipped foo"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_verifies_existing_in_parallel() {
+        let dir = test_dir("verify-jobs");
+        let settings = test_settings(&dir, &["--verify-jobs", "3"]);
+        let config = test_config();
+
+        let mut crates = BTreeSet::new();
+        for i in 0..10 {
+            let contents = format!("contents {}", i);
+            let c = test_crate(&format!("crate{}", i), "1.0.0", contents.as_bytes());
+            fs::write(dir.join(format!("crate{}-1.0.0.crate", i)), contents)
+                .unwrap();
+            crates.insert(c);
+        }
+
+        let mut downloader = MockDownloader::new();
+        let results = fetch_crates(crates.iter().cloned(),
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert!(downloader.requested.is_empty());
+        assert_eq!(results.verified.len(), 10);
+        assert_eq!(fs::read_to_string(dir.join(".ectype-verify-cache"))
+                       .unwrap()
+                       .lines()
+                       .count(),
+                   10);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_stops_after_max_duration() {
+        let dir = test_dir("max-duration");
+        let settings = test_settings(&dir, &["--max-duration", "0s"]);
+        let config = test_config();
+
+        let c = test_crate("foo", "1.0.0", b"foo contents");
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(c.download_url(&config, &settings),
+                    b"foo contents".to_vec());
+
+        let results = fetch_crates(vec![c],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_PARTIAL);
+        assert!(downloader.requested.is_empty());
+        assert!(dir.join(".ectype-state").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_stops_after_limit() {
+        let dir = test_dir("limit");
+        let settings = test_settings(&dir, &["--limit", "1"]);
+        let config = test_config();
+
+        let existing = test_crate("existing", "1.0.0", b"existing contents");
+        fs::write(dir.join("existing-1.0.0.crate"), b"existing contents").unwrap();
+        let crates = vec![existing,
+                          test_crate("foo", "1.0.0", b"foo contents"),
+                          test_crate("bar", "1.0.0", b"bar contents")];
+        let mut downloader = MockDownloader::new();
+        for (c, contents) in crates
+                .iter()
+                .skip(1)
+                .zip(&[b"foo contents", b"bar contents"]) {
+            downloader
+                .responses
+                .insert(c.download_url(&config, &settings), contents.to_vec());
+        }
+
+        let results = fetch_crates(crates.clone(),
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_PARTIAL);
+        assert_eq!(results.downloaded.crates, 1);
+        assert!(dir.join("foo-1.0.0.crate").exists());
+
+        /* The next run downloads the last crate, and is complete */
+        let results = fetch_crates(crates,
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert!(dir.join("bar-1.0.0.crate").exists());
+        assert_eq!(downloader.requested.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_stops_at_max_archive_size() {
+        let dir = test_dir("max-archive-size");
+        let settings = test_settings(&dir, &["--max-archive-size", "30"]);
+        let config = test_config();
+
+        fs::write(dir.join("old-1.0.0.crate"), b"old contents").unwrap();
+        let mut crates = Vec::new();
+        let mut downloader = MockDownloader::new();
+        for name in &["foo", "bar", "baz"] {
+            let contents = format!("{} contents", name);
+            let c = test_crate(name, "1.0.0", contents.as_bytes());
+            downloader
+                .responses
+                .insert(c.download_url(&config, &settings), contents.into_bytes());
+            crates.push(c);
+        }
+
+        /* Only the crate that isn't part of the run can be removed */
+        let results = fetch_crates(crates,
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_PARTIAL);
+        assert_eq!(results.downloaded.crates, 2);
+        assert!(!dir.join("old-1.0.0.crate").exists());
+        assert!(dir.join("foo-1.0.0.crate").exists());
+        assert!(dir.join("bar-1.0.0.crate").exists());
+        assert!(!dir.join("baz-1.0.0.crate").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_stops_after_max_failures() {
+        let dir = test_dir("max-failures");
+        let settings = test_settings(&dir, &["--max-failures", "1"]);
+        let config = test_config();
+
+        /* None of these can be downloaded */
+        let crates: Vec<Crate> = (0..5)
+            .map(|i| test_crate(&format!("crate{}", i), "1.0.0", b"contents"))
+            .collect();
+        let mut downloader = MockDownloader::new();
+        let results = fetch_crates(crates,
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert!(results.aborted);
+        assert_eq!(results.exit_code(), EXIT_NETWORK);
+        assert_eq!(downloader.requested.len(), 2);
+        assert!(dir.join(".ectype-state").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_verifies_tarballs() {
+        let dir = test_dir("verify-tarball");
+        let settings = test_settings(&dir, &["--verify-tarball"]);
+        let config = test_config();
+
+        let valid = tarball::test_tarball(&[("good-0.1.0/Cargo.toml", b"")]);
+        let good = test_crate("good", "0.1.0", &valid);
+        let bad = test_crate("bad", "0.1.0", b"not a tarball");
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(good.download_url(&config, &settings), valid);
+        downloader
+            .responses
+            .insert(bad.download_url(&config, &settings),
+                    b"not a tarball".to_vec());
+
+        let results = fetch_crates(vec![good, bad],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.malformed.len(), 1);
+        assert_eq!(results.malformed[0].0.name, "bad");
+        /* They still match the index, so they're kept */
+        assert!(dir.join("good-0.1.0.crate").exists());
+        assert!(dir.join("bad-0.1.0.crate").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_verifies_contents() {
+        let dir = test_dir("verify-contents");
+        let settings = test_settings(&dir, &["--verify-contents"]);
+        let config = test_config();
+
+        let manifest = |name: &str| {
+            format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name)
+        };
+        let good_data = tarball::test_tarball(&[("good-0.1.0/Cargo.toml",
+                                                 manifest("good").as_bytes())]);
+        /* The right checksum, since the index was tampered with too */
+        let wrong_data = good_data.clone();
+        let good = test_crate("good", "0.1.0", &good_data);
+        let wrong = test_crate("wrong", "0.1.0", &wrong_data);
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(good.download_url(&config, &settings), good_data);
+        downloader
+            .responses
+            .insert(wrong.download_url(&config, &settings), wrong_data);
+
+        let results = fetch_crates(vec![good, wrong],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_NETWORK);
+        assert_eq!(results.download_failures.len(), 1);
+        assert_eq!(results.download_failures[0].0.name, "wrong");
+        assert!(dir.join("good-0.1.0.crate").exists());
+        assert!(!dir.join("wrong-0.1.0.crate").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_remote_storage() {
+        let dir = test_dir("remote-storage");
+        let settings = test_settings(&dir, &[]);
+        let config = test_config();
+
+        let mut crates = Vec::new();
+        let mut downloader = MockDownloader::new();
+        for name in &["foo", "bar"] {
+            let contents = format!("{} contents", name);
+            let c = test_crate(name, "1.0.0", contents.as_bytes());
+            downloader
+                .responses
+                .insert(c.download_url(&config, &settings), contents.into_bytes());
+            crates.push(c);
+        }
+
+        let storage = MemoryStorage::default();
+        let results = fetch_crates(crates.clone(),
+                                   &config,
+                                   &settings,
+                                   &storage,
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert_eq!(results.downloaded.crates, 2);
+        assert_eq!(storage.files.borrow().len(), 2);
+        assert!(!dir.join("foo-1.0.0.crate").exists());
+
+        /* Stored crates are read back to verify them, and corrupt ones are
+         * downloaded again */
+        storage
+            .files
+            .borrow_mut()
+            .insert("foo-1.0.0.crate".to_string(), b"bit rot".to_vec());
+        let results = fetch_crates(crates.clone(),
+                                   &config,
+                                   &settings,
+                                   &storage,
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert_eq!(results.downloaded.crates, 1);
+        assert_eq!(results.verified.len(), 2);
+        assert_eq!(storage.files.borrow()["foo-1.0.0.crate"], b"foo contents");
+        assert_eq!(downloader.requested.len(), 3);
+
+        /* Storage that can't be reached fails the crates like a download */
+        let storage = MemoryStorage {
+            fail: true,
+            ..MemoryStorage::default()
+        };
+        let results = fetch_crates(crates,
+                                   &config,
+                                   &settings,
+                                   &storage,
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_NETWORK);
+        assert_eq!(results.download_failures.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_resumes_interrupted_run() {
+        let dir = test_dir("resume");
+        let settings = test_settings(&dir, &[]);
+        let config = test_config();
+
+        /* A previous run confirmed foo and baz but was interrupted before
+         * bar, and baz has been removed since */
+        let state = format!("{}\nfoo-1.0.0.crate\nbaz-1.0.0.crate\n",
+                            run_description(&settings));
+        fs::write(dir.join(".ectype-state"), &state).unwrap();
+        fs::write(dir.join("foo-1.0.0.crate"), "changed since").unwrap();
+
+        let mut crates = BTreeSet::new();
+        let mut downloader = MockDownloader::new();
+        for name in &["foo", "bar", "baz"] {
+            let contents = format!("{} contents", name);
+            let c = test_crate(name, "1.0.0", contents.as_bytes());
+            downloader
+                .responses
+                .insert(c.download_url(&config, &settings), contents.into_bytes());
+            crates.insert(c);
+        }
+
+        let results = fetch_crates(crates.iter().cloned(),
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert_eq!(downloader.requested.len(), 2);
+        assert_eq!(fs::read(dir.join("foo-1.0.0.crate")).unwrap(), b"changed since");
+        assert!(dir.join("bar-1.0.0.crate").exists());
+        assert!(dir.join("baz-1.0.0.crate").exists());
+        assert!(!dir.join(".ectype-state").exists());
+
+        /* The state of a run with other options isn't resumed */
+        fs::write(dir.join(".ectype-state"), &state).unwrap();
+        let settings = test_settings(&dir, &["--download-old"]);
+        let results = fetch_crates(crates.iter().cloned(),
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert_eq!(results.checksum_mismatches.len(), 0);
+        assert_eq!(fs::read(dir.join("foo-1.0.0.crate")).unwrap(), b"foo contents");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_quarantines_mismatches() {
+        let dir = test_dir("quarantine");
+        let quarantine = dir.join("quarantine");
+        fs::create_dir(&quarantine).unwrap();
+        let settings = test_settings(&dir,
+                                     &["--quarantine",
+                                       &quarantine.to_string_lossy()]);
+        let config = test_config();
+
+        let corrupt = test_crate("corrupt", "0.1.0", b"corrupt contents");
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(corrupt.download_url(&config, &settings),
+                    b"something else".to_vec());
+        let mut crates = BTreeSet::new();
+        crates.insert(corrupt.clone());
+
+        let results = fetch_crates(crates.iter().cloned(),
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_MISMATCH);
+        assert!(!dir.join("corrupt-0.1.0.crate").exists());
+        assert_eq!(fs::read(quarantine.join("corrupt-0.1.0.badsum")).unwrap(),
+                   b"something else");
+        let info: serde_json::Value =
+            serde_json::from_slice(&fs::read(quarantine.join("corrupt-0.1.0.json"))
+                                        .unwrap())
+                .unwrap();
+        assert_eq!(info["expected"], corrupt.cksum);
+        assert_eq!(info["received"], sha256sum(b"something else"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_checksum_only_new() {
+        let dir = test_dir("checksum-only-new");
+        let settings = test_settings(&dir, &["--checksum-only-new"]);
+        let config = test_config();
+
+        let existing = test_crate("existing", "0.1.0", b"existing contents");
+        let corrupt = test_crate("corrupt", "0.1.0", b"corrupt contents");
+        fs::write(dir.join("existing-0.1.0.crate"), b"bit rot").unwrap();
+
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(corrupt.download_url(&config, &settings),
+                    b"something else".to_vec());
+
+        let results = fetch_crates(vec![existing, corrupt],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.checksum_mismatches.len(), 1);
+        assert_eq!(results.checksum_mismatches[0].0.name, "corrupt");
+        assert_eq!(fs::read(dir.join("existing-0.1.0.crate")).unwrap(),
+                   b"bit rot");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_checksum_algorithm() {
+        let dir = test_dir("checksum-algorithm");
+        let settings = test_settings(&dir, &[]);
+        let config = test_config();
+
+        let c: Crate = serde_json::from_str(&format!(r#"{{"name":"foo","vers":"0.1.0","cksum":"{}","cksum_algo":"sha512","v":3,"yanked":false}}"#,
+                                                     checksum::Algorithm::Sha512
+                                                         .hash(b"contents")))
+            .unwrap();
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(c.download_url(&config, &settings), b"contents".to_vec());
+        let results = fetch_crates(vec![c.clone()],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert!(results.checksum_mismatches.is_empty());
+        assert!(dir.join("foo-0.1.0.crate").exists());
+
+        /* Already downloaded crates are verified with it too */
+        let settings = test_settings(&dir, &["--force-verify"]);
+        let results = fetch_crates(vec![c],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.verified.len(), 1);
+        assert_eq!(downloader.requested.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn head_check_report() {
+        let dir = test_dir("head-check");
+        let settings = test_settings(&dir, &["--head-check"]);
+        let config = test_config();
+
+        let available = test_crate("foo", "0.1.0", b"foo");
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(available.download_url(&config, &settings), b"foo".to_vec());
+        let crates = vec![available, test_crate("bar", "0.1.0", b"bar")];
+        let report = head_check::head_check(crates,
+                                            &config,
+                                            &settings,
+                                            &mut downloader);
+        assert_eq!(report.to_text(),
+                   "unavailable bar-0.1.0.crate (status 404)\n1 available, 1 unavailable, 0 failed\n");
+        assert!(!dir.join("foo-0.1.0.crate").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_skip_larger_than() {
+        let dir = test_dir("skip-larger-than");
+        let settings = test_settings(&dir, &["--skip-larger-than", "5"]);
+        let config = test_config();
+
+        let small = test_crate("small", "0.1.0", b"small");
+        let large = test_crate("large", "0.1.0", b"too large");
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(small.download_url(&config, &settings), b"small".to_vec());
+        downloader
+            .responses
+            .insert(large.download_url(&config, &settings), b"too large".to_vec());
+
+        let mut events = Vec::new();
+        let results = fetch_crates(vec![small, large],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |e| events.push(event_summary(e)));
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert_eq!(results.too_large.len(), 1);
+        assert_eq!(results.too_large[0], (Crate::new("large", "0.1.0"), 9));
+        assert!(dir.join("small-0.1.0.crate").exists());
+        assert!(!dir.join("large-0.1.0.crate").exists());
+        /* The large crate was only asked for its size */
+        assert_eq!(downloader.requested.len(), 3);
+        assert!(events.iter().any(|x| x.starts_with("TooLarge")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_prefer_orig_on_cdn_failure() {
+        let dir = test_dir("prefer-orig");
+        let settings = test_settings(&dir, &["--prefer-orig-on-cdn-failure"]);
+        let config = test_config();
+
+        let on_cdn = test_crate("foo", "0.1.0", b"foo");
+        let missing = test_crate("bar", "0.1.0", b"bar");
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(on_cdn.download_url(&config, &settings), b"foo".to_vec());
+        downloader
+            .responses
+            .insert(missing.registry_url(&config.dl), b"bar".to_vec());
+
+        let mut events = Vec::new();
+        let results = fetch_crates(vec![on_cdn, missing],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |e| events.push(event_summary(e)));
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert_eq!(results.cdn_fallbacks, vec![Crate::new("bar", "0.1.0")]);
+        assert!(dir.join("bar-0.1.0.crate").exists());
+        /* Only the crate missing from the CDN was downloaded from config.json */
+        assert_eq!(downloader.requested.len(), 3);
+        assert_eq!(events.iter().filter(|x| x.starts_with("Fallback")).count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_pauses() {
+        let dir = test_dir("pause");
+        let settings = test_settings(&dir, &[]);
+        let config = test_config();
+
+        let c = test_crate("foo", "0.1.0", b"foo");
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(c.download_url(&config, &settings), b"foo".to_vec());
+
+        fs::write(dir.join(PAUSE_FILE), "").unwrap();
+        let pause_file = dir.join(PAUSE_FILE);
+        let resume = thread::spawn(move || {
+                                       thread::sleep(Duration::from_millis(200));
+                                       fs::remove_file(pause_file).unwrap();
+                                   });
+        let mut events = Vec::new();
+        let results = fetch_crates(vec![c],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |e| events.push(event_summary(e)));
+        resume.join().unwrap();
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert!(events[0].starts_with("Paused"));
+        assert!(events[1].starts_with("Resumed"));
+        assert_eq!(events[2], "started foo");
+
+        /* A pause doesn't outlast --max-duration */
+        let settings = test_settings(&dir, &["--max-duration", "0s"]);
+        fs::write(dir.join(PAUSE_FILE), "").unwrap();
+        let mut events = Vec::new();
+        let results = fetch_crates(vec![test_crate("bar", "0.1.0", b"bar")],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |e| events.push(event_summary(e)));
+        assert!(results.stopped_early);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("Paused"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_header_rules() {
+        let dir = test_dir("header-rules");
+        fs::write(dir.join("rules.toml"),
+                  "[[rule]]\ncrates = \"acme-*\"\nheaders = [\"Authorization: Bearer acme\"]\n")
+            .unwrap();
+        let settings = test_settings(&dir,
+                                     &["--header-rules",
+                                       &dir.join("rules.toml").to_string_lossy()]);
+        let config = test_config();
+
+        let mut downloader = MockDownloader::new();
+        let crates = vec![test_crate("acme-util", "0.1.0", b"acme-util"),
+                          test_crate("serde", "0.1.0", b"serde")];
+        for c in &crates {
+            downloader
+                .responses
+                .insert(c.download_url(&config, &settings), c.name.as_bytes().to_vec());
+        }
+        let results = fetch_crates(crates,
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert_eq!(downloader.headers,
+                   vec![vec!["Authorization: Bearer acme".to_string()], vec![]]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_provenance() {
+        let dir = test_dir("provenance");
+        let settings = test_settings(&dir, &["--provenance"]);
+        let config = test_config();
+
+        let c = test_crate("foo", "0.1.0", b"foo");
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(c.download_url(&config, &settings), b"foo".to_vec());
+        let results = fetch_crates(vec![c.clone()],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+
+        let provenance: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("provenance/foo/0.1.0.json"))
+                                     .unwrap())
+                .unwrap();
+        assert_eq!(provenance["url"], c.download_url(&config, &settings));
+        assert_eq!(provenance["status"], 200);
+        assert_eq!(provenance["server"], "mock");
+        assert_eq!(provenance["sha256"], sha256sum(b"foo"));
+        assert!(provenance["timestamp"].as_f64().unwrap() > 0.0);
+
+        /* Crates that are already in the archive weren't downloaded now */
+        fs::remove_dir_all(dir.join("provenance")).unwrap();
+        fetch_crates(vec![c],
+                     &config,
+                     &settings,
+                     &LocalStorage::new(&settings),
+                     &mut downloader,
+                     &mut |_| ());
+        assert!(!dir.join("provenance").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_etags() {
+        let dir = test_dir("etags");
+        let settings = test_settings(&dir, &["--etags"]);
+        let config = test_config();
+
+        let c = test_crate("foo", "0.1.0", b"old contents");
+        let url = c.download_url(&config, &settings);
+        let mut downloader = MockDownloader::new();
+        downloader.responses.insert(url.clone(), b"old contents".to_vec());
+        downloader.etags.insert(url.clone(), "\"1\"".to_string());
+        fetch_crates(vec![c.clone()],
+                     &config,
+                     &settings,
+                     &LocalStorage::new(&settings),
+                     &mut downloader,
+                     &mut |_| ());
+        assert_eq!(fs::read_to_string(dir.join(".ectype-etags")).unwrap(),
+                   "foo-0.1.0.crate \"1\"\n");
+
+        /* Unchanged on the server */
+        let results = fetch_crates(vec![c],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.downloaded.crates, 0);
+        assert_eq!(downloader.requested, vec![url.clone(), url.clone()]);
+
+        /* Changed on the server, and in the index */
+        let c = test_crate("foo", "0.1.0", b"new contents");
+        downloader.responses.insert(url.clone(), b"new contents".to_vec());
+        downloader.etags.insert(url.clone(), "\"2\"".to_string());
+        let results = fetch_crates(vec![c],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.downloaded.crates, 1);
+        assert_eq!(fs::read(dir.join("foo-0.1.0.crate")).unwrap(),
+                   b"new contents");
+        assert_eq!(fs::read_to_string(dir.join(".ectype-etags")).unwrap(),
+                   "foo-0.1.0.crate \"2\"\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn promote_staged() {
+        let dir = test_dir("promote");
+        let staging = dir.join("staging");
+        let archive = dir.join("archive");
+        fs::create_dir_all(&staging).unwrap();
+        fs::create_dir_all(&archive).unwrap();
+        fs::write(staging.join("foo-0.1.0.crate"), b"foo").unwrap();
+        fs::write(staging.join("bar-0.1.0.crate"), b"bar").unwrap();
+        /* Left behind by an interrupted run */
+        fs::write(archive.join("bar-0.1.0.crate.part"), b"ba").unwrap();
+
+        promote_staged_crates(&staging, &archive);
+        assert_eq!(fs::read(archive.join("foo-0.1.0.crate")).unwrap(), b"foo");
+        assert_eq!(fs::read(archive.join("bar-0.1.0.crate")).unwrap(), b"bar");
+        assert!(!archive.join("bar-0.1.0.crate.part").exists());
+        assert_eq!(fs::read_dir(&staging).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn audit_against_index() {
+        let dir = test_dir("audit");
+        let staging = dir.join("staging");
+        fs::create_dir_all(&staging).unwrap();
+        let settings = test_settings(&dir,
+                                     &["--verify-against-index",
+                                       "--staging",
+                                       &staging.to_string_lossy()]);
+        fs::write(dir.join("good-0.1.0.crate"), b"good contents").unwrap();
+        fs::write(dir.join("bad-0.1.0.crate"), b"bit rot").unwrap();
+        fs::write(staging.join("staged-0.1.0.crate"), b"staged contents").unwrap();
+        let crates = vec![test_crate("good", "0.1.0", b"good contents"),
+                          test_crate("bad", "0.1.0", b"bad contents"),
+                          test_crate("staged", "0.1.0", b"staged contents"),
+                          test_crate("missing", "0.1.0", b"missing contents")];
+
+        let audit = audit::audit_archive(crates, &settings);
+        assert_eq!(audit.matching, 2);
+        assert_eq!(audit.mismatched, vec!["bad-0.1.0.crate"]);
+        assert_eq!(audit.missing, vec!["missing-0.1.0.crate"]);
+        assert_eq!(audit.to_text(),
+                   "mismatched bad-0.1.0.crate\nmissing missing-0.1.0.crate\n2 matching, 1 mismatched, 1 missing\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_report() {
+        let dir = test_dir("missing-report");
+        let staging = dir.join("staging");
+        fs::create_dir_all(&staging).unwrap();
+        let settings = test_settings(&dir,
+                                     &["--only-missing-report",
+                                       "--staging",
+                                       &staging.to_string_lossy()]);
+        fs::write(dir.join("present-0.1.0.crate"), b"bit rot").unwrap();
+        fs::write(staging.join("staged-0.1.0.crate"), b"staged").unwrap();
+        let crates = vec![Crate::new("present", "0.1.0"),
+                          Crate::new("staged", "0.1.0"),
+                          Crate::new("missing", "0.1.0")];
+
+        let report = audit::missing_crates(crates, &settings);
+        assert_eq!(report.to_text(),
+                   "missing-0.1.0.crate\n2 present, 1 missing\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_records_mismatches_and_failures() {
+        let dir = test_dir("mismatches");
+        let settings = test_settings(&dir, &[]);
+        let config = test_config();
+
+        let good = test_crate("good", "0.1.0", b"good contents");
+        let corrupt = test_crate("corrupt", "0.1.0", b"corrupt contents");
+        let missing = test_crate("missing", "0.1.0", b"missing contents");
+
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(good.download_url(&config, &settings),
+                    b"good contents".to_vec());
+        downloader
+            .responses
+            .insert(corrupt.download_url(&config, &settings),
+                    b"something else".to_vec());
+
+        let mut crates = BTreeSet::new();
+        crates.insert(good);
+        crates.insert(corrupt);
+        crates.insert(missing);
+
+        let results = fetch_crates(crates.iter().cloned(),
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.checksum_mismatches.len(), 1);
+        assert_eq!(results.checksum_mismatches[0].0.name, "corrupt");
+        assert_eq!(results.checksum_mismatches[0].1,
+                   sha256sum(b"something else"));
+        assert_eq!(results.download_failures.len(), 1);
+        assert_eq!(results.download_failures[0].0.name, "missing");
+        assert_eq!(results.exit_code(), EXIT_NETWORK);
+
+        assert!(dir.join("good-0.1.0.crate").exists());
+        assert!(!dir.join("corrupt-0.1.0.crate").exists());
+        assert!(!dir.join("quarantine").exists());
+        assert!(!dir.join("missing-0.1.0.crate").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_places_crates_in_tiers() {
+        let dir = test_dir("tiers");
+        let ssd = dir.join("ssd");
+        fs::create_dir(&ssd).unwrap();
+        let settings = test_settings(&dir,
+                                     &["--tier",
+                                       &format!("{}=serde*", ssd.to_string_lossy())]);
+        let config = test_config();
+
+        let serde = test_crate("serde", "1.0.0", b"serde contents");
+        let rand = test_crate("rand", "0.1.0", b"rand contents");
+        /* Crates already in another tier aren't downloaded again */
+        let moved = test_crate("serde_moved", "0.1.0", b"moved contents");
+        fs::write(dir.join("serde_moved-0.1.0.crate"), b"moved contents").unwrap();
+
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(serde.download_url(&config, &settings),
+                    b"serde contents".to_vec());
+        downloader
+            .responses
+            .insert(rand.download_url(&config, &settings),
+                    b"rand contents".to_vec());
+        let mut crates = BTreeSet::new();
+        crates.insert(serde);
+        crates.insert(rand);
+        crates.insert(moved);
+
+        let results = fetch_crates(crates.iter().cloned(),
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_OK);
+        assert!(ssd.join("serde-1.0.0.crate").exists());
+        assert!(!dir.join("serde-1.0.0.crate").exists());
+        assert!(dir.join("rand-0.1.0.crate").exists());
+        assert!(dir.join("serde_moved-0.1.0.crate").exists());
+        assert!(!ssd.join("serde_moved-0.1.0.crate").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_crates_repairs_corrupt_files() {
+        for args in &[&[] as &[&str], &["--verify-jobs", "2"]] {
+            let dir = test_dir("repair");
+            let settings = test_settings(&dir, args);
+            let config = test_config();
+
+            let c = test_crate("rotten", "0.1.0", b"good contents");
+            fs::write(dir.join("rotten-0.1.0.crate"), b"bit rot").unwrap();
+            let mut downloader = MockDownloader::new();
+            downloader
+                .responses
+                .insert(c.download_url(&config, &settings),
+                        b"good contents".to_vec());
+
+            let results = fetch_crates(vec![c],
+                                       &config,
+                                       &settings,
+                                       &LocalStorage::new(&settings),
+                                       &mut downloader,
+                                       &mut |_| ());
+            assert_eq!(results.exit_code(), EXIT_OK);
+            assert_eq!(fs::read(dir.join("rotten-0.1.0.crate")).unwrap(),
+                       b"good contents");
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn fetch_crates_reverifies_old_files() {
+        let dir = test_dir("reverify");
+        let config = test_config();
+        let c = test_crate("old", "0.1.0", b"good contents");
+        let path = dir.join("old-0.1.0.crate");
+        fs::write(&path, b"good contents").unwrap();
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(c.download_url(&config, &test_settings(&dir, &[])),
+                    b"good contents".to_vec());
+
+        let settings = test_settings(&dir, &[]);
+        fetch_crates(vec![c.clone()],
+                     &config,
+                     &settings,
+                     &LocalStorage::new(&settings),
+                     &mut downloader,
+                     &mut |_| ());
+
+        /* Same size and mtime, so only a new hash notices the change */
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, b"evil contents").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        fetch_crates(vec![c.clone()],
+                     &config,
+                     &settings,
+                     &LocalStorage::new(&settings),
+                     &mut downloader,
+                     &mut |_| ());
+        assert_eq!(fs::read(&path).unwrap(), b"evil contents");
+
+        let settings = test_settings(&dir, &["--reverify-after", "0s"]);
+        fetch_crates(vec![c],
+                     &config,
+                     &settings,
+                     &LocalStorage::new(&settings),
+                     &mut downloader,
+                     &mut |_| ());
+        assert_eq!(fs::read(&path).unwrap(), b"good contents");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fetch_crates_stops_without_free_space() {
+        let dir = test_dir("free-space");
+        let settings = test_settings(&dir, &["--min-free-space", "1000000T"]);
+        let config = test_config();
+
+        let c = test_crate("foo", "0.1.0", b"foo contents");
+        let mut downloader = MockDownloader::new();
+        downloader
+            .responses
+            .insert(c.download_url(&config, &settings),
+                    b"foo contents".to_vec());
+
+        let results = fetch_crates(vec![c],
+                                   &config,
+                                   &settings,
+                                   &LocalStorage::new(&settings),
+                                   &mut downloader,
+                                   &mut |_| ());
+        assert_eq!(results.exit_code(), EXIT_PARTIAL);
+        assert!(!dir.join("foo-0.1.0.crate").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
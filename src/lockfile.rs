@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use toml;
+
+#[derive(Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<Package>,
+    /// Where version 1 lockfiles keep the checksums
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+    source: Option<String>,
+    checksum: Option<String>,
+}
+
+/// The name and version of every package in the Cargo.lock that comes from a
+/// registry, along with its checksum if the lockfile has one
+pub fn read_lockfile(path: &Path) -> BTreeMap<(String, String), Option<String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => error!("Error reading {}: {}", path.to_string_lossy(), e),
+    };
+    parse_lockfile(&contents).unwrap_or_else(|e| {
+        error!("Error parsing {}: {}", path.to_string_lossy(), e)
+    })
+}
+
+fn parse_lockfile(contents: &str)
+                  -> Result<BTreeMap<(String, String), Option<String>>, String> {
+    let lockfile: Lockfile = toml::from_str(contents).map_err(|e| e.to_string())?;
+
+    let metadata = lockfile.metadata;
+    let mut ret = BTreeMap::new();
+    for package in lockfile.package {
+        /* Path and git dependencies aren't in the registry */
+        let source = match package.source {
+            Some(ref x) if x.starts_with("registry+") ||
+                           x.starts_with("sparse+") => x,
+            _ => continue,
+        };
+        let checksum = package.checksum.clone().or_else(|| {
+            let key = format!("checksum {} {} ({})",
+                              package.name,
+                              package.version,
+                              source);
+            metadata.get(&key).cloned()
+        });
+        ret.insert((package.name, package.version), checksum);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockfile_versions() {
+        let lockfile = parse_lockfile(r#"
+version = 3
+
+[[package]]
+name = "myproject"
+version = "0.1.0"
+dependencies = ["serde"]
+
+[[package]]
+name = "serde"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "abc"
+
+[[package]]
+name = "forked"
+version = "0.1.0"
+source = "git+https://example.com/forked#0123"
+"#)
+                .unwrap();
+        let expected: BTreeMap<_, _> =
+            vec![(("serde".to_string(), "1.0.0".to_string()),
+                  Some("abc".to_string()))]
+                .into_iter()
+                .collect();
+        assert_eq!(lockfile, expected);
+
+        let lockfile = parse_lockfile(r#"
+[[package]]
+name = "serde"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[metadata]
+"checksum serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)" = "def"
+"#)
+                .unwrap();
+        assert_eq!(lockfile[&("serde".to_string(), "1.0.0".to_string())],
+                   Some("def".to_string()));
+    }
+}
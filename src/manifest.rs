@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use tiers::{self, Tier};
+use write_atomically;
+
+/// The name of the manifest, placed in the archive directory
+const MANIFEST_FILE: &str = "SHA256SUMS";
+
+/// Add the given verified crate files to the SHA256SUMS file in the archive,
+/// in the format of `sha256sum`, so that the archive can be checked with
+/// `sha256sum -c SHA256SUMS` from the archive directory.
+///
+/// Entries from earlier runs are kept as long as their file still exists,
+/// since crates that weren't verified in this run aren't in the list. The
+/// file is sorted by path, so that it doesn't change if the archive doesn't,
+/// unless ordered is given for an explicit --sort-order. Then the crates come
+/// in the order they were verified in, followed by the ones from earlier runs
+/// by path.
+pub fn update_manifest(archive: &Path,
+                       tiers: &[Tier],
+                       verified: &[(String, String)],
+                       ordered: bool) {
+    let path = archive.join(MANIFEST_FILE);
+    let mut entries = BTreeMap::new();
+    if let Ok(contents) = fs::read_to_string(&path) {
+        for line in contents.lines() {
+            let mut fields = line.splitn(2, "  ");
+            match (fields.next(), fields.next()) {
+                (Some(sha256), Some(file)) => {
+                    entries.insert(file.to_string(), sha256.to_string());
+                },
+                _ => {
+                    println!("Warning: Ignoring invalid line in {}: {}",
+                             path.to_string_lossy(),
+                             line)
+                },
+            }
+        }
+    }
+
+    let mut order = Vec::new();
+    for (filename, sha256) in verified {
+        let file = match tiers::find_existing(tiers, archive, filename) {
+            Some(x) => x,
+            None => continue,
+        };
+        /* Crates in tiers outside the archive get their full path */
+        let file = file.strip_prefix(archive).unwrap_or(&file);
+        let file = file.to_string_lossy().into_owned();
+        entries.insert(file.clone(), sha256.clone());
+        if ordered {
+            order.push(file);
+        }
+    }
+    entries.retain(|file, _| archive.join(file).is_file());
+
+    let mut tmp = String::new();
+    for file in &order {
+        if let Some(sha256) = entries.remove(file) {
+            tmp.push_str(&format!("{}  {}\n", sha256, file));
+        }
+    }
+    for (file, sha256) in &entries {
+        tmp.push_str(&format!("{}  {}\n", sha256, file));
+    }
+
+    if let Err(e) = write_atomically(&path, tmp) {
+        error!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tests::test_dir;
+
+    #[test]
+    fn keeps_existing_entries() {
+        let dir = test_dir("manifest");
+        fs::write(dir.join("b-0.1.0.crate"), "b").unwrap();
+        fs::write(dir.join("a-0.1.0.crate"), "a").unwrap();
+        fs::write(dir.join(MANIFEST_FILE),
+                  "1111  b-0.1.0.crate\n2222  deleted-0.1.0.crate\n")
+            .unwrap();
+
+        let verified = vec![("a-0.1.0.crate".to_string(), "3333".to_string()),
+                            ("staged-0.1.0.crate".to_string(), "4444".to_string())];
+        update_manifest(&dir, &[], &verified, false);
+        assert_eq!(fs::read_to_string(dir.join(MANIFEST_FILE)).unwrap(),
+                   "3333  a-0.1.0.crate\n1111  b-0.1.0.crate\n");
+
+        /* In an explicit order, the verified crates come first */
+        let verified = vec![("b-0.1.0.crate".to_string(), "5555".to_string())];
+        update_manifest(&dir, &[], &verified, true);
+        assert_eq!(fs::read_to_string(dir.join(MANIFEST_FILE)).unwrap(),
+                   "5555  b-0.1.0.crate\n3333  a-0.1.0.crate\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+use std::time::Duration;
+
+use write_atomically;
+
+/// The numbers written by --metrics-file at the end of a run. The default is
+/// the record of a failed run, written when the run starts.
+#[derive(Debug, Default)]
+pub struct RunMetrics {
+    /// The crates from the index that the run went through
+    pub crates_total: u64,
+    pub crates_downloaded: u64,
+    pub crates_mismatched: u64,
+    pub bytes_downloaded: u64,
+    pub run_duration: Duration,
+    pub success: bool,
+}
+impl RunMetrics {
+    /// The metrics in the Prometheus text format, as read by the textfile
+    /// collector of node_exporter
+    pub fn to_text(&self) -> String {
+        let metrics = [("crates_total",
+                        "gauge",
+                        "Crates from the index that the last run went through",
+                        self.crates_total.to_string()),
+                       ("crates_downloaded",
+                        "gauge",
+                        "Crates downloaded by the last run",
+                        self.crates_downloaded.to_string()),
+                       ("crates_mismatched",
+                        "gauge",
+                        "Crates whose download didn't match the checksum in the index in the last run",
+                        self.crates_mismatched.to_string()),
+                       ("bytes_downloaded",
+                        "gauge",
+                        "Bytes of crates downloaded by the last run",
+                        self.bytes_downloaded.to_string()),
+                       ("run_duration_seconds",
+                        "gauge",
+                        "How long the last run took",
+                        format!("{:.3}", self.run_duration.as_secs_f64())),
+                       ("last_run_success",
+                        "gauge",
+                        "1 if the last run mirrored every crate without problems, 0 otherwise",
+                        (self.success as u8).to_string())];
+        let mut ret = String::new();
+        for &(name, kind, help, ref value) in &metrics {
+            ret.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                                  name,
+                                  help,
+                                  name,
+                                  kind,
+                                  name,
+                                  value));
+        }
+        ret
+    }
+
+    /// Replace the file at the given path with the metrics, through a
+    /// temporary file so that the collector never reads half of it
+    pub fn write(&self, path: &Path) {
+        /* The textfile collector only reads files ending in .prom, so the
+         * temporary file is ignored */
+        if let Err(e) = write_atomically(path, self.to_text()) {
+            error!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_text() {
+        let metrics = RunMetrics {
+            crates_total: 10,
+            crates_downloaded: 3,
+            bytes_downloaded: 4096,
+            run_duration: Duration::from_millis(1500),
+            success: true,
+            ..RunMetrics::default()
+        };
+        let text = metrics.to_text();
+        assert!(text.starts_with("# HELP crates_total Crates from the index that the last run went through\n# TYPE crates_total gauge\ncrates_total 10\n"));
+        assert!(text.contains("\ncrates_mismatched 0\n"));
+        assert!(text.contains("\nbytes_downloaded 4096\n"));
+        assert!(text.contains("\nrun_duration_seconds 1.500\n"));
+        assert!(text.ends_with("\nlast_run_success 1\n"));
+
+        let failed = RunMetrics::default().to_text();
+        assert!(failed.contains("\ncrates_downloaded 0\n"));
+        assert!(failed.ends_with("\nlast_run_success 0\n"));
+    }
+}
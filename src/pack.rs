@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json;
+use tar::{Archive, Builder, EntryType, Header};
+
+use checksum::Algorithm;
+use storage::set_mode;
+use tiers::{self, Tier};
+use write_atomically_with;
+
+/// The name of the manifest in the tar, which always comes first so that
+/// the crates can be checked as they are unpacked
+const MANIFEST_ENTRY: &str = "manifest.json";
+
+/// The directory the crates are in inside the tar
+const CRATES_DIR: &str = "crates";
+
+/// A crate file in the manifest of a packed archive
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct PackedCrate {
+    cksum: String,
+    cksum_algo: Algorithm,
+}
+
+/// Whether the tar is compressed, which is decided by its extension
+fn gzipped(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".gz") || name.ends_with(".tgz")
+}
+
+/// Write the given verified crate files into a single tar at output, along
+/// with a manifest of their checksums. The crates are under crates/, and
+/// the tar is gzipped if output ends with .gz or .tgz.
+///
+/// Returns the number of crates that were packed.
+pub fn pack_crates(output: &Path,
+                   archive: &Path,
+                   tiers: &[Tier],
+                   verified: &[(String, Algorithm, String)])
+                   -> usize {
+    let mut manifest = BTreeMap::new();
+    let mut files = Vec::new();
+    for &(ref filename, algorithm, ref cksum) in verified {
+        if let Some(path) = tiers::find_existing(tiers, archive, filename) {
+            manifest.insert(filename.clone(),
+                            PackedCrate {
+                                cksum: cksum.clone(),
+                                cksum_algo: algorithm,
+                            });
+            files.push((filename, path));
+        }
+    }
+    files.sort();
+    files.dedup();
+
+    let written = write_atomically_with(output, |partfile| {
+        write_tar(partfile, gzipped(output), &manifest, &files)
+    });
+    if let Err(e) = written {
+        error!("{}", e);
+    }
+    files.len()
+}
+
+/// Write the tar to path, finishing the gzip stream if it is compressed
+fn write_tar(path: &Path,
+             gzip: bool,
+             manifest: &BTreeMap<String, PackedCrate>,
+             files: &[(&String, PathBuf)])
+             -> Result<(), String> {
+    let write_error = |e: io::Error| {
+        format!("Error writing to {}: {}", path.to_string_lossy(), e)
+    };
+    let f = BufWriter::new(File::create(path).map_err(write_error)?);
+    let mut f = if gzip {
+        let mut builder = Builder::new(GzEncoder::new(f, Compression::default()));
+        append_crates(&mut builder, manifest, files)?;
+        builder
+            .into_inner()
+            .and_then(|x| x.finish())
+            .map_err(write_error)?
+    } else {
+        let mut builder = Builder::new(f);
+        append_crates(&mut builder, manifest, files)?;
+        builder.into_inner().map_err(write_error)?
+    };
+    f.flush().map_err(write_error)
+}
+
+/// Add the manifest and then the crate files to the tar
+fn append_crates<W: Write>(builder: &mut Builder<W>,
+                           manifest: &BTreeMap<String, PackedCrate>,
+                           files: &[(&String, PathBuf)])
+                           -> Result<(), String> {
+    let write_error = |e: io::Error| format!("Error writing tar: {}", e);
+    let manifest = serde_json::to_vec_pretty(manifest)
+        .expect("Error encoding pack manifest");
+    append(builder, MANIFEST_ENTRY, &manifest).map_err(write_error)?;
+
+    let mut buf = Vec::new();
+    for &(filename, ref file) in files {
+        buf.clear();
+        if let Err(e) = File::open(file).and_then(|mut x| x.read_to_end(&mut buf)) {
+            return Err(format!("Error reading {}: {}", file.to_string_lossy(), e));
+        }
+        append(builder, &format!("{}/{}", CRATES_DIR, filename), &buf)
+            .map_err(write_error)?;
+    }
+    Ok(())
+}
+
+/// Add a file to the tar, with a fixed mode and time so that packing the
+/// same crates always gives the same tar
+fn append<W: Write>(builder: &mut Builder<W>,
+                    path: &str,
+                    data: &[u8])
+                    -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    builder.append_data(&mut header, path, data)
+}
+
+/// Restore the crates in a tar written by pack_crates into the archive
+/// directory, checking every crate against the manifest first. Crates that
+/// are already in the archive are replaced, and the restored files get the
+/// permissions given by mode.
+///
+/// Returns the number of crates that were unpacked.
+pub fn unpack_crates(input: &Path, archive: &Path, mode: u32) -> Result<usize, String> {
+    let input_name = input.to_string_lossy();
+    let f = match File::open(input) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Error opening {}: {}", input_name, e)),
+    };
+    let f: Box<dyn Read> = if gzipped(input) {
+        Box::new(GzDecoder::new(f))
+    } else {
+        Box::new(f)
+    };
+    let mut tar = Archive::new(f);
+    let entries = match tar.entries() {
+        Ok(x) => x,
+        Err(e) => return Err(format!("{} is not a valid tar: {}", input_name, e)),
+    };
+
+    let mut manifest: Option<BTreeMap<String, PackedCrate>> = None;
+    let mut buf = Vec::new();
+    let mut count = 0;
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Invalid entry in {}: {}", input_name, e)),
+        };
+        let path = match entry.path() {
+            Ok(x) => x.to_string_lossy().into_owned(),
+            Err(e) => {
+                return Err(format!("Invalid entry path in {}: {}", input_name, e))
+            },
+        };
+        buf.clear();
+        if let Err(e) = entry.read_to_end(&mut buf) {
+            return Err(format!("Error reading {} from {}: {}", path, input_name, e));
+        }
+
+        let manifest = match manifest {
+            Some(ref x) => x,
+            None if path == MANIFEST_ENTRY => {
+                match serde_json::from_slice(&buf) {
+                    Ok(x) => manifest = Some(x),
+                    Err(e) => {
+                        return Err(format!("Invalid manifest in {}: {}", input_name, e))
+                    },
+                }
+                continue;
+            },
+            None => return Err(format!("{} doesn't start with a manifest", input_name)),
+        };
+        /* Only plain file names are accepted, so that nothing is written
+         * outside the archive */
+        let filename = match path.strip_prefix(&format!("{}/", CRATES_DIR)) {
+            Some(x) if !x.contains('/') && !x.starts_with('.') &&
+                       x.ends_with(".crate") => x,
+            _ => {
+                println!("Warning: Ignoring {} in {}", path, input_name);
+                continue;
+            },
+        };
+        let expected = match manifest.get(filename) {
+            Some(x) => x,
+            None => {
+                println!("Warning: Ignoring {} in {}, which isn't in its manifest",
+                         path,
+                         input_name);
+                continue;
+            },
+        };
+        let hash = expected.cksum_algo.hash(&buf);
+        if hash != expected.cksum {
+            return Err(format!("Checksum mismatch in {} in {}. Expected {} but it has {}",
+                               path,
+                               input_name,
+                               expected.cksum,
+                               hash));
+        }
+
+        let cratefile = archive.join(filename);
+        let partfile = archive.join(format!("{}.part", filename));
+        if let Err(e) = fs::write(&partfile, &buf).and_then(|()| set_mode(&partfile, mode)) {
+            return Err(format!("Error writing to {}: {}", partfile.to_string_lossy(), e));
+        }
+        if let Err(e) = fs::rename(&partfile, &cratefile) {
+            return Err(format!("Error renaming {} to {}: {}",
+                               partfile.to_string_lossy(),
+                               cratefile.to_string_lossy(),
+                               e));
+        }
+        count += 1;
+    }
+
+    if manifest.is_none() {
+        return Err(format!("{} doesn't start with a manifest", input_name));
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tests::test_dir;
+
+    #[test]
+    fn pack_and_unpack() {
+        let dir = test_dir("pack");
+        let archive = dir.join("archive");
+        let restored = dir.join("restored");
+        fs::create_dir_all(&archive).unwrap();
+        fs::create_dir_all(&restored).unwrap();
+        fs::write(archive.join("b-0.1.0.crate"), "b").unwrap();
+        fs::write(archive.join("a-0.1.0.crate"), "a").unwrap();
+
+        let entry = |name: &str, contents: &[u8]| {
+            (name.to_string(), Algorithm::Sha256, Algorithm::Sha256.hash(contents))
+        };
+        let verified = vec![entry("b-0.1.0.crate", b"b"),
+                            entry("a-0.1.0.crate", b"a"),
+                            entry("gone-0.1.0.crate", b"c")];
+        for name in &["mirror.tar", "mirror.tar.gz"] {
+            let output = dir.join(name);
+            assert_eq!(pack_crates(&output, &archive, &[], &verified), 2);
+            let first = fs::read(&output).unwrap();
+            pack_crates(&output, &archive, &[], &verified);
+            assert_eq!(fs::read(&output).unwrap(), first);
+
+            assert_eq!(unpack_crates(&output, &restored, 0o644), Ok(2));
+            assert_eq!(fs::read(restored.join("a-0.1.0.crate")).unwrap(), b"a");
+            assert_eq!(fs::read(restored.join("b-0.1.0.crate")).unwrap(), b"b");
+        }
+
+        /* Crates that don't match the manifest aren't unpacked */
+        let wrong = vec![entry("a-0.1.0.crate", b"x")];
+        let output = dir.join("wrong.tar");
+        pack_crates(&output, &archive, &[], &wrong);
+        assert!(unpack_crates(&output, &restored, 0o644)
+                    .unwrap_err()
+                    .starts_with("Checksum mismatch"));
+        assert_eq!(fs::read(restored.join("a-0.1.0.crate")).unwrap(), b"a");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::BTreeSet;
+
+use is_url;
+
+/// A registry mirrored into its own directory of the archive, given to
+/// --registry as `NAME=INDEX-URL`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Registry {
+    /// The directory of the archive the registry is mirrored into, which is
+    /// also its name in the output
+    pub name: String,
+    pub index_url: String,
+}
+impl Registry {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (name, index_url) = match s.find('=') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return Err(format!("{}, expected NAME=INDEX-URL", s)),
+        };
+        /* The name becomes a directory next to the other registries */
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(format!("{}, the name must be usable as a directory name", s));
+        }
+        if !is_url(index_url) {
+            return Err(format!("{}, expected NAME=INDEX-URL", s));
+        }
+        Ok(Registry {
+               name: name.to_string(),
+               index_url: index_url.to_string(),
+           })
+    }
+}
+
+/// Check that no two registries would be mirrored into the same directory
+pub fn check_unique(registries: &[Registry]) -> Result<(), String> {
+    let mut names = BTreeSet::new();
+    for registry in registries {
+        if !names.insert(registry.name.to_ascii_lowercase()) {
+            return Err(format!("the name {} is given more than once", registry.name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let registry = Registry::parse("private=https://git.example.com/index=1").unwrap();
+        assert_eq!(registry.name, "private");
+        assert_eq!(registry.index_url, "https://git.example.com/index=1");
+        assert!(Registry::parse("https://github.com/rust-lang/crates.io-index").is_err());
+        assert!(Registry::parse("=https://git.example.com/index").is_err());
+        assert!(Registry::parse("../up=https://git.example.com/index").is_err());
+        assert!(Registry::parse(".hidden=https://git.example.com/index").is_err());
+        assert!(Registry::parse("private=index").is_err());
+
+        let registries = vec![Registry::parse("a=https://a.example.com/index").unwrap(),
+                              Registry::parse("A=https://b.example.com/index").unwrap()];
+        assert!(check_unique(&registries[..1]).is_ok());
+        assert!(check_unique(&registries).is_err());
+    }
+}
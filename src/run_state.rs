@@ -0,0 +1,123 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the state file of an in-progress run
+const STATE_FILE: &str = ".ectype-state";
+/// The name of the marker written when a run completes
+const COMPLETE_FILE: &str = ".ectype-complete";
+
+/// Tracks which crates have been confirmed to be in the archive during the
+/// current run, so that if the run is interrupted, the next run can skip
+/// them instead of checking the whole archive again.
+///
+/// The state file starts with a line describing the run, and then has the
+/// filename of one confirmed crate per line. It is removed once the run
+/// completes.
+pub struct RunState {
+    path: PathBuf,
+    done: BTreeSet<String>,
+    file: File,
+}
+impl RunState {
+    /// Open the state file in the given archive directory, resuming the
+    /// previous run if it was interrupted. run describes the index and the
+    /// crates chosen from it, and has to be one line.
+    pub fn open(archive: &Path, run: &str) -> Self {
+        let path = archive.join(STATE_FILE);
+        let mut done = BTreeSet::new();
+        let mut resumed = false;
+
+        if let Ok(f) = File::open(&path) {
+            let mut lines = BufReader::new(f).lines();
+            /* A run of another index commit, or of other crates, didn't
+             * confirm what this one needs */
+            if lines.next().and_then(|x| x.ok()).as_deref() == Some(run) {
+                for line in lines {
+                    match line {
+                        Ok(x) => {
+                            done.insert(x);
+                        },
+                        /* Could be a partially written line from a crash,
+                         * so we just start over from there */
+                        Err(_) => break,
+                    }
+                }
+                println!("Resuming interrupted run, skipping {} crates that were already confirmed",
+                         done.len());
+                resumed = true;
+            } else {
+                println!("Not resuming the interrupted run, it was of another index commit or with other options");
+            }
+        }
+
+        /* The archive isn't complete until this run finishes */
+        let complete = archive.join(COMPLETE_FILE);
+        if complete.exists() {
+            match fs::remove_file(&complete) {
+                Ok(()) => (),
+                Err(e) => {
+                    error!("Error removing {}: {}", complete.to_string_lossy(), e)
+                },
+            }
+        }
+
+        let opened = if resumed {
+            OpenOptions::new().append(true).open(&path)
+        } else {
+            File::create(&path).and_then(|mut f| writeln!(f, "{}", run).map(|()| f))
+        };
+        let file = match opened {
+            Ok(f) => f,
+            Err(e) => error!("Error opening {}: {}", path.to_string_lossy(), e),
+        };
+
+        RunState {
+            path,
+            done,
+            file,
+        }
+    }
+
+    /// Whether the given crate file was confirmed earlier in this run
+    pub fn is_done(&self, filename: &str) -> bool {
+        self.done.contains(filename)
+    }
+
+    /// Record that the given crate file is in the archive and verified
+    pub fn record(&mut self, filename: &str) {
+        match writeln!(self.file, "{}", filename) {
+            Ok(()) => (),
+            Err(e) => {
+                error!("Error writing to {}: {}", self.path.to_string_lossy(), e)
+            },
+        }
+        self.done.insert(filename.to_string());
+    }
+
+    /// Remove the state file once all crates have been processed
+    pub fn finish(self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => (),
+            Err(e) => {
+                error!("Error removing {}: {}", self.path.to_string_lossy(), e)
+            },
+        }
+    }
+}
+
+/// Mark the archive as completely mirrored from the given index commit
+pub fn write_complete_marker(archive: &Path, index_commit: &str) {
+    let path = archive.join(COMPLETE_FILE);
+    let mut f = match File::create(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Error creating file {}: {}", path.to_string_lossy(), e)
+        },
+    };
+    match writeln!(f, "{}", index_commit) {
+        Ok(()) => (),
+        Err(e) => error!("Error writing to {}: {}", path.to_string_lossy(), e),
+    }
+}
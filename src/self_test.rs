@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use checksum::Algorithm;
+use download::{Downloader, Head};
+use storage::LocalStorage;
+use {fetch_crates, index_path, options, read_crate_index, ConfigJsonFile, Settings, EXIT_OK};
+
+/// Serves the crate files of the fixture registry from memory
+struct FixtureDownloader {
+    files: BTreeMap<String, Vec<u8>>,
+    requests: usize,
+}
+impl Downloader for FixtureDownloader {
+    fn download(&mut self, url: &str, output: &mut Vec<u8>) -> Result<(), String> {
+        self.requests += 1;
+        match self.files.get(url) {
+            Some(x) => {
+                output.extend_from_slice(x);
+                Ok(())
+            },
+            None => Err(format!("server returned status 404 for {}", url)),
+        }
+    }
+
+    fn head(&mut self, url: &str) -> Result<Head, String> {
+        self.requests += 1;
+        Ok(match self.files.get(url) {
+               Some(x) => {
+                   Head {
+                       status: 200,
+                       content_length: Some(x.len() as u64),
+                   }
+               },
+               None => {
+                   Head {
+                       status: 404,
+                       content_length: None,
+                   }
+               },
+           })
+    }
+}
+
+fn check(ok: bool, what: &str) -> Result<(), String> {
+    if ok {
+        println!("ok: {}", what);
+        Ok(())
+    } else {
+        Err(what.to_string())
+    }
+}
+
+/// Mirror a tiny registry that only exists in memory into a temporary
+/// archive, checking that every step does what it should, for --self-test
+///
+/// Nothing is downloaded, the index is written to the archive by hand and
+/// the crate files are served by a fake downloader.
+pub fn run() -> Result<(), String> {
+    let dir = env::temp_dir().join(format!("crates-ectype-self-test-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let ret = run_in(&dir);
+    let _ = fs::remove_dir_all(&dir);
+    ret
+}
+
+fn run_in(dir: &Path) -> Result<(), String> {
+    let git_dir = dir.join("index");
+    let config = ConfigJsonFile {
+        dl: "https://crates.io/api/v1/crates".to_string(),
+        api: Some("https://crates.io".to_string()),
+        dl_orig: None,
+        extra: ::serde_json::Map::new(),
+    };
+    let crates: &[(&str, &str, &[u8])] = &[("ab", "1.0.0", b"ab 1.0.0"),
+                                           ("fixture", "0.1.0", b"fixture 0.1.0"),
+                                           ("fixture", "0.2.0", b"fixture 0.2.0")];
+
+    let settings = |args: &[&str]| {
+        /* The notice about verifying existing crates is about real archives */
+        let mut args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        args.push("--quiet-existing".to_string());
+        args.push(dir.to_string_lossy().into_owned());
+        options()
+            .parse(&args)
+            .map(|x| Settings::from(&x))
+            .map_err(|e| e.to_string())
+    };
+    let write = |path: &Path, contents: &[u8]| {
+        path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(path, contents))
+            .map_err(|e| format!("Error writing to {}: {}", path.display(), e))
+    };
+
+    let mut index_files: BTreeMap<&str, String> = BTreeMap::new();
+    let mut downloader = FixtureDownloader {
+        files: BTreeMap::new(),
+        requests: 0,
+    };
+    for &(name, vers, contents) in crates {
+        let line = format!(r#"{{"name":"{}","vers":"{}","cksum":"{}","yanked":false}}"#,
+                           name,
+                           vers,
+                           Algorithm::Sha256.hash(contents));
+        let file = index_files.entry(name).or_default();
+        file.push_str(&line);
+        file.push('\n');
+        downloader.files.insert(format!("https://static.crates.io/crates/{}/{}-{}.crate",
+                                        name,
+                                        name,
+                                        vers),
+                                contents.to_vec());
+    }
+    for (name, contents) in &index_files {
+        write(&git_dir.join(index_path(name)), contents.as_bytes())?;
+    }
+    config.write(&git_dir);
+
+    let default = settings(&[])?;
+    let index = read_crate_index(&git_dir, &default);
+    check(index.len() == 2 &&
+          index.iter().all(|c| c.vers != "0.1.0"),
+          "the index is read, keeping the newest version of every crate")?;
+    check(ConfigJsonFile::read(&git_dir).dl == config.dl,
+          "config.json is read")?;
+
+    let results = fetch_crates(index.clone(),
+                               &config,
+                               &default,
+                               &LocalStorage::new(&default),
+                               &mut downloader,
+                               &mut |_| ());
+    check(results.exit_code() == EXIT_OK && results.downloaded.crates == 2,
+          "the crates are downloaded and verified")?;
+    check(crates[..1]
+              .iter()
+              .chain(&crates[2..])
+              .all(|&(name, vers, contents)| {
+                       fs::read(dir.join(format!("{}-{}.crate", name, vers))).ok().as_deref() ==
+                       Some(contents)
+                   }),
+          "the crate files are in the archive")?;
+
+    let requests = downloader.requests;
+    let results = fetch_crates(index.clone(),
+                               &config,
+                               &default,
+                               &LocalStorage::new(&default),
+                               &mut downloader,
+                               &mut |_| ());
+    check(results.exit_code() == EXIT_OK && results.downloaded.crates == 0 &&
+          downloader.requests == requests,
+          "crates that are already in the archive aren't downloaded again")?;
+
+    write(&dir.join("ab-1.0.0.crate"), b"corrupt")?;
+    let force_verify = settings(&["--force-verify"])?;
+    let results = fetch_crates(index,
+                               &config,
+                               &force_verify,
+                               &LocalStorage::new(&force_verify),
+                               &mut downloader,
+                               &mut |_| ());
+    check(results.exit_code() == EXIT_OK && results.downloaded.crates == 1 &&
+          fs::read(dir.join("ab-1.0.0.crate")).ok().as_deref() == Some(&b"ab 1.0.0"[..]),
+          "crate files that don't match their checksum are downloaded again")?;
+
+    let mismatched = settings(&["--download-old"])?;
+    downloader.files.insert("https://static.crates.io/crates/fixture/fixture-0.1.0.crate"
+                                .to_string(),
+                            b"tampered".to_vec());
+    let results = fetch_crates(read_crate_index(&git_dir, &mismatched),
+                               &config,
+                               &mismatched,
+                               &LocalStorage::new(&mismatched),
+                               &mut downloader,
+                               &mut |_| ());
+    check(results.checksum_mismatches.len() == 1 &&
+          !dir.join("fixture-0.1.0.crate").exists(),
+          "downloads that don't match their checksum aren't saved")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tests::test_dir;
+
+    #[test]
+    fn self_test() {
+        let dir = test_dir("self-test");
+        run_in(&dir).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+
+use serde_json;
+
+use tiers::{self, Tier};
+
+/// Serves the archive over HTTP as a sparse registry, so that the mirror can
+/// be tried out with cargo without setting up a web server
+///
+/// The index is served under /index/, with a config.json pointing cargo to
+/// the crates under /crates/.
+pub struct Server<'a> {
+    pub archive: &'a Path,
+    pub git_dir: &'a Path,
+    pub tiers: &'a [Tier],
+}
+
+/// The status code, content type and body of a response
+type Response = (u16, &'static str, Vec<u8>);
+
+impl<'a> Server<'a> {
+    /// Serve requests on the given address until the program is killed
+    pub fn run(&self, addr: &str) {
+        let listener = match TcpListener::bind(addr) {
+            Ok(x) => x,
+            Err(e) => error!("Error listening on {}: {}", addr, e),
+        };
+        println!("Serving the mirror on http://{}, use it with registry = \"sparse+http://{}/index/\"",
+                 addr,
+                 addr);
+
+        thread::scope(|s| {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        s.spawn(move || self.handle(stream));
+                    },
+                    Err(e) => println!("Warning: Error accepting connection: {}", e),
+                }
+            }
+        });
+    }
+
+    fn handle(&self, mut stream: TcpStream) {
+        let mut request_line = String::new();
+        let mut host = None;
+        {
+            let mut reader = BufReader::new(&stream);
+            if reader.read_line(&mut request_line).is_err() {
+                return;
+            }
+            loop {
+                let mut line = String::new();
+                match reader.read_line(&mut line) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => (),
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let mut header = line.splitn(2, ':');
+                let name = header.next().unwrap_or("");
+                if name.eq_ignore_ascii_case("host") {
+                    host = header.next().map(|x| x.trim().to_string());
+                }
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or("");
+        let path = parts.next().unwrap_or("");
+        let (status, content_type, body) = match method {
+            "GET" | "HEAD" => {
+                self.respond(path, host.as_deref().unwrap_or("localhost"))
+            },
+            _ => (405, "text/plain", b"Method not allowed\n".to_vec()),
+        };
+
+        let reason = match status {
+            200 => "OK",
+            404 => "Not Found",
+            _ => "Method Not Allowed",
+        };
+        let header = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                             status,
+                             reason,
+                             content_type,
+                             body.len());
+        let _ = stream.write_all(header.as_bytes());
+        if method != "HEAD" {
+            let _ = stream.write_all(&body);
+        }
+    }
+
+    /// The response to a GET request for the given path, where host is the
+    /// address the client reached us on
+    fn respond(&self, path: &str, host: &str) -> Response {
+        let not_found = (404, "text/plain", b"Not found\n".to_vec());
+        let path = path.split('?').next().unwrap_or("");
+        let segments: Vec<&str> =
+            path.trim_start_matches('/').split('/').collect();
+        /* Keep requests inside the archive, and out of .git */
+        if !segments.iter().all(|x| valid_segment(x)) {
+            return not_found;
+        }
+
+        match segments.split_first() {
+            Some((&"index", &["config.json"])) => {
+                let dl = format!("http://{}/crates", host);
+                let config = format!("{{\"dl\":{}}}\n",
+                                     serde_json::to_string(&dl)
+                                         .expect("Error encoding config.json"));
+                (200, "application/json", config.into_bytes())
+            },
+            Some((&"index", rest)) if !rest.is_empty() => {
+                match fs::read(self.git_dir.join(rest.join("/"))) {
+                    Ok(x) => (200, "text/plain", x),
+                    Err(_) => not_found,
+                }
+            },
+            Some((&"crates", &[name, version, "download"])) => {
+                let filename = format!("{}-{}.crate", name, version);
+                let path = tiers::existing_path(self.tiers,
+                                                self.archive,
+                                                name,
+                                                &filename);
+                match fs::read(path) {
+                    Ok(x) => (200, "application/gzip", x),
+                    Err(_) => not_found,
+                }
+            },
+            _ => not_found,
+        }
+    }
+}
+
+/// Whether the path segment can be part of a crate name, version or index
+/// path
+fn valid_segment(segment: &str) -> bool {
+    !segment.is_empty() && !segment.starts_with('.') &&
+    segment
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tests::test_dir;
+
+    #[test]
+    fn responses() {
+        let dir = test_dir("serve");
+        let git_dir = dir.join("index");
+        fs::create_dir_all(git_dir.join("3/f")).unwrap();
+        fs::create_dir_all(git_dir.join(".git")).unwrap();
+        fs::write(git_dir.join("3/f/foo"), "index entry").unwrap();
+        fs::write(git_dir.join(".git/config"), "secret").unwrap();
+        fs::write(dir.join("foo-0.1.0.crate"), "crate").unwrap();
+
+        let server = Server {
+            archive: &dir,
+            git_dir: &git_dir,
+            tiers: &[],
+        };
+        assert_eq!(server.respond("/index/config.json", "localhost:8080").2,
+                   b"{\"dl\":\"http://localhost:8080/crates\"}\n");
+        assert_eq!(server.respond("/index/3/f/foo", "localhost").2,
+                   b"index entry");
+        assert_eq!(server.respond("/crates/foo/0.1.0/download", "localhost").2,
+                   b"crate");
+        assert_eq!(server.respond("/crates/foo/0.2.0/download", "localhost").0,
+                   404);
+        assert_eq!(server.respond("/index/.git/config", "localhost").0, 404);
+        assert_eq!(server.respond("/index/../foo-0.1.0.crate", "localhost").0,
+                   404);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use std::str::FromStr;
+
+use index_path;
+
+/// One of N disjoint parts of the index, for --shard K/N
+///
+/// Crates are assigned by the index directory their file is in, so that
+/// every directory is read by only one shard.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Shard {
+    /// Counting from 0, unlike on the command line
+    index: u64,
+    count: u64,
+}
+impl FromStr for Shard {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut parts = s.splitn(2, '/');
+        let numbers = (parts.next().and_then(|x| x.trim().parse::<u64>().ok()),
+                       parts.next().and_then(|x| x.trim().parse::<u64>().ok()));
+        match numbers {
+            (Some(k), Some(n)) if k >= 1 && k <= n => {
+                Ok(Shard {
+                       index: k - 1,
+                       count: n,
+                   })
+            },
+            _ => Err(format!("expected K/N with 1 <= K <= N, e.g. 1/4, got {}", s)),
+        }
+    }
+}
+impl Shard {
+    pub fn contains(&self, name: &str) -> bool {
+        /* Joined with / on every platform, so that all machines agree */
+        let path = index_path(name);
+        let dir: Vec<_> = path.parent()
+            .into_iter()
+            .flat_map(|x| x.iter())
+            .map(|x| x.to_string_lossy())
+            .collect();
+        fnv1a(dir.join("/").as_bytes()) % self.count == self.index
+    }
+}
+
+/// A 64 bit FNV-1a hash, which unlike the hasher of the standard library is
+/// guaranteed to be the same on every machine and version
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &x in data {
+        hash ^= u64::from(x);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// A range of first characters of crate names, for --name-prefix, e.g. a-m
+/// or just a
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct NameRange {
+    first: char,
+    last: char,
+}
+impl FromStr for NameRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.to_ascii_lowercase();
+        let chars: Vec<char> = s.chars().collect();
+        let (first, last) = match chars[..] {
+            [x] => (x, x),
+            [x, '-', y] => (x, y),
+            _ => return Err(format!("expected a character or a range like a-m, got {}", s)),
+        };
+        if !first.is_ascii_alphanumeric() || !last.is_ascii_alphanumeric() ||
+           first > last {
+            return Err(format!("expected a character or a range like a-m, got {}", s));
+        }
+        Ok(NameRange { first, last })
+    }
+}
+impl NameRange {
+    pub fn contains(&self, name: &str) -> bool {
+        match name.chars().next() {
+            Some(x) => {
+                let x = x.to_ascii_lowercase();
+                self.first <= x && x <= self.last
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards_are_disjoint() {
+        let shards: Vec<Shard> = (1..5)
+            .map(|k| format!("{}/4", k).parse().unwrap())
+            .collect();
+        let names = ["a", "ab", "abc", "serde", "Serde_json", "serde_derive", "libc"];
+        for name in &names {
+            assert_eq!(shards.iter().filter(|x| x.contains(name)).count(),
+                       1,
+                       "{}",
+                       name);
+        }
+        /* Crates in the same directory are in the same shard */
+        for shard in &shards {
+            assert_eq!(shard.contains("serde"), shard.contains("serde_derive"));
+        }
+        assert_eq!(fnv1a(b"se/rd"), 0x860c_df2f_1161_d900);
+
+        assert!("0/4".parse::<Shard>().is_err());
+        assert!("5/4".parse::<Shard>().is_err());
+        assert!("1".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn name_ranges() {
+        let range: NameRange = "a-m".parse().unwrap();
+        assert!(range.contains("anyhow"));
+        assert!(range.contains("Markdown"));
+        assert!(!range.contains("serde"));
+        let range: NameRange = "S".parse().unwrap();
+        assert!(range.contains("serde"));
+        assert!(!range.contains("tokio"));
+        assert!("m-a".parse::<NameRange>().is_err());
+        assert!("ab".parse::<NameRange>().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json;
+
+use write_atomically;
+
+/// The name of the file with the totals of all runs, placed in the archive
+/// directory
+const STATS_FILE: &str = ".ectype-stats";
+
+/// How many crates were downloaded, and their total size
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct DownloadStats {
+    pub crates: u64,
+    pub bytes: u64,
+}
+impl DownloadStats {
+    pub fn add(&mut self, bytes: usize) {
+        self.crates += 1;
+        self.bytes += bytes as u64;
+    }
+
+    pub fn to_text(self) -> String {
+        format!("{} crates totaling {}", self.crates, format_size(self.bytes))
+    }
+}
+
+/// Add the downloads of this run to the totals of all earlier runs into the
+/// archive, returning the new totals
+pub fn update_lifetime_stats(archive: &Path, run: DownloadStats) -> DownloadStats {
+    let path = archive.join(STATS_FILE);
+    let mut stats = match fs::read_to_string(&path) {
+        Ok(x) => {
+            match serde_json::from_str::<DownloadStats>(&x) {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("Warning: Error parsing {}, starting the totals over: {}",
+                             path.to_string_lossy(),
+                             e);
+                    DownloadStats::default()
+                },
+            }
+        },
+        Err(_) => DownloadStats::default(),
+    };
+    stats.crates += run.crates;
+    stats.bytes += run.bytes;
+
+    let tmp = serde_json::to_string(&stats).expect("Error encoding stats");
+    if let Err(e) = write_atomically(&path, tmp + "\n") {
+        error!("{}", e);
+    }
+    stats
+}
+
+/// The number of bytes in the largest unit that keeps it at least 1, e.g.
+/// 1.5 GiB
+fn format_size(bytes: u64) -> String {
+    let units = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tests::test_dir;
+
+    #[test]
+    fn lifetime_totals() {
+        assert_eq!(format_size(12), "12 bytes");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(680 << 30), "680.0 GiB");
+
+        let dir = test_dir("stats");
+
+        let run = DownloadStats {
+            crates: 2,
+            bytes: 100,
+        };
+        assert_eq!(update_lifetime_stats(&dir, run), run);
+        assert_eq!(update_lifetime_stats(&dir, run),
+                   DownloadStats {
+                       crates: 4,
+                       bytes: 200,
+                   });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use tiers;
+use {has_free_space, Crate, Settings};
+
+/// Where the downloaded crate files are kept
+pub trait Storage {
+    /// Whether the crate file is stored
+    fn exists(&self, c: &Crate) -> Result<bool, String>;
+    /// Store the crate file, replacing any earlier one. The data has already
+    /// been verified.
+    fn write(&self, c: &Crate, data: &[u8]) -> Result<(), String>;
+    /// The stored crate file, if there is one
+    fn read(&self, c: &Crate) -> Result<Option<Vec<u8>>, String>;
+    /// Whether the stored crate file matches the checksum in the index, or
+    /// None if it isn't stored. The file is read back unless the storage has
+    /// a quicker way.
+    fn verify(&self, c: &Crate) -> Result<Option<bool>, String> {
+        Ok(self.read(c)?.map(|x| c.checksum_algorithm().hash(&x) == c.cksum))
+    }
+    /// The stored crate file on the local filesystem, for storage that has
+    /// one. It is verified in place, and its verification is remembered in
+    /// the verify cache, instead of reading it back every run.
+    fn local_path(&self, _c: &Crate) -> Option<PathBuf> {
+        None
+    }
+    /// Whether there is room for another crate, so that a run can stop
+    /// cleanly instead of failing halfway through a write
+    fn has_room(&self, _c: &Crate) -> bool {
+        true
+    }
+    /// Whether the storage is reached over the network, so that failing to
+    /// write to it is counted like a failed download instead of stopping the
+    /// run
+    fn is_remote(&self) -> bool {
+        false
+    }
+}
+
+/// Give the file the permissions of --file-mode, so that they don't depend
+/// on the umask of whoever ran the mirror
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+#[cfg(not(unix))]
+pub fn set_mode(_: &Path, _: u32) -> io::Result<()> {
+    Ok(())
+}
+
+pub fn crate_filename(c: &Crate) -> String {
+    format!("{}-{}.crate", c.name, c.vers)
+}
+
+/// The archive directory and its tiers, where new crates go into the staging
+/// directory if there is one
+pub struct LocalStorage<'a> {
+    settings: &'a Settings,
+}
+impl<'a> LocalStorage<'a> {
+    pub fn new(settings: &'a Settings) -> Self {
+        LocalStorage { settings }
+    }
+
+    /// The directory new crate files are written to
+    fn download_dir(&self, c: &Crate) -> &Path {
+        match self.settings.staging {
+            Some(ref x) => x.as_path(),
+            None => tiers::placement(&self.settings.tiers, &self.settings.archive, &c.name),
+        }
+    }
+}
+impl<'a> Storage for LocalStorage<'a> {
+    fn exists(&self, c: &Crate) -> Result<bool, String> {
+        Ok(self.local_path(c).is_some())
+    }
+
+    fn write(&self, c: &Crate, data: &[u8]) -> Result<(), String> {
+        let download_dir = self.download_dir(c);
+        let cratefile = download_dir.join(crate_filename(c));
+        let partfile = download_dir.join(format!("{}.part", crate_filename(c)));
+
+        let mut f = match File::create(&partfile) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(format!("Error creating file {}: {}",
+                                   partfile.to_string_lossy(),
+                                   e))
+            },
+        };
+        /* The data is already in memory, so it is written in pieces of
+         * --write-buffer-size instead of going through a BufWriter, which
+         * would pass it on in one piece */
+        for chunk in data.chunks(self.settings.write_buffer_size) {
+            if let Err(e) = f.write_all(chunk) {
+                return Err(format!("Error writing to {}: {}", partfile.to_string_lossy(), e));
+            }
+        }
+        if let Err(e) = set_mode(&partfile, self.settings.file_mode) {
+            return Err(format!("Error setting the permissions of {}: {}",
+                               partfile.to_string_lossy(),
+                               e));
+        }
+        match fs::rename(&partfile, &cratefile) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                Err(format!("Error renaming {} to {}: {}",
+                            partfile.to_string_lossy(),
+                            cratefile.to_string_lossy(),
+                            e))
+            },
+        }
+    }
+
+    fn read(&self, c: &Crate) -> Result<Option<Vec<u8>>, String> {
+        let path = match self.local_path(c) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        match fs::read(&path) {
+            Ok(x) => Ok(Some(x)),
+            Err(e) => Err(format!("Error reading {}: {}", path.to_string_lossy(), e)),
+        }
+    }
+
+    /// Crates in the archive or its tiers come before ones waiting in the
+    /// staging directory
+    fn local_path(&self, c: &Crate) -> Option<PathBuf> {
+        let filename = crate_filename(c);
+        let path = tiers::existing_path(&self.settings.tiers,
+                                        &self.settings.archive,
+                                        &c.name,
+                                        &filename);
+        if path.exists() {
+            return Some(path);
+        }
+        self.settings
+            .staging
+            .as_ref()
+            .map(|x| x.join(&filename))
+            .filter(|x| x.exists())
+    }
+
+    fn has_room(&self, c: &Crate) -> bool {
+        has_free_space(self.download_dir(c), self.settings)
+    }
+}
+
+/// Storage keeping the crate files in memory, for testing storage without
+/// local files
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStorage {
+    pub files: ::std::cell::RefCell<::std::collections::BTreeMap<String, Vec<u8>>>,
+    /// Fail every request, like storage that can't be reached
+    pub fail: bool,
+}
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn exists(&self, c: &Crate) -> Result<bool, String> {
+        Ok(self.read(c)?.is_some())
+    }
+
+    fn write(&self, c: &Crate, data: &[u8]) -> Result<(), String> {
+        if self.fail {
+            return Err("storage unreachable".to_string());
+        }
+        self.files.borrow_mut().insert(crate_filename(c), data.to_vec());
+        Ok(())
+    }
+
+    fn read(&self, c: &Crate) -> Result<Option<Vec<u8>>, String> {
+        if self.fail {
+            return Err("storage unreachable".to_string());
+        }
+        Ok(self.files.borrow().get(&crate_filename(c)).cloned())
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tests::{test_crate, test_dir, test_settings};
+
+    #[test]
+    fn local_storage() {
+        let dir = test_dir("storage");
+        fs::create_dir_all(dir.join("staging")).unwrap();
+        let settings = test_settings(&dir,
+                                     &["--write-buffer-size",
+                                       "2",
+                                       "--staging",
+                                       &dir.join("staging").to_string_lossy()]);
+        let storage = LocalStorage::new(&settings);
+        let c = Crate::new("foo", "0.1.0");
+
+        assert_eq!(storage.exists(&c), Ok(false));
+        assert_eq!(storage.read(&c), Ok(None));
+        storage.write(&c, b"staged").unwrap();
+        assert_eq!(storage.local_path(&c),
+                   Some(dir.join("staging/foo-0.1.0.crate")));
+        assert!(!dir.join("staging/foo-0.1.0.crate.part").exists());
+        assert_eq!(storage.read(&c), Ok(Some(b"staged".to_vec())));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = fs::metadata(dir.join("staging/foo-0.1.0.crate")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o644);
+        }
+
+        fs::write(dir.join("foo-0.1.0.crate"), b"archived").unwrap();
+        assert_eq!(storage.read(&c), Ok(Some(b"archived".to_vec())));
+        assert_eq!(storage.verify(&test_crate("foo", "0.1.0", b"archived")), Ok(Some(true)));
+        assert_eq!(storage.verify(&test_crate("foo", "0.1.0", b"staged")), Ok(Some(false)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
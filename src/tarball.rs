@@ -0,0 +1,134 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use tar::Archive;
+use toml;
+
+/// Read the .crate file as a gzipped tarball, returning the paths of its
+/// entries
+///
+/// Every entry is read to the end, so that truncated or corrupt data
+/// anywhere in the file is noticed.
+pub fn list_entries(data: &[u8]) -> Result<Vec<String>, String> {
+    let mut archive = Archive::new(GzDecoder::new(data));
+    let entries = match archive.entries() {
+        Ok(x) => x,
+        Err(e) => return Err(format!("not a valid tarball: {}", e)),
+    };
+
+    let mut ret = Vec::new();
+    let mut buf = Vec::new();
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(x) => x,
+            Err(e) => return Err(format!("invalid tarball entry: {}", e)),
+        };
+        let path = match entry.path() {
+            Ok(x) => x.to_string_lossy().into_owned(),
+            Err(e) => return Err(format!("invalid tarball entry path: {}", e)),
+        };
+        buf.clear();
+        if let Err(e) = entry.read_to_end(&mut buf) {
+            return Err(format!("error reading {} from tarball: {}", path, e));
+        }
+        ret.push(path);
+    }
+
+    if ret.is_empty() {
+        return Err("tarball is empty".to_string());
+    }
+    Ok(ret)
+}
+
+/// Read the package name and version from the Cargo.toml at the top of the
+/// .crate file
+pub fn package_info(data: &[u8]) -> Result<(String, String), String> {
+    let mut archive = Archive::new(GzDecoder::new(data));
+    let entries = match archive.entries() {
+        Ok(x) => x,
+        Err(e) => return Err(format!("not a valid tarball: {}", e)),
+    };
+
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(x) => x,
+            Err(e) => return Err(format!("invalid tarball entry: {}", e)),
+        };
+        /* Everything is in a single {name}-{version} directory */
+        let is_manifest = match entry.path() {
+            Ok(x) => x.components().count() == 2 && x.ends_with("Cargo.toml"),
+            Err(e) => return Err(format!("invalid tarball entry path: {}", e)),
+        };
+        if !is_manifest {
+            continue;
+        }
+
+        let mut manifest = String::new();
+        if let Err(e) = entry.read_to_string(&mut manifest) {
+            return Err(format!("error reading Cargo.toml from tarball: {}", e));
+        }
+        let manifest: toml::Value = match toml::from_str(&manifest) {
+            Ok(x) => x,
+            Err(e) => return Err(format!("invalid Cargo.toml: {}", e)),
+        };
+        let field = |name: &str| {
+            manifest
+                .get("package")
+                .and_then(|x| x.get(name))
+                .and_then(|x| x.as_str())
+                .map(|x| x.to_string())
+                .ok_or_else(|| format!("Cargo.toml has no package.{}", name))
+        };
+        return Ok((field("name")?, field("version")?));
+    }
+
+    Err("tarball has no Cargo.toml".to_string())
+}
+
+/// A gzipped tarball with the given files, for use in tests
+#[cfg(test)]
+pub fn test_tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    let mut builder =
+        tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for &(path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, contents).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        let data = test_tarball(&[("foo-0.1.0/Cargo.toml", b"[package]"),
+                                  ("foo-0.1.0/src/lib.rs", b"")]);
+        assert_eq!(list_entries(&data).unwrap(),
+                   vec!["foo-0.1.0/Cargo.toml", "foo-0.1.0/src/lib.rs"]);
+
+        assert!(list_entries(b"not a tarball").is_err());
+        assert!(list_entries(&data[..data.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn package() {
+        let data = test_tarball(&[("foo-0.1.0/src/Cargo.toml", b"[package]"),
+                                  ("foo-0.1.0/Cargo.toml",
+                                   b"[package]\nname = \"foo\"\nversion = \"0.1.0\"\n")]);
+        assert_eq!(package_info(&data).unwrap(),
+                   ("foo".to_string(), "0.1.0".to_string()));
+
+        let data = test_tarball(&[("foo-0.1.0/Cargo.toml", b"[package]\nname = \"foo\"\n")]);
+        assert!(package_info(&data).is_err());
+        let data = test_tarball(&[("foo-0.1.0/src/lib.rs", b"")]);
+        assert!(package_info(&data).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ignore::glob_match;
+
+/// An additional directory that some of the crates are stored in instead of
+/// the archive directory, given as `DIR=PATTERNS` where PATTERNS is a comma
+/// separated list of crate name patterns, or `DIR=@FILE` where FILE lists one
+/// crate name per line.
+#[derive(Serialize, Debug)]
+pub struct Tier {
+    pub dir: PathBuf,
+    rule: TierRule,
+}
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum TierRule {
+    Patterns(Vec<String>),
+    Names(BTreeSet<String>),
+}
+impl Tier {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let i = match s.rfind('=') {
+            Some(x) => x,
+            None => return Err(format!("{}, expected DIR=PATTERNS or DIR=@FILE", s)),
+        };
+        let (dir, rule) = (&s[..i], &s[i + 1..]);
+        if dir.is_empty() || rule.is_empty() {
+            return Err(format!("{}, expected DIR=PATTERNS or DIR=@FILE", s));
+        }
+
+        let rule = if let Some(path) = rule.strip_prefix('@') {
+            let contents = match fs::read_to_string(path) {
+                Ok(x) => x,
+                Err(e) => return Err(format!("error reading {}: {}", path, e)),
+            };
+            TierRule::Names(contents
+                                .lines()
+                                .map(|x| x.trim())
+                                .filter(|x| !x.is_empty() && !x.starts_with('#'))
+                                .map(|x| x.to_ascii_lowercase())
+                                .collect())
+        } else {
+            TierRule::Patterns(rule.split(',')
+                                   .map(|x| x.trim().to_ascii_lowercase())
+                                   .collect())
+        };
+
+        Ok(Tier {
+               dir: PathBuf::from(dir),
+               rule,
+           })
+    }
+
+    /// Whether the crate with the given name belongs in this tier
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        match self.rule {
+            TierRule::Patterns(ref patterns) => {
+                patterns.iter().any(|x| glob_match(x, &name))
+            },
+            TierRule::Names(ref names) => names.contains(&name),
+        }
+    }
+}
+
+/// The directory new downloads of the given crate are placed in, which is
+/// the first tier matching it, or the archive if none do
+pub fn placement<'a>(tiers: &'a [Tier], archive: &'a Path, name: &str) -> &'a Path {
+    tiers
+        .iter()
+        .find(|x| x.matches(name))
+        .map(|x| x.dir.as_path())
+        .unwrap_or(archive)
+}
+
+/// The path of the crate file in whichever tier or the archive it is in, if
+/// it is anywhere
+pub fn find_existing(tiers: &[Tier],
+                     archive: &Path,
+                     filename: &str)
+                     -> Option<PathBuf> {
+    tiers
+        .iter()
+        .map(|x| x.dir.join(filename))
+        .chain(Some(archive.join(filename)))
+        .find(|x| x.exists())
+}
+
+/// The path of the crate file in whichever tier or the archive it is in, so
+/// that moving a crate between tiers doesn't download it again. If it isn't
+/// anywhere, this is the path it would be placed at.
+pub fn existing_path(tiers: &[Tier],
+                     archive: &Path,
+                     name: &str,
+                     filename: &str)
+                     -> PathBuf {
+    find_existing(tiers, archive, filename)
+        .unwrap_or_else(|| placement(tiers, archive, name).join(filename))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tier_rules() {
+        let tiers = vec![Tier::parse("/ssd=serde*,tokio").unwrap(),
+                         Tier::parse("/other=Rand").unwrap()];
+        let archive = Path::new("/hdd");
+        assert_eq!(placement(&tiers, archive, "serde_json"), Path::new("/ssd"));
+        assert_eq!(placement(&tiers, archive, "tokio"), Path::new("/ssd"));
+        assert_eq!(placement(&tiers, archive, "tokio-util"), Path::new("/hdd"));
+        assert_eq!(placement(&tiers, archive, "rand"), Path::new("/other"));
+        assert_eq!(existing_path(&tiers, archive, "rand", "rand-0.1.0.crate"),
+                   Path::new("/other/rand-0.1.0.crate"));
+
+        assert!(Tier::parse("/ssd").is_err());
+        assert!(Tier::parse("=serde").is_err());
+        assert!(Tier::parse("/ssd=@/nonexistent/file").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fs::{File, Metadata};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use write_atomically;
+
+/// The name of the cache file, placed in the archive directory
+const CACHE_FILE: &str = ".ectype-verify-cache";
+
+/// A single file in the cache
+struct CacheEntry {
+    size: u64,
+    /// Modification time as nanoseconds since the unix epoch
+    mtime: u128,
+    sha256: String,
+    /// When the file was last hashed, as seconds since the unix epoch
+    verified: u64,
+}
+
+/// Remembers the sha256sums of already verified .crate files, so that files
+/// whose size and mtime are unchanged since they were last verified don't
+/// have to be read and hashed again.
+///
+/// The cache file has one line per file, of the form
+/// `{filename} {size} {mtime} {sha256} {verified}`. Lines written by older
+/// versions lack the verified time, and count as verified long ago.
+pub struct VerifyCache {
+    path: PathBuf,
+    entries: BTreeMap<String, CacheEntry>,
+    dirty: bool,
+}
+impl VerifyCache {
+    /// Read the cache from the given archive directory. A missing or invalid
+    /// cache file results in an empty cache.
+    pub fn read(archive: &Path) -> Self {
+        let path = archive.join(CACHE_FILE);
+        let mut ret = VerifyCache {
+            path,
+            entries: BTreeMap::new(),
+            dirty: false,
+        };
+
+        let f = match File::open(&ret.path) {
+            Ok(f) => f,
+            Err(_) => return ret,
+        };
+
+        for line in BufReader::new(f).lines() {
+            let line = match line {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("Warning: Error reading {}, ignoring it: {}",
+                             ret.path.to_string_lossy(),
+                             e);
+                    ret.entries.clear();
+                    return ret;
+                },
+            };
+
+            let fields: Vec<&str> = line.split(' ').collect();
+            let verified = match fields.get(4) {
+                Some(x) => x.parse().ok(),
+                None => Some(0),
+            };
+            let entry = match (fields.len(),
+                               fields.get(1).and_then(|x| x.parse().ok()),
+                               fields.get(2).and_then(|x| x.parse().ok()),
+                               verified) {
+                (4..=5, Some(size), Some(mtime), Some(verified)) => {
+                    CacheEntry {
+                        size,
+                        mtime,
+                        sha256: fields[3].to_string(),
+                        verified,
+                    }
+                },
+                _ => {
+                    println!("Warning: Ignoring invalid line in {}: {}",
+                             ret.path.to_string_lossy(),
+                             line);
+                    continue;
+                },
+            };
+            ret.entries.insert(fields[0].to_string(), entry);
+        }
+
+        ret
+    }
+
+    /// Return the cached sha256sum of the given file, if its metadata
+    /// matches what it was when it was verified, and it was verified less
+    /// than max_age ago if given
+    pub fn lookup(&self,
+                  filename: &str,
+                  metadata: &Metadata,
+                  max_age: Option<Duration>)
+                  -> Option<&str> {
+        let entry = self.entries.get(filename)?;
+        let age = now().saturating_sub(entry.verified);
+        if max_age.is_some_and(|x| age >= x.as_secs()) {
+            return None;
+        }
+        if entry.size == metadata.len() && Some(entry.mtime) == mtime(metadata) {
+            Some(&entry.sha256)
+        } else {
+            None
+        }
+    }
+
+    /// Record that the given file has been verified to have the given
+    /// sha256sum
+    pub fn insert(&mut self, filename: &str, metadata: &Metadata, sha256: &str) {
+        let mtime = match mtime(metadata) {
+            Some(x) => x,
+            None => return,
+        };
+        self.entries.insert(filename.to_string(),
+                            CacheEntry {
+                                size: metadata.len(),
+                                mtime,
+                                sha256: sha256.to_string(),
+                                verified: now(),
+                            });
+        self.dirty = true;
+    }
+
+    /// Write the cache back to the archive directory, if it has changed
+    pub fn write(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        let mut tmp = String::new();
+        for (filename, entry) in &self.entries {
+            tmp.push_str(&format!("{} {} {} {} {}\n",
+                                  filename,
+                                  entry.size,
+                                  entry.mtime,
+                                  entry.sha256,
+                                  entry.verified));
+        }
+
+        if let Err(e) = write_atomically(&self.path, tmp) {
+            error!("{}", e);
+        }
+
+        self.dirty = false;
+    }
+}
+
+/// The modification time of the file in nanoseconds since the unix epoch
+fn mtime(metadata: &Metadata) -> Option<u128> {
+    let modified = metadata.modified().ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some(since_epoch.as_nanos())
+}
+
+/// The current time in seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
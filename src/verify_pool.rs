@@ -0,0 +1,78 @@
+use std::fs::Metadata;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use Crate;
+
+/// An already downloaded crate file whose checksum has to be verified
+pub struct VerifyJob {
+    pub krate: Crate,
+    pub crate_name: String,
+    pub path: PathBuf,
+    pub metadata: Metadata,
+}
+
+/// Hashes already downloaded crate files on a pool of threads, so that
+/// verifying a large archive isn't limited to a single core
+pub struct VerifyPool {
+    jobs: SyncSender<VerifyJob>,
+    /// Finished jobs along with the hash of the file
+    results: Receiver<(VerifyJob, String)>,
+}
+impl VerifyPool {
+    /// Start a pool with the given number of threads
+    pub fn new(threads: usize) -> Self {
+        let (jobs, job_receiver) = mpsc::sync_channel::<VerifyJob>(threads * 4);
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        for _ in 0..threads {
+            let job_receiver = Arc::clone(&job_receiver);
+            let result_sender = result_sender.clone();
+            thread::spawn(move || {
+                let mut buf = Vec::new();
+                loop {
+                    /* Don't hold the lock while hashing */
+                    let job = match job_receiver
+                              .lock()
+                              .expect("VerifyPool job lock poisoned")
+                              .recv() {
+                        Ok(x) => x,
+                        Err(_) => break,
+                    };
+                    let hash = job.krate
+                        .checksum_algorithm()
+                        .hash_file(&job.path, &mut buf);
+                    if result_sender.send((job, hash)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        VerifyPool {
+            jobs,
+            results,
+        }
+    }
+
+    /// Queue a file for hashing, blocking if the threads are busy
+    pub fn submit(&self, job: VerifyJob) {
+        self.jobs
+            .send(job)
+            .expect("VerifyPool threads exited unexpectedly");
+    }
+
+    /// Return a finished job, if there is one
+    pub fn try_result(&self) -> Option<(VerifyJob, String)> {
+        self.results.try_recv().ok()
+    }
+
+    /// Wait for all queued jobs to finish, returning their results
+    pub fn finish(self) -> Vec<(VerifyJob, String)> {
+        drop(self.jobs);
+        self.results.iter().collect()
+    }
+}
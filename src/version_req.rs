@@ -0,0 +1,402 @@
+use std::cmp::Ordering;
+
+use serde::{Serialize, Serializer};
+
+/// A part of the pre-release of a version, e.g. alpha or 1 in 1.0.0-alpha.1
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier {
+    /// Numeric identifiers always sort before alphanumeric ones
+    Numeric(u64),
+    Alphanumeric(String),
+}
+
+/// A semver version, without its build metadata since that doesn't affect
+/// which requirements it matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    major: u64,
+    minor: u64,
+    patch: u64,
+    pre: Vec<Identifier>,
+}
+impl Version {
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let s = s.split('+').next().unwrap_or(s);
+        let mut parts = s.splitn(2, '-');
+        let mut numbers = parts.next().unwrap_or("").split('.');
+        let ret = Version {
+            major: numbers.next()?.parse().ok()?,
+            minor: numbers.next()?.parse().ok()?,
+            patch: numbers.next()?.parse().ok()?,
+            pre: match parts.next() {
+                Some(x) => parse_pre(x)?,
+                None => Vec::new(),
+            },
+        };
+        if numbers.next().is_some() {
+            return None;
+        }
+        Some(ret)
+    }
+}
+/// Versions are serialized as they are written, e.g. 1.0.0-beta.1
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut ret = format!("{}.{}.{}", self.major, self.minor, self.patch);
+        for (i, x) in self.pre.iter().enumerate() {
+            ret.push(if i == 0 { '-' } else { '.' });
+            match *x {
+                Identifier::Numeric(n) => ret.push_str(&n.to_string()),
+                Identifier::Alphanumeric(ref x) => ret.push_str(x),
+            }
+        }
+        s.serialize_str(&ret)
+    }
+}
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| cmp_pre(&self.pre, &other.pre))
+    }
+}
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn parse_pre(s: &str) -> Option<Vec<Identifier>> {
+    s.split('.')
+        .map(|x| if x.is_empty() {
+                 None
+             } else if let Ok(n) = x.parse() {
+                 Some(Identifier::Numeric(n))
+             } else {
+                 Some(Identifier::Alphanumeric(x.to_string()))
+             })
+        .collect()
+}
+
+/// Versions without a pre-release come after the ones with one
+fn cmp_pre(a: &[Identifier], b: &[Identifier]) -> Ordering {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.cmp(b),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Tilde,
+    Caret,
+    Wildcard,
+}
+
+/// A single comparison in a requirement, e.g. >=1.2, where the parts that
+/// weren't given are None
+#[derive(Debug, Clone)]
+struct Comparator {
+    op: Op,
+    major: u64,
+    minor: Option<u64>,
+    patch: Option<u64>,
+    pre: Vec<Identifier>,
+}
+impl Comparator {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (op, rest) = [(">=", Op::GreaterEq),
+                          ("<=", Op::LessEq),
+                          (">", Op::Greater),
+                          ("<", Op::Less),
+                          ("=", Op::Exact),
+                          ("~", Op::Tilde),
+                          ("^", Op::Caret)]
+                .iter()
+                .find(|x| s.starts_with(x.0))
+                .map(|x| (x.1, &s[x.0.len()..]))
+                .unwrap_or((Op::Caret, s));
+        let rest = rest.trim_start();
+        let rest = rest.split('+').next().unwrap_or(rest);
+        let mut parts = rest.splitn(2, '-');
+
+        let mut numbers = Vec::new();
+        let mut wildcard = false;
+        for x in parts.next().unwrap_or("").split('.') {
+            if x == "*" || x == "x" || x == "X" {
+                wildcard = true;
+            } else if wildcard {
+                /* Nothing can come after a wildcard, as in 1.*.2 */
+                return None;
+            } else {
+                numbers.push(x.parse().ok()?);
+            }
+        }
+        if numbers.is_empty() || numbers.len() > 3 {
+            return None;
+        }
+        let pre = match parts.next() {
+            Some(x) if numbers.len() == 3 => parse_pre(x)?,
+            Some(_) => return None,
+            None => Vec::new(),
+        };
+        let op = if wildcard {
+            match op {
+                Op::Caret | Op::Exact => Op::Wildcard,
+                x => x,
+            }
+        } else {
+            op
+        };
+
+        Some(Comparator {
+                 op,
+                 major: numbers[0],
+                 minor: numbers.get(1).cloned(),
+                 patch: numbers.get(2).cloned(),
+                 pre,
+             })
+    }
+
+    fn matches(&self, v: &Version) -> bool {
+        match self.op {
+            Op::Exact | Op::Wildcard => self.matches_exact(v),
+            Op::Greater => self.matches_greater(v),
+            Op::GreaterEq => self.matches_exact(v) || self.matches_greater(v),
+            Op::Less => self.matches_less(v),
+            Op::LessEq => self.matches_exact(v) || self.matches_less(v),
+            Op::Tilde => self.matches_tilde(v),
+            Op::Caret => self.matches_caret(v),
+        }
+    }
+
+    fn matches_exact(&self, v: &Version) -> bool {
+        v.major == self.major && self.minor.is_none_or(|x| v.minor == x) &&
+        self.patch.is_none_or(|x| v.patch == x) && v.pre == self.pre
+    }
+
+    fn matches_greater(&self, v: &Version) -> bool {
+        if v.major != self.major {
+            return v.major > self.major;
+        }
+        match self.minor {
+            None => return false,
+            Some(x) if v.minor != x => return v.minor > x,
+            Some(_) => (),
+        }
+        match self.patch {
+            None => return false,
+            Some(x) if v.patch != x => return v.patch > x,
+            Some(_) => (),
+        }
+        cmp_pre(&v.pre, &self.pre) == Ordering::Greater
+    }
+
+    fn matches_less(&self, v: &Version) -> bool {
+        if v.major != self.major {
+            return v.major < self.major;
+        }
+        match self.minor {
+            None => return false,
+            Some(x) if v.minor != x => return v.minor < x,
+            Some(_) => (),
+        }
+        match self.patch {
+            None => return false,
+            Some(x) if v.patch != x => return v.patch < x,
+            Some(_) => (),
+        }
+        cmp_pre(&v.pre, &self.pre) == Ordering::Less
+    }
+
+    fn matches_tilde(&self, v: &Version) -> bool {
+        if v.major != self.major || self.minor.is_some_and(|x| v.minor != x) {
+            return false;
+        }
+        match self.patch {
+            Some(x) if v.patch != x => v.patch > x,
+            _ => cmp_pre(&v.pre, &self.pre) != Ordering::Less,
+        }
+    }
+
+    fn matches_caret(&self, v: &Version) -> bool {
+        if v.major != self.major {
+            return false;
+        }
+        let minor = match self.minor {
+            Some(x) => x,
+            None => return true,
+        };
+        let patch = match self.patch {
+            Some(x) => x,
+            None if self.major > 0 => return v.minor >= minor,
+            None => return v.minor == minor,
+        };
+        if self.major > 0 {
+            if v.minor != minor {
+                return v.minor > minor;
+            }
+        } else if v.minor != minor || (minor == 0 && v.patch != patch) {
+            /* ^0.0.3 only matches 0.0.3 */
+            return false;
+        }
+        if v.patch != patch {
+            return v.patch > patch;
+        }
+        cmp_pre(&v.pre, &self.pre) != Ordering::Less
+    }
+
+    /// The versions at which the comparator starts or stops matching, e.g.
+    /// 1.2.3 and 1.2.4 for >1.2.3, with the parts that weren't given as 0
+    fn bounds(&self) -> Vec<Version> {
+        let v = |major, minor, patch| {
+            Version {
+                major,
+                minor,
+                patch,
+                pre: Vec::new(),
+            }
+        };
+        let (major, minor, patch) = (self.major,
+                                     self.minor.unwrap_or(0),
+                                     self.patch.unwrap_or(0));
+        vec![Version { pre: self.pre.clone(), ..v(major, minor, patch) },
+             v(major, minor, patch),
+             v(major, minor, patch.saturating_add(1)),
+             v(major, minor.saturating_add(1), 0),
+             v(major.saturating_add(1), 0, 0)]
+    }
+
+    /// Whether the comparator allows pre-releases of the version, which are
+    /// only matched by comparators that mention a pre-release of the same
+    /// version
+    fn allows_pre(&self, v: &Version) -> bool {
+        self.major == v.major && self.minor == Some(v.minor) &&
+        self.patch == Some(v.patch) && !self.pre.is_empty()
+    }
+}
+
+/// A version requirement of a dependency, as interpreted by cargo
+#[derive(Debug, Clone)]
+pub struct VersionReq {
+    comparators: Vec<Comparator>,
+}
+impl VersionReq {
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if s == "*" || s.is_empty() {
+            return Some(VersionReq { comparators: Vec::new() });
+        }
+        Some(VersionReq {
+                 comparators: s.split(',')
+                     .map(Comparator::parse)
+                     .collect::<Option<_>>()?,
+             })
+    }
+
+    pub fn matches(&self, v: &Version) -> bool {
+        self.comparators.iter().all(|x| x.matches(v)) &&
+        (v.pre.is_empty() || self.comparators.iter().any(|x| x.allows_pre(v)))
+    }
+
+    /// Whether some version matches both requirements
+    ///
+    /// Every comparator matches a range of versions, so the lowest version
+    /// matching both is where one of the ranges starts, or right after it.
+    /// Only those versions have to be tried.
+    pub fn overlaps(&self, other: &VersionReq) -> bool {
+        let mut candidates = vec![Version::parse("0.0.0").expect("overlaps invalid version")];
+        for x in self.comparators.iter().chain(&other.comparators) {
+            candidates.extend(x.bounds());
+        }
+        candidates.iter().any(|v| self.matches(v) && other.matches(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(req: &str, v: &str) -> bool {
+        VersionReq::parse(req)
+            .unwrap()
+            .matches(&Version::parse(v).unwrap())
+    }
+
+    #[test]
+    fn requirements() {
+        assert!(matches("1.2.3", "1.9.0"));
+        assert!(!matches("1.2.3", "2.0.0"));
+        assert!(!matches("1.2.3", "1.2.2"));
+        assert!(matches("0.2.3", "0.2.9"));
+        assert!(!matches("0.2.3", "0.3.0"));
+        assert!(matches("^0.0.3", "0.0.3"));
+        assert!(!matches("^0.0.3", "0.0.4"));
+        assert!(matches("0.0", "0.0.7"));
+        assert!(!matches("0.0", "0.1.0"));
+        assert!(matches("~1.2.3", "1.2.9"));
+        assert!(!matches("~1.2.3", "1.3.0"));
+        assert!(matches("=1.2.3", "1.2.3"));
+        assert!(!matches("=1.2.3", "1.2.4"));
+        assert!(matches(">= 1.2, < 1.5", "1.4.9"));
+        assert!(!matches(">= 1.2, < 1.5", "1.5.0"));
+        assert!(matches("<=1.2", "1.2.9"));
+        assert!(matches("1.*", "1.9.0"));
+        assert!(!matches("1.*", "2.0.0"));
+        assert!(matches("*", "3.0.0"));
+
+        assert!(!matches("1.0.0", "1.1.0-beta.1"));
+        assert!(!matches("*", "1.0.0-beta.1"));
+        assert!(matches("1.1.0-beta.1", "1.1.0-beta.2"));
+        assert!(matches("1.1.0-beta.1", "1.1.0"));
+        assert!(!matches("1.1.0-beta.2", "1.1.0-beta.1"));
+        assert!(!matches("1.1.0-beta.1", "1.1.0-alpha.1"));
+        assert!(!matches("1.1.0-beta.1", "1.2.0-beta.1"));
+
+        let overlaps = |a: &str, b: &str| {
+            VersionReq::parse(a)
+                .unwrap()
+                .overlaps(&VersionReq::parse(b).unwrap())
+        };
+        assert!(overlaps("1", "^1.2"));
+        assert!(overlaps("^1.2", "1.1.0"));
+        assert!(overlaps(">=1.1, <1.3", "^1.2"));
+        assert!(overlaps("<1.2.1", "^1.2"));
+        assert!(overlaps("*", "0.1"));
+        assert!(overlaps(">1.2.3", "~1.2.3"));
+        assert!(!overlaps("2", "^1.2"));
+        assert!(!overlaps("<1.2", "^1.2"));
+        assert!(!overlaps("=1.1.0", "^1.2"));
+        assert!(!overlaps("0.2", "0.3"));
+        assert!(!overlaps(">1.2.3", "=1.2.3"));
+        assert!(overlaps("1.1.0-beta.1", "=1.1.0-beta.2"));
+        assert!(!overlaps("1.0", "=1.1.0-beta.2"));
+
+        assert!(VersionReq::parse("1.*.2").is_none());
+        assert!(VersionReq::parse("1.2-beta").is_none());
+        assert!(VersionReq::parse("foo").is_none());
+    }
+
+    #[test]
+    fn versions() {
+        let v = |x| Version::parse(x).unwrap();
+        assert!(v("1.0.0-alpha") < v("1.0.0-alpha.1"));
+        assert!(v("1.0.0-alpha.1") < v("1.0.0-alpha.beta"));
+        assert!(v("1.0.0-beta.2") < v("1.0.0-beta.11"));
+        assert!(v("1.0.0-rc.1") < v("1.0.0"));
+        assert!(v("1.0.0") < v("1.0.10"));
+        assert_eq!(v("1.0.0+build.5"), v("1.0.0"));
+        assert!(Version::parse("1.0").is_none());
+        assert_eq!(::serde_json::to_string(&v("1.0.0-beta.11+build")).unwrap(),
+                   r#""1.0.0-beta.11""#);
+    }
+}